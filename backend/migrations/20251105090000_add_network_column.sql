@@ -0,0 +1,19 @@
+-- 1. Añade la red (mainnet, testnet4, signet, regtest) a la que pertenece cada nodo.
+--    Los nodos existentes se consideran de mainnet.
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS network text NOT NULL DEFAULT 'mainnet';
+
+-- 2. La misma dirección puede existir en varias redes, así que la clave primaria
+--    pasa a ser (network, address).
+ALTER TABLE bnetwork DROP CONSTRAINT IF EXISTS bnetwork_pkey;
+ALTER TABLE bnetwork ADD PRIMARY KEY (network, address);
+
+-- 3. La cola del crawler se consulta siempre por red.
+DROP INDEX IF EXISTS idi_bnetwork_scan_queue;
+CREATE INDEX idi_bnetwork_scan_queue
+ON bnetwork (network, next_attempt_time ASC NULLS FIRST)
+WHERE type IN ('ipv4', 'ipv6', 'onionv2', 'onionv3', 'i2p', 'cjdns', 'yggdrasil');
+
+-- 4. Las instantáneas horarias se guardan por red.
+ALTER TABLE hourly_stats ADD COLUMN IF NOT EXISTS network text NOT NULL DEFAULT 'mainnet';
+ALTER TABLE hourly_stats DROP CONSTRAINT IF EXISTS hourly_stats_pkey;
+ALTER TABLE hourly_stats ADD PRIMARY KEY (snapshot_time, network);
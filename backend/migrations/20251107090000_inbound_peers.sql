@@ -0,0 +1,8 @@
+-- Registro de pares que se conectan a nuestro listener.
+-- 'last_inbound' es la última vez que el nodo nos abrió una conexión entrante;
+-- 'inbound_only' marca los nodos vistos como entrantes pero que no aceptan
+-- conexiones salientes desde el crawler.
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS last_inbound timestamp with time zone;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS inbound_only boolean NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS idi_bnetwork_inbound_only ON bnetwork (network) WHERE (inbound_only = true);
@@ -0,0 +1,5 @@
+-- Intentos fallidos mientras nuestra propia conectividad estaba caída.
+-- No cuentan como fallos del nodo: no tocan 'consecutive_failures',
+-- 'reliability_score' ni 'incoming'.
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS vantage_failures integer NOT NULL DEFAULT 0;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS last_vantage_failure timestamp with time zone;
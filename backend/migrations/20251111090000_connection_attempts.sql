@@ -0,0 +1,26 @@
+-- Registro de cada intento de conexión saliente del crawler.
+-- 'outcome': refused, timeout, reset, socks_error, bad_magic, no_version,
+-- handshake_complete u other. Los campos del handshake solo se rellenan
+-- cuando el nodo llegó a enviar 'version'.
+CREATE TABLE IF NOT EXISTS connection_attempts (
+    id bigserial PRIMARY KEY,
+    network text NOT NULL,
+    address text NOT NULL,
+    port integer,
+    attempted_at timestamp with time zone NOT NULL,
+    duration_ms integer NOT NULL,
+    outcome text NOT NULL,
+    error text,
+    vantage_failure boolean NOT NULL DEFAULT FALSE,
+    user_agent text,
+    services text,
+    protocol_version integer,
+    start_height integer,
+    relay boolean
+);
+
+CREATE INDEX IF NOT EXISTS idx_connection_attempts_node
+    ON connection_attempts (network, address, attempted_at DESC);
+
+CREATE INDEX IF NOT EXISTS idx_connection_attempts_time
+    ON connection_attempts (attempted_at);
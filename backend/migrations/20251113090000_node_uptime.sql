@@ -0,0 +1,13 @@
+-- Métricas de disponibilidad calculadas a partir de 'connection_attempts'.
+-- Se recalculan periódicamente y se guardan aquí para poder ordenar la lista
+-- de nodos. Los porcentajes son NULL cuando no hay intentos en la ventana.
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_2h real;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_8h real;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_24h real;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_7d real;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_30d real;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS longest_streak_seconds bigint NOT NULL DEFAULT 0;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS current_streak_seconds bigint NOT NULL DEFAULT 0;
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS uptime_updated timestamp with time zone;
+
+CREATE INDEX IF NOT EXISTS idi_bnetwork_uptime_30d ON bnetwork (network, uptime_30d DESC NULLS LAST);
@@ -0,0 +1,31 @@
+-- 1. Un nodo queda identificado por (network, address, port). Los nodos
+--    antiguos sin puerto reciben el puerto por defecto de su red.
+UPDATE bnetwork SET port = CASE network
+        WHEN 'testnet' THEN 18333
+        WHEN 'testnet4' THEN 48333
+        WHEN 'signet' THEN 38333
+        WHEN 'regtest' THEN 18444
+        ELSE 8333
+    END
+WHERE port IS NULL;
+ALTER TABLE bnetwork ALTER COLUMN port SET NOT NULL;
+
+-- 2. Clave sustituta 'id' y unicidad sobre (network, address, port).
+ALTER TABLE bnetwork ADD COLUMN IF NOT EXISTS id bigserial;
+ALTER TABLE bnetwork DROP CONSTRAINT IF EXISTS bnetwork_pkey;
+ALTER TABLE bnetwork ADD PRIMARY KEY (id);
+ALTER TABLE bnetwork ADD CONSTRAINT bnetwork_network_address_port_key UNIQUE (network, address, port);
+
+-- 3. Los intentos de conexión apuntan al nodo por su id.
+ALTER TABLE connection_attempts ADD COLUMN IF NOT EXISTS node_id bigint;
+UPDATE connection_attempts c SET node_id = b.id
+FROM bnetwork b
+WHERE b.network = c.network AND b.address = c.address AND b.port = c.port;
+DELETE FROM connection_attempts WHERE node_id IS NULL;
+ALTER TABLE connection_attempts ALTER COLUMN node_id SET NOT NULL;
+ALTER TABLE connection_attempts ALTER COLUMN port SET NOT NULL;
+ALTER TABLE connection_attempts ADD CONSTRAINT connection_attempts_node_id_fkey
+    FOREIGN KEY (node_id) REFERENCES bnetwork (id) ON DELETE CASCADE;
+
+DROP INDEX IF EXISTS idx_connection_attempts_node;
+CREATE INDEX idx_connection_attempts_node ON connection_attempts (node_id, attempted_at DESC);
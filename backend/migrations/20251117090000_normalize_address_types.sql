@@ -0,0 +1,11 @@
+-- Los mensajes 'addr' (v1) guardaban los onion v2 como 'onionV2', que nunca
+-- coincidía con la cola del crawler. Se normalizan los tipos y se limita la
+-- columna a los valores de AddressType.
+UPDATE bnetwork SET type = lower(type) WHERE type <> lower(type);
+
+DELETE FROM bnetwork
+WHERE type IS NOT NULL
+  AND type NOT IN ('ipv4', 'ipv6', 'onionv2', 'onionv3', 'i2p', 'cjdns', 'yggdrasil');
+
+ALTER TABLE bnetwork ADD CONSTRAINT bnetwork_type_check
+    CHECK (type IN ('ipv4', 'ipv6', 'onionv2', 'onionv3', 'i2p', 'cjdns', 'yggdrasil'));
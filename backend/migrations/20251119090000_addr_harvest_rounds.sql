@@ -0,0 +1,16 @@
+-- Estadísticas de cada ronda de recolección de direcciones (HARVEST_MODE).
+-- 'new_addresses' cuenta las direcciones únicas que no habían aparecido en las
+-- rondas anteriores de la misma pasada sobre el nodo.
+CREATE TABLE IF NOT EXISTS addr_harvest_rounds (
+    id bigserial PRIMARY KEY,
+    node_id bigint NOT NULL REFERENCES bnetwork (id) ON DELETE CASCADE,
+    network text NOT NULL,
+    harvested_at timestamp with time zone NOT NULL,
+    round integer NOT NULL,
+    addr_messages integer NOT NULL,
+    addresses integer NOT NULL,
+    new_addresses integer NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_addr_harvest_rounds_time
+    ON addr_harvest_rounds (network, harvested_at);
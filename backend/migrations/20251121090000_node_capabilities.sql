@@ -0,0 +1,14 @@
+-- Capacidades negociadas por cada nodo durante el handshake (BIP130, BIP133,
+-- BIP152, BIP155, BIP339). Solo se rellenan cuando el nodo llega a enviar
+-- 'verack'; 'feefilter' está en sat/kvB.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS sendaddrv2 boolean,
+    ADD COLUMN IF NOT EXISTS wtxidrelay boolean,
+    ADD COLUMN IF NOT EXISTS sendheaders boolean,
+    ADD COLUMN IF NOT EXISTS sendcmpct_version bigint,
+    ADD COLUMN IF NOT EXISTS feefilter bigint,
+    ADD COLUMN IF NOT EXISTS capabilities_updated timestamp with time zone;
+
+-- Distribución de capacidades en cada instantánea horaria.
+ALTER TABLE hourly_stats
+    ADD COLUMN IF NOT EXISTS capabilities jsonb;
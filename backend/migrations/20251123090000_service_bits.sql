@@ -0,0 +1,33 @@
+-- Los servicios dejan de guardarse como el texto de ServiceFlags
+-- ('ServiceFlags(NETWORK|WITNESS|0x800)') y pasan a ser el campo de bits
+-- original (u64 reinterpretado como bigint).
+CREATE FUNCTION pg_temp.service_flags_bits(flags text) RETURNS bigint AS $$
+    SELECT CASE WHEN flags IS NULL THEN NULL ELSE COALESCE((
+        SELECT bit_or(
+            CASE
+                WHEN token = 'NETWORK' THEN 1
+                WHEN token = 'GETUTXO' THEN 2
+                WHEN token = 'BLOOM' THEN 4
+                WHEN token = 'WITNESS' THEN 8
+                WHEN token = 'COMPACT_FILTERS' THEN 64
+                WHEN token = 'NETWORK_LIMITED' THEN 1024
+                WHEN token = 'P2P_V2' THEN 2048
+                WHEN token ~ '^0x[0-9a-f]{1,16}$'
+                    THEN ('x' || lpad(substr(token, 3), 16, '0'))::bit(64)::bigint
+                ELSE 0
+            END)
+        FROM regexp_split_to_table(substring(flags FROM '^ServiceFlags\((.*)\)$'), '\|') AS token
+    ), 0) END
+$$ LANGUAGE sql IMMUTABLE;
+
+DROP INDEX IF EXISTS idi_bnetwork_services_gin;
+
+ALTER TABLE bnetwork
+    ALTER COLUMN services TYPE bigint USING pg_temp.service_flags_bits(services);
+
+ALTER TABLE connection_attempts
+    ALTER COLUMN services TYPE bigint USING pg_temp.service_flags_bits(services);
+
+-- Nodos alcanzables por bit de servicio en cada instantánea horaria.
+ALTER TABLE hourly_stats
+    ADD COLUMN IF NOT EXISTS service_bits jsonb;
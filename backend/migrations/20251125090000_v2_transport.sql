@@ -0,0 +1,14 @@
+-- Resultado del último handshake BIP324 (transporte cifrado v2) con cada nodo.
+-- Sólo se intenta con los que anuncian P2P_V2 y sólo se guarda cuando el nodo
+-- llega a responder, sea en v2 o tras volver a v1; NULL = nunca comprobado.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS v2_handshake boolean,
+    ADD COLUMN IF NOT EXISTS v2_checked timestamp with time zone;
+
+-- Transporte negociado en cada intento (NULL si no se intentó v2).
+ALTER TABLE connection_attempts
+    ADD COLUMN IF NOT EXISTS v2_handshake boolean;
+
+-- Adopción de v2 en cada instantánea horaria.
+ALTER TABLE hourly_stats
+    ADD COLUMN IF NOT EXISTS transport jsonb;
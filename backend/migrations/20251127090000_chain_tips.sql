@@ -0,0 +1,18 @@
+-- Punta de cadena de cada nodo según el último sondeo con 'getheaders':
+-- 'synced', 'lagging', 'forked' o 'no_response' (el nodo no contestó, p. ej.
+-- por estar en IBD). 'tip_lag' son los bloques que le faltan respecto a la
+-- referencia del crawler (negativo si va por delante en otra rama).
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS tip_status text,
+    ADD COLUMN IF NOT EXISTS tip_hash text,
+    ADD COLUMN IF NOT EXISTS tip_height integer,
+    ADD COLUMN IF NOT EXISTS tip_lag integer,
+    ADD COLUMN IF NOT EXISTS tip_checked timestamp with time zone;
+
+-- Punta de la cadena de referencia que mantiene el crawler para cada red.
+CREATE TABLE IF NOT EXISTS chain_reference (
+    network text PRIMARY KEY,
+    height integer NOT NULL,
+    hash text NOT NULL,
+    updated_at timestamp with time zone NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Verificación de nodos de archivo: si el nodo sirvió el bloque 1 al pedirlo
+-- con 'getdata' y cuánto tardó. 'archive_mismatch' marca a los nodos cuyo
+-- flag NETWORK no coincide con lo comprobado.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS archive_served boolean,
+    ADD COLUMN IF NOT EXISTS archive_latency_ms integer,
+    ADD COLUMN IF NOT EXISTS archive_mismatch boolean,
+    ADD COLUMN IF NOT EXISTS archive_checked timestamp with time zone;
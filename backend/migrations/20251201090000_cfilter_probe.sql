@@ -0,0 +1,14 @@
+-- Verificación de filtros compactos (BIP157): si el nodo contestó bien a
+-- 'getcfcheckpt', la parada pedida, un hash de las cabeceras de filtro
+-- recibidas y si coincide con la mayoría de nodos con la misma parada.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS cfilter_served boolean,
+    ADD COLUMN IF NOT EXISTS cfilter_stop_height integer,
+    ADD COLUMN IF NOT EXISTS cfilter_stop_hash text,
+    ADD COLUMN IF NOT EXISTS cfilter_digest text,
+    ADD COLUMN IF NOT EXISTS cfilter_consensus boolean,
+    ADD COLUMN IF NOT EXISTS cfilter_checked timestamp with time zone;
+
+CREATE INDEX IF NOT EXISTS idi_bnetwork_cfilter_stop
+    ON bnetwork (network, cfilter_stop_hash)
+    WHERE cfilter_stop_hash IS NOT NULL;
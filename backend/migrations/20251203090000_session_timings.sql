@@ -0,0 +1,8 @@
+-- Tiempos de cada intento saliente: conexión, handshake ('version' enviado ->
+-- 'verack' recibido) y RTT de nuestro 'ping', más el desfase del reloj del
+-- peer según el 'timestamp' de su 'version' (segundos, positivo si adelanta).
+ALTER TABLE connection_attempts
+    ADD COLUMN IF NOT EXISTS connect_ms integer,
+    ADD COLUMN IF NOT EXISTS handshake_ms integer,
+    ADD COLUMN IF NOT EXISTS ping_ms integer,
+    ADD COLUMN IF NOT EXISTS clock_offset_s bigint;
@@ -0,0 +1,38 @@
+-- Nodos retirados por la política de retención, con su último estado
+-- conocido, para estudiar la rotación y la vida de los nodos. Un mismo nodo
+-- puede aparecer varias veces si se redescubre y vuelve a retirarse.
+CREATE TABLE IF NOT EXISTS retired_nodes (
+    id bigserial PRIMARY KEY,
+    node_id bigint NOT NULL,
+    network text NOT NULL,
+    address text NOT NULL,
+    port integer NOT NULL,
+    type text,
+    added timestamp with time zone,
+    detected timestamp with time zone,
+    scanned timestamp with time zone,
+    soft text,
+    services text,
+    protocol_version integer,
+    start_height integer,
+    country text,
+    region text,
+    city text,
+    isp text,
+    asn text,
+    latitude real,
+    longitude real,
+    incoming boolean,
+    last_inbound timestamp with time zone,
+    reliability_score integer,
+    consecutive_failures integer,
+    uptime_30d real,
+    longest_streak_seconds bigint,
+    retired_at timestamp with time zone NOT NULL DEFAULT NOW(),
+    -- 'unseen': nadie lo ha anunciado en el plazo; 'unreachable': onion con
+    -- demasiados fallos seguidos.
+    reason text NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_retired_nodes_time ON retired_nodes (network, retired_at DESC);
+CREATE INDEX IF NOT EXISTS idx_retired_nodes_address ON retired_nodes (network, address);
@@ -0,0 +1,39 @@
+-- 'detected' sigue siendo la última vez que un peer nos habló del nodo, pero
+-- eso no dice si está vivo: las direcciones viejas se siguen retransmitiendo.
+-- Aparte se guarda la marca de tiempo más reciente anunciada en 'addr', el
+-- último handshake completo y cuántos peers distintos lo han anunciado.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS last_gossip timestamp with time zone,
+    ADD COLUMN IF NOT EXISTS last_handshake timestamp with time zone,
+    ADD COLUMN IF NOT EXISTS announcers integer NOT NULL DEFAULT 0;
+
+-- Un par por nodo anunciado y peer que lo anunció. Se purga a los 35 días,
+-- como los intentos de conexión; 'announcers' cuenta los pares vigentes.
+CREATE TABLE IF NOT EXISTS node_announcements (
+    node_id bigint NOT NULL REFERENCES bnetwork (id) ON DELETE CASCADE,
+    announcer_id bigint NOT NULL REFERENCES bnetwork (id) ON DELETE CASCADE,
+    first_announced timestamp with time zone NOT NULL,
+    last_announced timestamp with time zone NOT NULL,
+    PRIMARY KEY (node_id, announcer_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_node_announcements_announcer ON node_announcements (announcer_id);
+CREATE INDEX IF NOT EXISTS idx_node_announcements_time ON node_announcements (last_announced);
+
+-- Datos existentes: el último handshake sale de los intentos guardados y, a
+-- falta de la marca anunciada, se toma 'detected' para no retirar de golpe
+-- todos los nodos al actualizar.
+UPDATE bnetwork b SET last_handshake = a.last_handshake
+FROM (
+    SELECT node_id, MAX(attempted_at) AS last_handshake
+    FROM connection_attempts
+    WHERE outcome = 'handshake_complete'
+    GROUP BY node_id
+) a
+WHERE a.node_id = b.id;
+UPDATE bnetwork SET last_gossip = detected WHERE last_gossip IS NULL;
+
+ALTER TABLE retired_nodes
+    ADD COLUMN IF NOT EXISTS last_gossip timestamp with time zone,
+    ADD COLUMN IF NOT EXISTS last_handshake timestamp with time zone,
+    ADD COLUMN IF NOT EXISTS announcers integer;
@@ -0,0 +1,28 @@
+-- Retirar un nodo ya no borra su historial: los intentos de conexión, las
+-- rondas de recolección y los anuncios se conservan con la referencia al nodo
+-- a NULL. Los intentos siguen llevando red, dirección y puerto, así que se
+-- pueden cruzar con 'retired_nodes'.
+ALTER TABLE connection_attempts DROP CONSTRAINT IF EXISTS connection_attempts_node_id_fkey;
+ALTER TABLE connection_attempts ALTER COLUMN node_id DROP NOT NULL;
+ALTER TABLE connection_attempts ADD CONSTRAINT connection_attempts_node_id_fkey
+    FOREIGN KEY (node_id) REFERENCES bnetwork (id) ON DELETE SET NULL;
+
+ALTER TABLE addr_harvest_rounds DROP CONSTRAINT IF EXISTS addr_harvest_rounds_node_id_fkey;
+ALTER TABLE addr_harvest_rounds ALTER COLUMN node_id DROP NOT NULL;
+ALTER TABLE addr_harvest_rounds ADD CONSTRAINT addr_harvest_rounds_node_id_fkey
+    FOREIGN KEY (node_id) REFERENCES bnetwork (id) ON DELETE SET NULL;
+
+ALTER TABLE node_announcements DROP CONSTRAINT IF EXISTS node_announcements_pkey;
+ALTER TABLE node_announcements DROP CONSTRAINT IF EXISTS node_announcements_node_id_fkey;
+ALTER TABLE node_announcements DROP CONSTRAINT IF EXISTS node_announcements_announcer_id_fkey;
+ALTER TABLE node_announcements ALTER COLUMN node_id DROP NOT NULL;
+ALTER TABLE node_announcements ALTER COLUMN announcer_id DROP NOT NULL;
+ALTER TABLE node_announcements ADD CONSTRAINT node_announcements_node_id_announcer_id_key
+    UNIQUE (node_id, announcer_id);
+ALTER TABLE node_announcements ADD CONSTRAINT node_announcements_node_id_fkey
+    FOREIGN KEY (node_id) REFERENCES bnetwork (id) ON DELETE SET NULL;
+ALTER TABLE node_announcements ADD CONSTRAINT node_announcements_announcer_id_fkey
+    FOREIGN KEY (announcer_id) REFERENCES bnetwork (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_connection_attempts_address
+    ON connection_attempts (network, address, port, attempted_at DESC);
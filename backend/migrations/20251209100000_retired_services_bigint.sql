@@ -0,0 +1,3 @@
+-- 'services' es un bigint en 'bnetwork'; en 'retired_nodes' se creó como texto
+-- y no se podía leer como ServiceBits.
+ALTER TABLE retired_nodes ALTER COLUMN services TYPE bigint USING services::bigint;
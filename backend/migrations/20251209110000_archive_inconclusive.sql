@@ -0,0 +1,4 @@
+-- Verificaciones de archivo sin respuesta (plazo vencido o desconexión)
+-- seguidas. No cuentan como bloque no servido hasta que se repiten.
+ALTER TABLE bnetwork
+    ADD COLUMN IF NOT EXISTS archive_failures integer NOT NULL DEFAULT 0;
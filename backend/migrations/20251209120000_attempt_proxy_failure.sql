@@ -0,0 +1,4 @@
+-- Intentos que fallaron por el proxy (Tor, I2P) y no por el nodo. No cuentan
+-- como caída del nodo en el cálculo de disponibilidad.
+ALTER TABLE connection_attempts
+    ADD COLUMN IF NOT EXISTS proxy_failure boolean NOT NULL DEFAULT FALSE;
@@ -0,0 +1,499 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use bitcoin::p2p::address::AddrV2;
+use data_encoding::BASE32_NOPAD;
+use sha3::{Digest, Sha3_256};
+
+// Prefijo OnionCat con el que las direcciones Tor v2 viajan en mensajes 'addr' (v1).
+const ONIONCAT_PREFIX: [u8; 6] = [0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43];
+// Identificador de red AddrV2 que usa Yggdrasil (no está en BIP155).
+const YGGDRASIL_NETWORK_ID: u8 = 0x07;
+const TORV3_VERSION: u8 = 0x03;
+const TORV3_CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressType {
+    Ipv4,
+    Ipv6,
+    OnionV2,
+    OnionV3,
+    I2p,
+    Cjdns,
+    Yggdrasil,
+}
+
+impl AddressType {
+    pub const ALL: [AddressType; 7] = [
+        AddressType::Ipv4,
+        AddressType::Ipv6,
+        AddressType::OnionV2,
+        AddressType::OnionV3,
+        AddressType::I2p,
+        AddressType::Cjdns,
+        AddressType::Yggdrasil,
+    ];
+
+    // Valor guardado en la columna 'type' de bnetwork.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressType::Ipv4 => "ipv4",
+            AddressType::Ipv6 => "ipv6",
+            AddressType::OnionV2 => "onionv2",
+            AddressType::OnionV3 => "onionv3",
+            AddressType::I2p => "i2p",
+            AddressType::Cjdns => "cjdns",
+            AddressType::Yggdrasil => "yggdrasil",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        AddressType::ALL
+            .iter()
+            .copied()
+            .find(|t| t.as_str().eq_ignore_ascii_case(name))
+    }
+
+    pub fn is_onion(&self) -> bool {
+        matches!(self, AddressType::OnionV2 | AddressType::OnionV3)
+    }
+
+    pub fn is_ip(&self) -> bool {
+        matches!(self, AddressType::Ipv4 | AddressType::Ipv6)
+    }
+}
+
+impl fmt::Display for AddressType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NetAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    TorV2([u8; 10]),
+    TorV3([u8; 32]),
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    Yggdrasil(Ipv6Addr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    Invalid(String),
+    OnionChecksum(String),
+    OnionVersion(String, u8),
+    UnsupportedAddrV2(u8, usize),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::Invalid(address) => write!(f, "Dirección inválida: '{}'", address),
+            AddressError::OnionChecksum(address) => {
+                write!(f, "Checksum onion v3 incorrecto: '{}'", address)
+            }
+            AddressError::OnionVersion(address, version) => write!(
+                f,
+                "Versión onion no soportada ({}) en '{}'",
+                version, address
+            ),
+            AddressError::UnsupportedAddrV2(network_id, len) => write!(
+                f,
+                "Tipo AddrV2 no soportado: ID={}, longitud={}",
+                network_id, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl NetAddress {
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            NetAddress::Ipv4(_) => AddressType::Ipv4,
+            NetAddress::Ipv6(_) => AddressType::Ipv6,
+            NetAddress::TorV2(_) => AddressType::OnionV2,
+            NetAddress::TorV3(_) => AddressType::OnionV3,
+            NetAddress::I2p(_) => AddressType::I2p,
+            NetAddress::Cjdns(_) => AddressType::Cjdns,
+            NetAddress::Yggdrasil(_) => AddressType::Yggdrasil,
+        }
+    }
+
+    // Direcciones IP vistas en sockets o mensajes 'addr': las IPv4 mapeadas se
+    // normalizan a IPv4 y los rangos fc00::/8 y 200::/7 se tratan como CJDNS y
+    // Yggdrasil.
+    pub fn from_ip(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => NetAddress::Ipv4(ip),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ipv4) => NetAddress::Ipv4(ipv4),
+                None if is_cjdns(&ip) => NetAddress::Cjdns(ip),
+                None if is_yggdrasil(&ip) => NetAddress::Yggdrasil(ip),
+                None => NetAddress::Ipv6(ip),
+            },
+        }
+    }
+
+    // Dirección de un mensaje 'addr' (v1): IPv6 de 16 bytes con OnionCat para Tor v2.
+    pub fn from_legacy(segments: [u16; 8]) -> Self {
+        let ip = Ipv6Addr::from(segments);
+        let octets = ip.octets();
+        if octets[..6] == ONIONCAT_PREFIX {
+            let mut onion = [0u8; 10];
+            onion.copy_from_slice(&octets[6..]);
+            return NetAddress::TorV2(onion);
+        }
+        NetAddress::from_ip(IpAddr::V6(ip))
+    }
+
+    pub fn from_addrv2(addr: &AddrV2) -> Result<Self, AddressError> {
+        match addr {
+            AddrV2::Ipv4(ip) => Ok(NetAddress::Ipv4(*ip)),
+            AddrV2::Ipv6(ip) => Ok(NetAddress::Ipv6(*ip)),
+            AddrV2::TorV2(bytes) => Ok(NetAddress::TorV2(*bytes)),
+            AddrV2::TorV3(bytes) => Ok(NetAddress::TorV3(*bytes)),
+            AddrV2::I2p(bytes) => Ok(NetAddress::I2p(*bytes)),
+            AddrV2::Cjdns(ip) => Ok(NetAddress::Cjdns(*ip)),
+            AddrV2::Unknown(YGGDRASIL_NETWORK_ID, bytes) if bytes.len() == 16 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(bytes);
+                Ok(NetAddress::Yggdrasil(Ipv6Addr::from(octets)))
+            }
+            AddrV2::Unknown(network_id, bytes) => {
+                Err(AddressError::UnsupportedAddrV2(*network_id, bytes.len()))
+            }
+        }
+    }
+
+    pub fn to_addrv2(&self) -> AddrV2 {
+        match self {
+            NetAddress::Ipv4(ip) => AddrV2::Ipv4(*ip),
+            NetAddress::Ipv6(ip) => AddrV2::Ipv6(*ip),
+            NetAddress::TorV2(bytes) => AddrV2::TorV2(*bytes),
+            NetAddress::TorV3(bytes) => AddrV2::TorV3(*bytes),
+            NetAddress::I2p(bytes) => AddrV2::I2p(*bytes),
+            NetAddress::Cjdns(ip) => AddrV2::Cjdns(*ip),
+            NetAddress::Yggdrasil(ip) => {
+                AddrV2::Unknown(YGGDRASIL_NETWORK_ID, ip.octets().to_vec())
+            }
+        }
+    }
+
+    // Dirección IP a la que conectar directamente (sin proxy).
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            NetAddress::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            NetAddress::Ipv6(ip) | NetAddress::Cjdns(ip) | NetAddress::Yggdrasil(ip) => {
+                Some(IpAddr::V6(*ip))
+            }
+            NetAddress::TorV2(_) | NetAddress::TorV3(_) | NetAddress::I2p(_) => None,
+        }
+    }
+
+    pub fn parse(input: &str) -> Result<Self, AddressError> {
+        let lower = input.trim().to_ascii_lowercase();
+        let invalid = || AddressError::Invalid(input.to_string());
+
+        if let Some(host) = lower.strip_suffix(".onion") {
+            let bytes = BASE32_NOPAD
+                .decode(host.to_ascii_uppercase().as_bytes())
+                .map_err(|_| invalid())?;
+            return match bytes.len() {
+                10 => {
+                    let mut onion = [0u8; 10];
+                    onion.copy_from_slice(&bytes);
+                    Ok(NetAddress::TorV2(onion))
+                }
+                35 => {
+                    let mut pubkey = [0u8; 32];
+                    pubkey.copy_from_slice(&bytes[..32]);
+                    if bytes[34] != TORV3_VERSION {
+                        return Err(AddressError::OnionVersion(input.to_string(), bytes[34]));
+                    }
+                    if bytes[32..34] != torv3_checksum(&pubkey) {
+                        return Err(AddressError::OnionChecksum(input.to_string()));
+                    }
+                    Ok(NetAddress::TorV3(pubkey))
+                }
+                _ => Err(invalid()),
+            };
+        }
+
+        if let Some(host) = lower.strip_suffix(".b32.i2p") {
+            let bytes = BASE32_NOPAD
+                .decode(host.to_ascii_uppercase().as_bytes())
+                .map_err(|_| invalid())?;
+            let key: [u8; 32] = bytes.as_slice().try_into().map_err(|_| invalid())?;
+            return Ok(NetAddress::I2p(key));
+        }
+
+        let host = lower.trim_start_matches('[').trim_end_matches(']');
+        host.parse::<IpAddr>()
+            .map(NetAddress::from_ip)
+            .map_err(|_| invalid())
+    }
+}
+
+impl NetAddress {
+    // Interpreta una dirección guardada con su tipo. Una IPv6 de los rangos
+    // CJDNS/Yggdrasil llegada como IPv6 en AddrV2 conserva el tipo guardado.
+    pub fn parse_as(input: &str, address_type: AddressType) -> Result<Self, AddressError> {
+        let parsed = NetAddress::parse(input)?;
+        if parsed.address_type() == address_type {
+            return Ok(parsed);
+        }
+
+        match (parsed.ip(), address_type) {
+            (Some(IpAddr::V6(ip)), AddressType::Ipv6) => Ok(NetAddress::Ipv6(ip)),
+            (Some(IpAddr::V6(ip)), AddressType::Cjdns) => Ok(NetAddress::Cjdns(ip)),
+            (Some(IpAddr::V6(ip)), AddressType::Yggdrasil) => Ok(NetAddress::Yggdrasil(ip)),
+            _ => Err(AddressError::Invalid(input.to_string())),
+        }
+    }
+}
+
+impl FromStr for NetAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NetAddress::parse(s)
+    }
+}
+
+// Formato canónico, el mismo que se guarda en la columna 'address'.
+impl fmt::Display for NetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetAddress::Ipv4(ip) => write!(f, "{}", ip),
+            NetAddress::Ipv6(ip) | NetAddress::Cjdns(ip) | NetAddress::Yggdrasil(ip) => {
+                write!(f, "{}", ip)
+            }
+            NetAddress::TorV2(bytes) => {
+                write!(f, "{}.onion", BASE32_NOPAD.encode(bytes).to_lowercase())
+            }
+            NetAddress::TorV3(pubkey) => {
+                let mut full = [0u8; 35];
+                full[..32].copy_from_slice(pubkey);
+                full[32..34].copy_from_slice(&torv3_checksum(pubkey));
+                full[34] = TORV3_VERSION;
+                write!(f, "{}.onion", BASE32_NOPAD.encode(&full).to_lowercase())
+            }
+            NetAddress::I2p(bytes) => {
+                write!(f, "{}.b32.i2p", BASE32_NOPAD.encode(bytes).to_lowercase())
+            }
+        }
+    }
+}
+
+pub fn is_cjdns(ip: &Ipv6Addr) -> bool {
+    ip.octets()[0] == 0xfc
+}
+
+pub fn is_yggdrasil(ip: &Ipv6Addr) -> bool {
+    ip.octets()[0] & 0xfe == 0x02
+}
+
+fn torv3_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(TORV3_CHECKSUM_PREFIX);
+    hasher.update(pubkey);
+    hasher.update([TORV3_VERSION]);
+    let hash = hasher.finalize();
+    [hash[0], hash[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUCKDUCKGO: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    // Dirección onion v3 con los 35 bytes (clave, checksum, versión) dados.
+    fn onion_v3(bytes: &[u8; 35]) -> String {
+        format!("{}.onion", BASE32_NOPAD.encode(bytes).to_lowercase())
+    }
+
+    fn onion_v3_bytes(pubkey: [u8; 32]) -> [u8; 35] {
+        let mut full = [0u8; 35];
+        full[..32].copy_from_slice(&pubkey);
+        full[32..34].copy_from_slice(&torv3_checksum(&pubkey));
+        full[34] = TORV3_VERSION;
+        full
+    }
+
+    #[test]
+    fn parses_known_onion_v3() {
+        let address = NetAddress::parse(DUCKDUCKGO).unwrap();
+        assert_eq!(address.address_type(), AddressType::OnionV3);
+        assert_eq!(address.to_string(), DUCKDUCKGO);
+        assert_eq!(
+            NetAddress::parse(&DUCKDUCKGO.to_uppercase()).unwrap(),
+            address
+        );
+    }
+
+    #[test]
+    fn rejects_bad_onion_v3_checksum() {
+        let mut bytes = onion_v3_bytes([7u8; 32]);
+        assert!(NetAddress::parse(&onion_v3(&bytes)).is_ok());
+        bytes[32] ^= 1;
+        let input = onion_v3(&bytes);
+        assert_eq!(
+            NetAddress::parse(&input),
+            Err(AddressError::OnionChecksum(input.clone()))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_onion_version() {
+        let mut bytes = onion_v3_bytes([7u8; 32]);
+        bytes[34] = 0x04;
+        let input = onion_v3(&bytes);
+        assert_eq!(
+            NetAddress::parse(&input),
+            Err(AddressError::OnionVersion(input.clone(), 0x04))
+        );
+    }
+
+    #[test]
+    fn display_round_trips_per_type() {
+        let i2p = format!("{}.b32.i2p", BASE32_NOPAD.encode(&[9u8; 32]).to_lowercase());
+        let cases = [
+            ("1.2.3.4", AddressType::Ipv4),
+            ("2001:db8::1", AddressType::Ipv6),
+            ("expyuzz4wqqyqhjn.onion", AddressType::OnionV2),
+            (DUCKDUCKGO, AddressType::OnionV3),
+            (i2p.as_str(), AddressType::I2p),
+            ("fc00::1", AddressType::Cjdns),
+            ("200:1234::1", AddressType::Yggdrasil),
+        ];
+        for (input, address_type) in cases.iter() {
+            let address = NetAddress::parse(input).unwrap();
+            assert_eq!(address.address_type(), *address_type, "{}", input);
+            assert_eq!(address.to_string(), *input);
+            assert_eq!(
+                NetAddress::parse_as(&address.to_string(), *address_type).unwrap(),
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn normalizes_ip_input() {
+        assert_eq!(
+            NetAddress::parse("[2001:db8::1]").unwrap(),
+            NetAddress::Ipv6("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(
+            NetAddress::parse(" ::ffff:1.2.3.4 ").unwrap(),
+            NetAddress::Ipv4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn parse_as_keeps_stored_overlay_type() {
+        let ip: Ipv6Addr = "fc00::1".parse().unwrap();
+        assert_eq!(
+            NetAddress::parse_as("fc00::1", AddressType::Ipv6).unwrap(),
+            NetAddress::Ipv6(ip)
+        );
+        assert_eq!(
+            NetAddress::parse_as("2001:db8::1", AddressType::Yggdrasil).unwrap(),
+            NetAddress::Yggdrasil("2001:db8::1".parse().unwrap())
+        );
+        assert!(NetAddress::parse_as("1.2.3.4", AddressType::Cjdns).is_err());
+        assert!(NetAddress::parse_as("1.2.3.4", AddressType::OnionV3).is_err());
+    }
+
+    #[test]
+    fn addrv2_round_trips() {
+        let addresses = [
+            NetAddress::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+            NetAddress::Ipv6("2001:db8::1".parse().unwrap()),
+            NetAddress::TorV2([1u8; 10]),
+            NetAddress::TorV3([2u8; 32]),
+            NetAddress::I2p([3u8; 32]),
+            NetAddress::Cjdns("fc00::1".parse().unwrap()),
+            NetAddress::Yggdrasil("200:1234::1".parse().unwrap()),
+        ];
+        for address in addresses.iter() {
+            assert_eq!(
+                NetAddress::from_addrv2(&address.to_addrv2()).as_ref(),
+                Ok(address)
+            );
+        }
+        assert_eq!(
+            NetAddress::Yggdrasil("200::1".parse().unwrap()).to_addrv2(),
+            AddrV2::Unknown(YGGDRASIL_NETWORK_ID, {
+                let ip: Ipv6Addr = "200::1".parse().unwrap();
+                ip.octets().to_vec()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_addrv2() {
+        assert_eq!(
+            NetAddress::from_addrv2(&AddrV2::Unknown(YGGDRASIL_NETWORK_ID, vec![0; 15])),
+            Err(AddressError::UnsupportedAddrV2(YGGDRASIL_NETWORK_ID, 15))
+        );
+        assert_eq!(
+            NetAddress::from_addrv2(&AddrV2::Unknown(9, vec![0; 4])),
+            Err(AddressError::UnsupportedAddrV2(9, 4))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        let i2p_short = format!("{}.b32.i2p", BASE32_NOPAD.encode(&[9u8; 31]).to_lowercase());
+        let inputs = [
+            "",
+            "no-es-una-direccion",
+            "1.2.3.4.5",
+            "abc.onion",
+            "!!!!!!!!!!!!!!!!.onion",
+            i2p_short.as_str(),
+        ];
+        for input in inputs.iter() {
+            assert_eq!(
+                NetAddress::parse(input),
+                Err(AddressError::Invalid(input.to_string())),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_onioncat_is_tor_v2() {
+        let mut octets = [0u8; 16];
+        octets[..6].copy_from_slice(&ONIONCAT_PREFIX);
+        octets[6..].copy_from_slice(&[5u8; 10]);
+        assert_eq!(
+            NetAddress::from_legacy(Ipv6Addr::from(octets).segments()),
+            NetAddress::TorV2([5u8; 10])
+        );
+        assert_eq!(
+            NetAddress::from_legacy(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().segments()),
+            NetAddress::Ipv4(Ipv4Addr::new(1, 2, 3, 4))
+        );
+    }
+
+    #[test]
+    fn address_type_names() {
+        for address_type in AddressType::ALL.iter() {
+            assert_eq!(
+                AddressType::from_name(&address_type.as_str().to_uppercase()),
+                Some(*address_type)
+            );
+        }
+        assert_eq!(AddressType::from_name("tor"), None);
+    }
+}
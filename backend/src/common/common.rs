@@ -11,13 +11,16 @@ use std::time::Duration;
 use tokio::net::TcpStream;
 
 use arc_swap::ArcSwap;
+use bitcoin::Network;
 use maxminddb::{geoip2, Reader};
 use std::net::IpAddr;
-#[derive(Clone)]
-pub struct TypeAddress {
-    pub address_type: String,
-    pub address: String,
-}
+
+#[path = "address.rs"]
+pub mod address;
+pub use address::{is_cjdns, is_yggdrasil, AddressError, AddressType, NetAddress};
+#[path = "services.rs"]
+pub mod services;
+pub use services::{service_bit_name, ServiceBits, NAMED_SERVICE_BITS};
 
 #[derive(Debug, Clone, Default)]
 pub struct GeoIpInfo {
@@ -33,33 +36,86 @@ pub struct AsnInfo {
     pub asn: u32,
 }
 
-pub fn first_nodes() -> Vec<SocketAddr> {
+pub fn network_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "mainnet",
+        Network::Testnet => "testnet",
+        Network::Testnet4 => "testnet4",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+pub fn network_from_name(name: &str) -> Option<Network> {
+    match name.to_lowercase().as_str() {
+        "mainnet" | "bitcoin" | "main" => Some(Network::Bitcoin),
+        "testnet" | "testnet3" => Some(Network::Testnet),
+        "testnet4" => Some(Network::Testnet4),
+        "signet" => Some(Network::Signet),
+        "regtest" => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+pub fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Testnet4 => 48333,
+        Network::Signet => 38333,
+        Network::Regtest => 18444,
+    }
+}
+
+fn dns_seeds(network: Network) -> Vec<&'static str> {
+    match network {
+        Network::Bitcoin => vec![
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "dnsseed.bitcoin.dashjr.org",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.org",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+            "seed.bitcoin.wiz.biz",
+        ],
+        Network::Testnet => vec![
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.net",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me",
+        ],
+        Network::Testnet4 => vec![
+            "seed.testnet4.bitcoin.sprovoost.nl",
+            "seed.testnet4.wiz.biz",
+        ],
+        Network::Signet => vec![
+            "seed.signet.bitcoin.sprovoost.nl",
+            "seed.signet.achownodes.xyz",
+        ],
+        Network::Regtest => vec![],
+    }
+}
+
+pub fn first_nodes(network: Network, extra_seeds: &[String]) -> Vec<SocketAddr> {
+    let port = default_port(network);
+    let mut seeds: Vec<String> = dns_seeds(network)
+        .into_iter()
+        .map(|host| format!("{}:{}", host, port))
+        .collect();
+    seeds.extend(extra_seeds.iter().cloned());
+
     let mut first_nodes = Vec::new();
-    let seeds = vec![
-        "seed.bitcoin.sipa.be:8333",
-        "dnsseed.bluematt.me:8333",
-        "dnsseed.bitcoin.dashjr.org:8333",
-        "seed.bitcoinstats.com:8333",
-        "seed.bitcoin.jonasschnelli.ch:8333",
-        "seed.btc.petertodd.org:8333",
-        "seed.bitcoin.sprovoost.nl:8333",
-        "seed.bitcoin.sprovoost.nl:8333",
-        "dnsseed.emzy.de:8333",
-        "seed.bitcoin.wiz.biz:8333",
-    ];
     for seed in seeds {
-        let server_details = seed;
-        let server: Vec<_> = server_details
-            .to_socket_addrs()
-            .expect("Unable to resolve domain")
-            .collect();
-        first_nodes.push(server);
-    }
-    let flatten_nodes = first_nodes
-        .into_iter()
-        .flatten()
-        .collect::<Vec<SocketAddr>>();
-    return flatten_nodes;
+        match seed.to_socket_addrs() {
+            Ok(server) => first_nodes.extend(server),
+            Err(e) => {
+                tracing::warn!("No se pudo resolver la semilla {}: {}", seed, e);
+            }
+        }
+    }
+    first_nodes
 }
 
 pub async fn scan_port_addr(addr: SocketAddr) -> bool {
@@ -71,47 +127,46 @@ pub async fn scan_port_addr(addr: SocketAddr) -> bool {
     }
 }
 
-pub fn type_address(addr: [u16; 8]) -> TypeAddress {
-    let ipv6 = Ipv6Addr::from(addr);
-    let onion = "fd87:d87e:eb43".to_string();
-    match ipv6.to_ipv4() {
-        Some(ip) => {
-            return TypeAddress {
-                address_type: "ipv4".to_string(),
-                address: ip.to_string(),
-            }
-        }
-        None => {
-            if ipv6.to_string().contains(&onion) {
-                return TypeAddress {
-                    address_type: "onionV2".to_string(),
-                    address: ipv6_to_onion(ipv6.to_string()),
-                };
-            } else {
-                return TypeAddress {
-                    address_type: "ipv6".to_string(),
-                    address: ipv6.to_string(),
-                };
-            }
+// Separa "host:puerto", "[ipv6]:puerto", "ipv6" u "host" en host y puerto opcional.
+pub fn split_host_port(input: &str) -> (String, Option<u16>) {
+    if let Some(rest) = input.strip_prefix('[') {
+        if let Some((host, tail)) = rest.split_once(']') {
+            let port = tail.strip_prefix(':').and_then(|p| p.parse().ok());
+            return (host.to_string(), port);
         }
     }
+
+    match input.split_once(':') {
+        Some((host, port)) if !port.contains(':') => match port.parse() {
+            Ok(port) => (host.to_string(), Some(port)),
+            Err(_) => (input.to_string(), None),
+        },
+        _ => (input.to_string(), None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OverlayInterfaces {
+    pub cjdns: Option<Ipv6Addr>,
+    pub yggdrasil: Option<Ipv6Addr>,
 }
 
-pub fn ipv6_to_onion(ipv6: String) -> String {
-    let mut step1: Vec<_> = ipv6.split(":").collect();
-    let _step2: Vec<_> = step1.drain(0..3).collect();
-    let mut step3: String = "".to_string();
-    for hextect in step1.iter_mut() {
-        if hextect.len() != 4 {
-            let good_hextect: String = format!("{:0>4}", hextect);
-            step3 = step3 + &good_hextect;
-        } else {
-            step3 = step3 + &hextect.to_string();
+impl OverlayInterfaces {
+    pub fn detect() -> Self {
+        OverlayInterfaces {
+            cjdns: local_route_source(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 1), is_cjdns),
+            yggdrasil: local_route_source(Ipv6Addr::new(0x200, 0, 0, 0, 0, 0, 0, 1), is_yggdrasil),
         }
     }
-    let encode = hex::decode(step3.as_bytes()).unwrap();
-    let encode1 = data_encoding::BASE32.encode(&encode).to_lowercase() + ".onion";
-    return encode1;
+}
+
+fn local_route_source(probe: Ipv6Addr, belongs_to: fn(&Ipv6Addr) -> bool) -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind("[::]:0").ok()?;
+    socket.connect(SocketAddr::new(IpAddr::V6(probe), 9)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) if belongs_to(&ip) => Some(ip),
+        _ => None,
+    }
 }
 
 pub fn isprivate(addr: [u16; 8]) -> bool {
@@ -0,0 +1,64 @@
+use bitcoin::p2p::ServiceFlags;
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+// Bits de servicio con nombre propio. El resto se muestran como "bit_N".
+pub const NAMED_SERVICE_BITS: [(u32, &str); 7] = [
+    (0, "NETWORK"),
+    (1, "GETUTXO"),
+    (2, "BLOOM"),
+    (3, "WITNESS"),
+    (6, "COMPACT_FILTERS"),
+    (10, "NETWORK_LIMITED"),
+    (11, "P2P_V2"),
+];
+
+pub fn service_bit_name(bit: u32) -> String {
+    NAMED_SERVICE_BITS
+        .iter()
+        .find(|(named, _)| *named == bit)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("bit_{}", bit))
+}
+
+// Campo de servicios tal y como llega en 'version'/'addr'. Se guarda en un
+// bigint de Postgres reinterpretando los 64 bits, y en la API se serializa
+// junto con la lista de flags decodificados.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct ServiceBits(pub i64);
+
+impl ServiceBits {
+    pub fn bits(&self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn has_bit(&self, bit: u32) -> bool {
+        self.bits() & (1u64 << bit) != 0
+    }
+
+    pub fn has(&self, flags: ServiceFlags) -> bool {
+        self.bits() & flags.to_u64() == flags.to_u64()
+    }
+
+    pub fn flag_names(&self) -> Vec<String> {
+        (0..64)
+            .filter(|bit| self.has_bit(*bit))
+            .map(service_bit_name)
+            .collect()
+    }
+}
+
+impl From<ServiceFlags> for ServiceBits {
+    fn from(flags: ServiceFlags) -> Self {
+        ServiceBits(flags.to_u64() as i64)
+    }
+}
+
+impl Serialize for ServiceBits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ServiceBits", 2)?;
+        state.serialize_field("bits", &self.bits())?;
+        state.serialize_field("flags", &self.flag_names())?;
+        state.end()
+    }
+}
@@ -0,0 +1,466 @@
+use anyhow::{Context, Result};
+use bitcoin::Network;
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::common::{network_from_name, network_name, AddressType};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub networks: Vec<Network>,
+    pub extra_seeds: HashMap<Network, Vec<String>>,
+    pub tor_proxy: ProxyConfig,
+    pub i2p_proxy: ProxyConfig,
+    pub vantage: VantageConfig,
+    pub harvest: HarvestConfig,
+    pub crawl: CrawlConfig,
+    pub schedule: ScheduleConfig,
+    // Umbrales de retención por red; los nodos vencidos pasan a 'retired_nodes'.
+    pub retention: HashMap<Network, RetentionConfig>,
+    // Cuánto se conservan los nodos retirados (None: siempre).
+    pub retired_keep: Option<Duration>,
+    // Sondear la punta de cadena de cada nodo con 'getheaders' tras el handshake.
+    pub chain_tip_probe: bool,
+    // Cada cuánto se vuelve a verificar que un nodo sirve bloques antiguos
+    // (None si la verificación está desactivada).
+    pub archive_probe_interval: Option<Duration>,
+    // Lo mismo para los filtros compactos (BIP157); necesita la cadena de
+    // referencia del sondeo de punta.
+    pub filter_probe_interval: Option<Duration>,
+    // Respuestas a una misma parada necesarias para decidir el consenso de
+    // filtros; por debajo queda sin decidir.
+    pub filter_consensus_min: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub stream_isolation: bool,
+}
+
+impl ProxyConfig {
+    fn from_env(prefix: &str, default_address: &str, default_isolation: bool) -> Result<Self> {
+        let address = env::var(format!("{}_PROXY_ADDRESS", prefix))
+            .unwrap_or_else(|_| default_address.to_string());
+        let username = env::var(format!("{}_PROXY_USERNAME", prefix)).ok();
+        let password = env::var(format!("{}_PROXY_PASSWORD", prefix)).ok();
+        let stream_isolation =
+            env_bool(&format!("{}_STREAM_ISOLATION", prefix), default_isolation)?;
+
+        if password.is_some() && username.is_none() {
+            anyhow::bail!(
+                "{}_PROXY_PASSWORD requiere también {}_PROXY_USERNAME",
+                prefix,
+                prefix
+            );
+        }
+        // El aislamiento usa credenciales aleatorias por conexión, que un
+        // proxy con contraseña rechazaría.
+        if password.is_some() && stream_isolation {
+            anyhow::bail!(
+                "{}_PROXY_PASSWORD no es compatible con {}_STREAM_ISOLATION; desactívalo con {}_STREAM_ISOLATION=false",
+                prefix,
+                prefix,
+                prefix
+            );
+        }
+
+        Ok(ProxyConfig {
+            address,
+            username,
+            password,
+            stream_isolation,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VantageConfig {
+    pub canaries: Vec<String>,
+    pub failure_threshold: f64,
+    pub min_batch: usize,
+}
+
+impl VantageConfig {
+    fn from_env() -> Result<Self> {
+        let canaries = env::var("VANTAGE_CANARIES")
+            .unwrap_or_else(|_| "1.1.1.1:443,8.8.8.8:443,9.9.9.9:443".to_string())
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect();
+
+        let failure_threshold = match env::var("VANTAGE_FAILURE_THRESHOLD") {
+            Ok(value) => value
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("VANTAGE_FAILURE_THRESHOLD inválido: '{}'", value))?,
+            Err(_) => 0.95,
+        };
+        if !(0.0..=1.0).contains(&failure_threshold) {
+            anyhow::bail!("VANTAGE_FAILURE_THRESHOLD debe estar entre 0 y 1");
+        }
+
+        let min_batch = match env::var("VANTAGE_MIN_BATCH") {
+            Ok(value) => value
+                .trim()
+                .parse::<usize>()
+                .with_context(|| format!("VANTAGE_MIN_BATCH inválido: '{}'", value))?,
+            Err(_) => 20,
+        };
+
+        Ok(VantageConfig {
+            canaries,
+            failure_threshold,
+            min_batch,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HarvestConfig {
+    pub enabled: bool,
+    pub rounds: u32,
+    pub idle_timeout: Duration,
+    pub max_duration: Duration,
+    pub large_response: usize,
+}
+
+impl HarvestConfig {
+    fn from_env() -> Result<Self> {
+        let enabled = env_bool("HARVEST_MODE", false)?;
+        let rounds = env_parse("HARVEST_ROUNDS", 1u32)?;
+        if rounds == 0 {
+            anyhow::bail!("HARVEST_ROUNDS debe ser al menos 1");
+        }
+        let idle_timeout = Duration::from_secs(env_parse("HARVEST_IDLE_SECONDS", 30u64)?);
+        let max_duration = Duration::from_secs(env_parse("HARVEST_MAX_SECONDS", 120u64)?);
+        if max_duration < idle_timeout {
+            anyhow::bail!("HARVEST_MAX_SECONDS no puede ser menor que HARVEST_IDLE_SECONDS");
+        }
+        let large_response = env_parse("HARVEST_LARGE_RESPONSE", 500usize)?;
+
+        Ok(HarvestConfig {
+            enabled,
+            rounds,
+            idle_timeout,
+            max_duration,
+            large_response,
+        })
+    }
+}
+
+// Presupuesto de un tipo de dirección: sesiones simultáneas (compartidas por
+// todas las redes de Bitcoin) y conexiones nuevas por segundo como máximo.
+#[derive(Debug, Clone, Copy)]
+pub struct TypeBudget {
+    pub concurrency: usize,
+    pub rate: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CrawlConfig {
+    // Tiempo que un nodo queda reservado al sacarlo de la cola. Si la sesión
+    // no deja su propio 'next_attempt_time' (p. ej. un fallo del proxy), el
+    // nodo vuelve a estar disponible al vencer la reserva.
+    pub lease: Duration,
+    // Un presupuesto por cada tipo que se sondea, en el orden de `CRAWLED_TYPES`.
+    pub budgets: Vec<(AddressType, TypeBudget)>,
+}
+
+// Tipos de dirección con cola propia. Las onion v2 ya no existen en la red.
+pub const CRAWLED_TYPES: [AddressType; 6] = [
+    AddressType::Ipv4,
+    AddressType::Ipv6,
+    AddressType::OnionV3,
+    AddressType::I2p,
+    AddressType::Cjdns,
+    AddressType::Yggdrasil,
+];
+
+// Valores por defecto (sesiones, conexiones por segundo). Tor e I2P se limitan
+// para no saturar el proxy con circuitos nuevos.
+fn default_budget(address_type: AddressType) -> (usize, f64) {
+    match address_type {
+        AddressType::Ipv4 => (50, 0.0),
+        AddressType::Ipv6 => (25, 0.0),
+        AddressType::OnionV2 | AddressType::OnionV3 => (30, 5.0),
+        AddressType::I2p => (10, 2.0),
+        AddressType::Cjdns | AddressType::Yggdrasil => (5, 0.0),
+    }
+}
+
+impl CrawlConfig {
+    fn from_env() -> Result<Self> {
+        let lease = Duration::from_secs(env_parse("CRAWL_LEASE_SECONDS", 900u64)?);
+        if lease < Duration::from_secs(60) {
+            anyhow::bail!("CRAWL_LEASE_SECONDS debe ser al menos 60");
+        }
+
+        let mut budgets = Vec::with_capacity(CRAWLED_TYPES.len());
+        for address_type in CRAWLED_TYPES {
+            let suffix = address_type.as_str().to_uppercase();
+            let (concurrency, rate) = default_budget(address_type);
+            let concurrency = env_parse(&format!("CRAWL_CONCURRENCY_{}", suffix), concurrency)?;
+            let rate = env_parse(&format!("CRAWL_RATE_{}", suffix), rate)?;
+            if !(rate >= 0.0 && rate.is_finite()) {
+                anyhow::bail!("CRAWL_RATE_{} debe ser un número no negativo", suffix);
+            }
+            budgets.push((
+                address_type,
+                TypeBudget {
+                    concurrency,
+                    rate: (rate > 0.0).then_some(rate),
+                },
+            ));
+        }
+
+        Ok(CrawlConfig { lease, budgets })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulePolicyKind {
+    // Intervalos de siempre: 12 horas tras un éxito (8-15 en Tor/I2P) y entre
+    // 4 y 24 tras un fallo, sea cual sea el historial.
+    Fixed,
+    // Intervalo según el historial del nodo.
+    Adaptive,
+}
+
+// Cuándo se vuelve a sondear un nodo tras cada sesión.
+#[derive(Debug, Clone)]
+pub struct ScheduleConfig {
+    pub policy: SchedulePolicyKind,
+    // Nodos estables: responden y tienen buena disponibilidad.
+    pub stable: Duration,
+    // Nodos nuevos, intermitentes o que acaban de fallar.
+    pub probe: Duration,
+    // Por debajo de esta disponibilidad a 7 días (en %, como las columnas
+    // 'uptime_*') un nodo se trata como intermitente.
+    pub flap_uptime: f64,
+    // Primer intervalo de un nodo caído; se dobla con cada fallo hasta `backoff_max`.
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+    // Ningún nodo vivo espera más que esto entre dos sondeos.
+    pub freshness: Duration,
+}
+
+impl ScheduleConfig {
+    fn from_env() -> Result<Self> {
+        let policy = match env::var("SCHEDULE_POLICY") {
+            Ok(value) => match value.trim().to_lowercase().as_str() {
+                "fixed" => SchedulePolicyKind::Fixed,
+                "adaptive" => SchedulePolicyKind::Adaptive,
+                other => anyhow::bail!(
+                    "SCHEDULE_POLICY inválido: '{}' (valores: adaptive, fixed)",
+                    other
+                ),
+            },
+            Err(_) => SchedulePolicyKind::Adaptive,
+        };
+        let hours = |key: &str, default: f64| -> Result<Duration> {
+            let value = env_parse(key, default)?;
+            if !(value > 0.0 && value.is_finite()) {
+                anyhow::bail!("{} debe ser un número de horas positivo", key);
+            }
+            Ok(Duration::from_secs_f64(value * 3600.0))
+        };
+        let stable = hours("SCHEDULE_STABLE_HOURS", 12.0)?;
+        let probe = hours("SCHEDULE_PROBE_HOURS", 2.0)?;
+        let backoff = hours("SCHEDULE_BACKOFF_HOURS", 4.0)?;
+        let backoff_max = hours("SCHEDULE_BACKOFF_MAX_HOURS", 168.0)?;
+        let freshness = hours("SCHEDULE_FRESHNESS_HOURS", 24.0)?;
+        let flap_uptime = env_parse("SCHEDULE_FLAP_UPTIME", 90.0f64)?;
+        if !(0.0..=100.0).contains(&flap_uptime) {
+            anyhow::bail!("SCHEDULE_FLAP_UPTIME debe estar entre 0 y 100");
+        }
+        if probe > stable {
+            anyhow::bail!("SCHEDULE_PROBE_HOURS no puede ser mayor que SCHEDULE_STABLE_HOURS");
+        }
+        if stable > freshness {
+            anyhow::bail!("SCHEDULE_STABLE_HOURS no puede ser mayor que SCHEDULE_FRESHNESS_HOURS");
+        }
+        if backoff > backoff_max {
+            anyhow::bail!(
+                "SCHEDULE_BACKOFF_HOURS no puede ser mayor que SCHEDULE_BACKOFF_MAX_HOURS"
+            );
+        }
+
+        Ok(ScheduleConfig {
+            policy,
+            stable,
+            probe,
+            flap_uptime,
+            backoff,
+            backoff_max,
+            freshness,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    // Sin noticias del nodo durante este tiempo, se retira.
+    pub unseen: Duration,
+    // Fallos seguidos tras los que se retira una onion.
+    pub onion_failures: i32,
+}
+
+impl RetentionConfig {
+    // `RETENTION_*` fija el valor por defecto y `<RED>_RETENTION_*` lo cambia
+    // para una red.
+    fn from_env(network: Network) -> Result<Self> {
+        let prefix = network_name(network).to_uppercase();
+        let value = |key: &str, default: u64| -> Result<u64> {
+            let default = env_parse(&format!("RETENTION_{}", key), default)?;
+            let network_key = format!("{}_RETENTION_{}", prefix, key);
+            let value = env_parse(&network_key, default)?;
+            if value == 0 {
+                anyhow::bail!("{} debe ser al menos 1", network_key);
+            }
+            Ok(value)
+        };
+
+        Ok(RetentionConfig {
+            unseen: Duration::from_secs(value("UNSEEN_DAYS", 2)? * 86400),
+            onion_failures: value("ONION_FAILURES", 3)? as i32,
+        })
+    }
+}
+
+fn env_parse<T>(key: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match env::var(key) {
+        Ok(value) => value
+            .trim()
+            .parse::<T>()
+            .with_context(|| format!("{} inválido: '{}'", key, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn env_bool(key: &str, default: bool) -> Result<bool> {
+    match env::var(key) {
+        Ok(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            other => anyhow::bail!("Valor booleano inválido para {}: '{}'", key, other),
+        },
+        Err(_) => Ok(default),
+    }
+}
+
+// Intervalo de un sondeo periódico activado con `<prefix>` y repetido cada
+// `<prefix>_HOURS` horas.
+fn probe_interval(prefix: &str) -> Result<Option<Duration>> {
+    if !env_bool(prefix, false)? {
+        return Ok(None);
+    }
+    let key = format!("{}_HOURS", prefix);
+    let hours = env_parse(&key, 24u64)?;
+    if hours == 0 {
+        anyhow::bail!("{} debe ser al menos 1", key);
+    }
+    Ok(Some(Duration::from_secs(hours * 3600)))
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self> {
+        let networks_var = env::var("CRAWLY_NETWORKS").unwrap_or_else(|_| "mainnet".to_string());
+
+        let mut networks = Vec::new();
+        for name in networks_var
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let network = network_from_name(name)
+                .with_context(|| format!("Red desconocida en CRAWLY_NETWORKS: '{}'", name))?;
+            if !networks.contains(&network) {
+                networks.push(network);
+            }
+        }
+
+        if networks.is_empty() {
+            anyhow::bail!("CRAWLY_NETWORKS no contiene ninguna red válida");
+        }
+
+        let mut extra_seeds = HashMap::new();
+        for network in &networks {
+            let key = format!("{}_SEEDS", network_name(*network).to_uppercase());
+            if let Ok(value) = env::var(&key) {
+                let seeds: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect();
+                extra_seeds.insert(*network, seeds);
+            }
+        }
+
+        let mut retention = HashMap::new();
+        for network in &networks {
+            retention.insert(*network, RetentionConfig::from_env(*network)?);
+        }
+        let retired_keep = match env_parse("RETIRED_KEEP_DAYS", 0u64)? {
+            0 => None,
+            days => Some(Duration::from_secs(days * 86400)),
+        };
+
+        let chain_tip_probe = env_bool("CHAIN_TIP_PROBE", false)?;
+        let filter_probe_interval = probe_interval("FILTER_PROBE")?;
+        if filter_probe_interval.is_some() && !chain_tip_probe {
+            anyhow::bail!("FILTER_PROBE necesita CHAIN_TIP_PROBE activado");
+        }
+        let filter_consensus_min = env_parse("FILTER_CONSENSUS_MIN", 3i64)?;
+        if filter_consensus_min < 1 {
+            anyhow::bail!("FILTER_CONSENSUS_MIN debe ser al menos 1");
+        }
+        let archive_probe_interval = probe_interval("ARCHIVE_PROBE")?;
+        if archive_probe_interval.is_some() && !chain_tip_probe {
+            anyhow::bail!("ARCHIVE_PROBE necesita CHAIN_TIP_PROBE activado");
+        }
+
+        Ok(Config {
+            networks,
+            extra_seeds,
+            tor_proxy: ProxyConfig::from_env("TOR", "127.0.0.1:9050", true)?,
+            i2p_proxy: ProxyConfig::from_env("I2P", "127.0.0.1:4446", false)?,
+            vantage: VantageConfig::from_env()?,
+            harvest: HarvestConfig::from_env()?,
+            crawl: CrawlConfig::from_env()?,
+            schedule: ScheduleConfig::from_env()?,
+            retention,
+            retired_keep,
+            chain_tip_probe,
+            archive_probe_interval,
+            filter_probe_interval,
+            filter_consensus_min,
+        })
+    }
+
+    pub fn default_network(&self) -> Network {
+        self.networks[0]
+    }
+
+    pub fn retention_for(&self, network: Network) -> &RetentionConfig {
+        &self.retention[&network]
+    }
+
+    pub fn seeds_for(&self, network: Network) -> &[String] {
+        self.extra_seeds
+            .get(&network)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[])
+    }
+}
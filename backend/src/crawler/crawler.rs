@@ -0,0 +1,228 @@
+// Colas del crawler: una por red de Bitcoin y tipo de dirección, cada una con
+// el presupuesto de su tipo (sesiones simultáneas y ritmo de conexiones). Así
+// una cola larga de onion, con conexiones de 30 segundos, no frena a la de IPv4
+// ni al revés.
+
+use bitcoin::Network;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::common::AddressType;
+use crate::config::TypeBudget;
+
+#[path = "schedule.rs"]
+pub mod schedule;
+
+// Ventana con la que se calcula el ritmo de sesiones terminadas.
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(300);
+
+// Espaciado mínimo entre conexiones nuevas. Cada conexión reserva su turno al
+// llamar, así que el ritmo se respeta aunque se lancen varias a la vez.
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+pub struct Lane {
+    pub address_type: AddressType,
+    budget: TypeBudget,
+    slots: Arc<Semaphore>,
+    limiter: Option<RateLimiter>,
+}
+
+impl Lane {
+    fn new(address_type: AddressType, budget: TypeBudget) -> Self {
+        Lane {
+            address_type,
+            budget,
+            slots: Arc::new(Semaphore::new(budget.concurrency)),
+            limiter: budget.rate.map(RateLimiter::new),
+        }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.budget.concurrency
+    }
+
+    // Hueco para una sesión más. Puede cancelarse sin perder nada.
+    pub async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("El semáforo de una cola no se cierra")
+    }
+
+    // Espera el turno de conexión; se llama ya dentro de la sesión.
+    pub async fn pace(&self) {
+        if let Some(limiter) = &self.limiter {
+            limiter.wait().await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    enabled: bool,
+    queued: usize,
+    in_flight: usize,
+    started: u64,
+    succeeded: u64,
+    failed: u64,
+    finished: VecDeque<Instant>,
+}
+
+// Contadores de una cola, que actualiza su tarea y lee la API.
+pub struct QueueStats {
+    network: Network,
+    lane: Arc<Lane>,
+    state: Mutex<QueueState>,
+}
+
+impl QueueStats {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.state.lock().unwrap().enabled = enabled;
+    }
+
+    pub fn set_queued(&self, queued: usize) {
+        self.state.lock().unwrap().queued = queued;
+    }
+
+    pub fn record_start(&self, queued: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.queued = queued;
+        state.in_flight += 1;
+        state.started += 1;
+    }
+
+    pub fn record_finish(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_flight = state.in_flight.saturating_sub(1);
+        if success {
+            state.succeeded += 1;
+        } else {
+            state.failed += 1;
+        }
+        let now = Instant::now();
+        state.finished.push_back(now);
+        while state
+            .finished
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > THROUGHPUT_WINDOW)
+        {
+            state.finished.pop_front();
+        }
+    }
+
+    fn status(&self) -> QueueStatus {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let recent = state
+            .finished
+            .iter()
+            .filter(|t| now.duration_since(**t) <= THROUGHPUT_WINDOW)
+            .count();
+        QueueStatus {
+            address_type: self.lane.address_type.as_str(),
+            concurrency: self.lane.budget.concurrency,
+            rate_per_second: self.lane.budget.rate,
+            enabled: state.enabled,
+            backlog: None,
+            stale: None,
+            queued: state.queued,
+            in_flight: state.in_flight,
+            started: state.started,
+            succeeded: state.succeeded,
+            failed: state.failed,
+            per_minute: recent as f64 / THROUGHPUT_WINDOW.as_secs_f64() * 60.0,
+        }
+    }
+}
+
+// `backlog` son los nodos pendientes en BBDD y `stale` los alcanzables fuera
+// del plazo de frescura; los rellena la API.
+#[derive(Serialize, Debug)]
+pub struct QueueStatus {
+    pub address_type: &'static str,
+    pub concurrency: usize,
+    pub rate_per_second: Option<f64>,
+    pub enabled: bool,
+    pub backlog: Option<i64>,
+    pub stale: Option<i64>,
+    pub queued: usize,
+    pub in_flight: usize,
+    pub started: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub per_minute: f64,
+}
+
+// Presupuestos por tipo, compartidos por todas las redes, y las colas creadas
+// con ellos.
+pub struct QueueMonitor {
+    lanes: Vec<Arc<Lane>>,
+    queues: Mutex<Vec<Arc<QueueStats>>>,
+}
+
+impl QueueMonitor {
+    pub fn new(config: &crate::config::Config) -> Self {
+        QueueMonitor {
+            lanes: config
+                .crawl
+                .budgets
+                .iter()
+                .filter(|(_, budget)| budget.concurrency > 0)
+                .map(|(address_type, budget)| Arc::new(Lane::new(*address_type, *budget)))
+                .collect(),
+            queues: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn lanes(&self) -> &[Arc<Lane>] {
+        &self.lanes
+    }
+
+    pub fn register(&self, network: Network, lane: Arc<Lane>) -> Arc<QueueStats> {
+        let stats = Arc::new(QueueStats {
+            network,
+            lane,
+            state: Mutex::new(QueueState::default()),
+        });
+        self.queues.lock().unwrap().push(stats.clone());
+        stats
+    }
+
+    pub fn statuses(&self, network: Network) -> Vec<QueueStatus> {
+        self.queues
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|stats| stats.network == network)
+            .map(|stats| stats.status())
+            .collect()
+    }
+}
@@ -0,0 +1,211 @@
+// Políticas de reprogramación: tras cada sesión deciden cuándo se vuelve a
+// sondear el nodo. Se elige una al arrancar con SCHEDULE_POLICY.
+
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::common::AddressType;
+use crate::config::{ScheduleConfig, SchedulePolicyKind};
+use crate::db::NodeHistory;
+
+// Con tres fallos seguidos un nodo deja de contar como alcanzable (y las
+// onion se retiran), así que a partir de ahí se considera caído.
+pub const DEAD_AFTER: i32 = 3;
+
+// Por debajo de esta puntuación el nodo tiene poco historial, o tantos fallos
+// como éxitos, y se sondea más a menudo.
+const SETTLED_SCORE: i32 = 3;
+
+// Variación aleatoria (±10%) para que los nodos descubiertos a la vez no
+// vuelvan a la cola todos juntos.
+const JITTER: f64 = 0.1;
+
+pub trait SchedulePolicy: Send + Sync {
+    fn name(&self) -> &'static str;
+    // Espera hasta el siguiente sondeo. `history` ya incluye el resultado de
+    // la sesión: `consecutive_failures` es 0 si ha respondido.
+    fn next_attempt(&self, history: &NodeHistory) -> chrono::Duration;
+}
+
+pub fn from_config(config: &ScheduleConfig) -> Arc<dyn SchedulePolicy> {
+    match config.policy {
+        SchedulePolicyKind::Fixed => Arc::new(FixedPolicy),
+        SchedulePolicyKind::Adaptive => Arc::new(AdaptivePolicy {
+            config: config.clone(),
+        }),
+    }
+}
+
+pub struct FixedPolicy;
+
+impl SchedulePolicy for FixedPolicy {
+    fn name(&self) -> &'static str {
+        "fixed"
+    }
+
+    fn next_attempt(&self, history: &NodeHistory) -> chrono::Duration {
+        let mut rng = rand::rng();
+        if history.consecutive_failures > 0 {
+            return chrono::Duration::seconds(rng.random_range((4 * 60 * 60)..=(24 * 60 * 60)));
+        }
+        match history.address_type {
+            Some(AddressType::OnionV2) | Some(AddressType::OnionV3) | Some(AddressType::I2p) => {
+                chrono::Duration::hours(rng.random_range(8..=15))
+                    + chrono::Duration::minutes(rng.random_range(0..=59))
+            }
+            _ => chrono::Duration::hours(12),
+        }
+    }
+}
+
+// Nodos estables a ritmo fijo; nuevos, intermitentes o con algún fallo
+// reciente, más a menudo; caídos, cada vez más espaciados. Ningún nodo vivo
+// pasa del plazo de frescura.
+pub struct AdaptivePolicy {
+    config: ScheduleConfig,
+}
+
+impl AdaptivePolicy {
+    fn interval(&self, history: &NodeHistory) -> Duration {
+        let config = &self.config;
+        if history.consecutive_failures >= DEAD_AFTER {
+            let doublings = (history.consecutive_failures - DEAD_AFTER).min(16) as u32;
+            return config.backoff.saturating_mul(1 << doublings);
+        }
+
+        let flapping = history
+            .uptime_7d
+            .is_some_and(|uptime| (uptime as f64) < config.flap_uptime);
+        if history.consecutive_failures > 0 || history.reliability_score < SETTLED_SCORE || flapping
+        {
+            config.probe
+        } else {
+            config.stable
+        }
+    }
+}
+
+impl SchedulePolicy for AdaptivePolicy {
+    fn name(&self) -> &'static str {
+        "adaptive"
+    }
+
+    fn next_attempt(&self, history: &NodeHistory) -> chrono::Duration {
+        let jitter = rand::rng().random_range((1.0 - JITTER)..=(1.0 + JITTER));
+        let limit = if history.consecutive_failures >= DEAD_AFTER {
+            self.config.backoff_max
+        } else {
+            self.config.freshness
+        };
+        let interval = self.interval(history).mul_f64(jitter).min(limit);
+        chrono::Duration::from_std(interval).unwrap_or_else(|_| chrono::Duration::hours(24))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    fn policy() -> AdaptivePolicy {
+        AdaptivePolicy {
+            config: ScheduleConfig {
+                policy: SchedulePolicyKind::Adaptive,
+                stable: Duration::from_secs(12 * HOUR),
+                probe: Duration::from_secs(HOUR),
+                flap_uptime: 90.0,
+                backoff: Duration::from_secs(2 * HOUR),
+                backoff_max: Duration::from_secs(48 * HOUR),
+                freshness: Duration::from_secs(8 * HOUR),
+            },
+        }
+    }
+
+    // Nodo asentado: buena puntuación, sin fallos y siempre disponible.
+    fn history() -> NodeHistory {
+        NodeHistory {
+            address: "203.0.113.1:8333".to_string(),
+            address_type: Some(AddressType::Ipv4),
+            reliability_score: 10,
+            consecutive_failures: 0,
+            uptime_7d: Some(100.0),
+        }
+    }
+
+    fn hours(history: &NodeHistory) -> u64 {
+        policy().interval(history).as_secs() / HOUR
+    }
+
+    #[test]
+    fn dead_nodes_double_backoff() {
+        let mut node = history();
+        for (failures, expected) in [(3, 2), (4, 4), (5, 8), (8, 64)] {
+            node.consecutive_failures = failures;
+            assert_eq!(hours(&node), expected, "{} fallos", failures);
+        }
+
+        // Las duplicaciones se detienen en 2^16.
+        node.consecutive_failures = 19;
+        assert_eq!(hours(&node), 2 << 16);
+        node.consecutive_failures = 1000;
+        assert_eq!(hours(&node), 2 << 16);
+    }
+
+    #[test]
+    fn dead_nodes_capped_at_backoff_max() {
+        let mut node = history();
+        node.consecutive_failures = 10;
+        let next = policy().next_attempt(&node);
+        assert_eq!(next, chrono::Duration::hours(48));
+
+        // Por debajo del tope sólo se aplica la variación aleatoria (±10%,
+        // con un segundo de margen por el redondeo).
+        node.consecutive_failures = 3;
+        let next = policy().next_attempt(&node).num_seconds();
+        assert!((6479..=7921).contains(&next), "{} s", next);
+    }
+
+    #[test]
+    fn live_nodes_capped_at_freshness() {
+        // El intervalo estable (12 h) supera el plazo de frescura (8 h).
+        let next = policy().next_attempt(&history());
+        assert_eq!(next, chrono::Duration::hours(8));
+
+        // Con fallos pero sin llegar a caído, el tope sigue siendo la frescura.
+        let mut node = history();
+        node.consecutive_failures = DEAD_AFTER - 1;
+        let next = policy().next_attempt(&node).num_seconds();
+        assert!((3239..=3961).contains(&next), "{} s", next);
+    }
+
+    #[test]
+    fn flapping_below_flap_uptime() {
+        let mut node = history();
+        node.uptime_7d = Some(90.0);
+        assert_eq!(hours(&node), 12);
+        node.uptime_7d = Some(89.9);
+        assert_eq!(hours(&node), 1);
+
+        // Sin dato de disponibilidad no se considera intermitente.
+        node.uptime_7d = None;
+        assert_eq!(hours(&node), 12);
+    }
+
+    #[test]
+    fn settled_score_threshold() {
+        let mut node = history();
+        node.reliability_score = SETTLED_SCORE;
+        assert_eq!(hours(&node), 12);
+        node.reliability_score = SETTLED_SCORE - 1;
+        assert_eq!(hours(&node), 1);
+    }
+
+    #[test]
+    fn recent_failure_probes_sooner() {
+        let mut node = history();
+        node.consecutive_failures = 1;
+        assert_eq!(hours(&node), 1);
+    }
+}
@@ -1,17 +1,113 @@
 use anyhow::{Context, Result};
+use bitcoin::Network;
 use chrono::Utc;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPoolOptions;
-use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
-#[derive(Serialize)]
+use crate::common::{
+    network_name, service_bit_name, AddressType, NetAddress, ServiceBits, NAMED_SERVICE_BITS,
+};
+use crate::config::RetentionConfig;
+use crate::crawler::schedule::SchedulePolicy;
+
+#[derive(Serialize, sqlx::FromRow)]
 pub struct NodeInfo {
+    pub id: i64,
+    pub network: String,
     pub address: String,
+    pub port: i32,
+    pub address_type: Option<String>,
     pub soft: Option<String>,
+    pub services: Option<ServiceBits>,
     pub country: Option<String>,
     pub detected: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_gossip: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_handshake: Option<chrono::DateTime<chrono::Utc>>,
+    pub announcers: i32,
+    pub first_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub uptime_2h: Option<f32>,
+    pub uptime_8h: Option<f32>,
+    pub uptime_24h: Option<f32>,
+    pub uptime_7d: Option<f32>,
+    pub uptime_30d: Option<f32>,
+    pub longest_streak_seconds: i64,
+    pub current_streak_seconds: i64,
+    pub sendaddrv2: Option<bool>,
+    pub wtxidrelay: Option<bool>,
+    pub sendheaders: Option<bool>,
+    pub sendcmpct_version: Option<i64>,
+    pub feefilter: Option<i64>,
+    pub v2_handshake: Option<bool>,
+    pub v2_checked: Option<chrono::DateTime<chrono::Utc>>,
+    pub tip_status: Option<String>,
+    pub tip_hash: Option<String>,
+    pub tip_height: Option<i32>,
+    pub tip_lag: Option<i32>,
+    pub tip_checked: Option<chrono::DateTime<chrono::Utc>>,
+    pub archive_served: Option<bool>,
+    pub archive_latency_ms: Option<i32>,
+    pub archive_mismatch: Option<bool>,
+    pub archive_checked: Option<chrono::DateTime<chrono::Utc>>,
+    pub cfilter_served: Option<bool>,
+    pub cfilter_stop_height: Option<i32>,
+    pub cfilter_consensus: Option<bool>,
+    pub cfilter_checked: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+const NODE_INFO_COLUMNS: &str =
+    "id, network, address, port, type AS address_type, soft, services, country, detected, \
+     last_gossip, last_handshake, announcers, added AS first_seen, \
+     uptime_2h, uptime_8h, uptime_24h, uptime_7d, uptime_30d, \
+     longest_streak_seconds, current_streak_seconds, \
+     sendaddrv2, wtxidrelay, sendheaders, sendcmpct_version, feefilter, \
+     v2_handshake, v2_checked, tip_status, tip_hash, tip_height, tip_lag, tip_checked, \
+     archive_served, archive_latency_ms, archive_mismatch, archive_checked, \
+     cfilter_served, cfilter_stop_height, cfilter_consensus, cfilter_checked";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeSort {
+    Scanned,
+    FirstSeen,
+    Uptime2h,
+    Uptime8h,
+    Uptime24h,
+    Uptime7d,
+    Uptime30d,
+    LongestStreak,
+    CurrentStreak,
+}
+
+impl NodeSort {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "scanned" => Some(NodeSort::Scanned),
+            "first_seen" => Some(NodeSort::FirstSeen),
+            "uptime_2h" => Some(NodeSort::Uptime2h),
+            "uptime_8h" => Some(NodeSort::Uptime8h),
+            "uptime_24h" => Some(NodeSort::Uptime24h),
+            "uptime_7d" => Some(NodeSort::Uptime7d),
+            "uptime_30d" => Some(NodeSort::Uptime30d),
+            "longest_streak" => Some(NodeSort::LongestStreak),
+            "current_streak" => Some(NodeSort::CurrentStreak),
+            _ => None,
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            NodeSort::Scanned => "scanned",
+            NodeSort::FirstSeen => "added",
+            NodeSort::Uptime2h => "uptime_2h",
+            NodeSort::Uptime8h => "uptime_8h",
+            NodeSort::Uptime24h => "uptime_24h",
+            NodeSort::Uptime7d => "uptime_7d",
+            NodeSort::Uptime30d => "uptime_30d",
+            NodeSort::LongestStreak => "longest_streak_seconds",
+            NodeSort::CurrentStreak => "current_streak_seconds",
+        }
+    }
 }
 
 #[derive(Serialize, sqlx::FromRow, Debug)]
@@ -37,6 +133,7 @@ pub struct ProtocolStats {
 #[derive(Serialize, sqlx::FromRow, Debug)]
 pub struct HourlyStat {
     pub snapshot_time: chrono::DateTime<chrono::Utc>,
+    pub network: String,
     pub total_nodes: Option<i64>,
     pub incoming_nodes: Option<i64>,
     pub archive_nodes: Option<i64>,
@@ -44,23 +141,330 @@ pub struct HourlyStat {
     pub ipv6_nodes: Option<i64>,
     pub onion_nodes: Option<i64>,
     pub top_software: Option<serde_json::Value>,
+    pub capabilities: Option<serde_json::Value>,
+    pub service_bits: Option<serde_json::Value>,
+    pub transport: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionInfo {
+    pub user_agent: String,
+    pub services: ServiceBits,
+    pub protocol_version: i32,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
+// Mensajes de negociación recibidos durante el handshake.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub sendaddrv2: bool,
+    pub wtxidrelay: bool,
+    pub sendheaders: bool,
+    pub sendcmpct_version: Option<i64>,
+    pub feefilter: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow, Debug)]
+pub struct CapabilityCount {
+    pub client: String,
+    pub value: Option<i64>,
+    pub node_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CapabilityStats {
+    pub nodes: i64,
+    pub sendaddrv2: i64,
+    pub wtxidrelay: i64,
+    pub sendheaders: i64,
+    pub sendcmpct: Vec<CapabilityCount>,
+    pub feefilter: Vec<CapabilityCount>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceBitCount {
+    pub bit: i32,
+    pub flag: String,
+    pub node_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceStats {
+    pub nodes: i64,
+    pub bits: Vec<ServiceBitCount>,
+}
+
+// Adopción del transporte v2 entre los nodos alcanzables: cuántos anuncian
+// P2P_V2, a cuántos se ha probado y con cuántos funcionó el handshake.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransportStats {
+    pub nodes: i64,
+    pub advertised: i64,
+    pub checked: i64,
+    pub v2_nodes: i64,
+}
+
+// Resultado del sondeo de punta de cadena de un nodo.
+#[derive(Debug, Clone)]
+pub struct ChainTip {
+    pub status: &'static str,
+    pub hash: Option<String>,
+    pub height: Option<i32>,
+    pub lag: Option<i32>,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct ChainReference {
+    pub height: i32,
+    pub hash: String,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TipStatusCount {
+    pub status: String,
+    pub node_count: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct ChainTipCount {
+    pub hash: String,
+    pub height: i32,
+    pub status: String,
+    pub node_count: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TipLagCount {
+    pub lag: i32,
+    pub node_count: i64,
+}
+
+// Distribución de puntas entre los nodos alcanzables sondeados en las últimas
+// 24 horas.
+#[derive(Serialize, Debug)]
+pub struct ChainTipStats {
+    pub reference: Option<ChainReference>,
+    pub statuses: Vec<TipStatusCount>,
+    pub tips: Vec<ChainTipCount>,
+    pub lagging: Vec<TipLagCount>,
+}
+
+// Resultado de pedir un bloque antiguo a un nodo que anuncia NETWORK.
+#[derive(Debug, Clone)]
+pub struct ArchiveCheck {
+    // None si el nodo no contestó ni con el bloque ni con 'notfound'.
+    pub served: Option<bool>,
+    pub latency_ms: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArchiveStats {
+    pub advertised: i64,
+    pub checked: i64,
+    pub served: i64,
+    pub mismatched: i64,
+    pub avg_latency_ms: Option<f64>,
+}
+
+// Resultado de pedir 'getcfcheckpt' a un nodo que anuncia COMPACT_FILTERS.
+// `digest` resume las cabeceras de filtro recibidas hasta la parada.
+#[derive(Debug, Clone)]
+pub struct FilterCheck {
+    pub served: bool,
+    pub stop_height: Option<i32>,
+    pub stop_hash: Option<String>,
+    pub digest: Option<String>,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct FilterDigestCount {
+    pub digest: String,
+    pub node_count: i64,
+}
+
+// `consensus` y `dissenting` cuentan los nodos según su última verificación;
+// `digests` reparte las respuestas a la parada más reciente.
+#[derive(Serialize, Debug)]
+pub struct FilterStats {
+    pub advertised: i64,
+    pub checked: i64,
+    pub served: i64,
+    pub consensus: i64,
+    pub dissenting: i64,
+    pub stop_height: Option<i32>,
+    pub stop_hash: Option<String>,
+    pub digests: Vec<FilterDigestCount>,
+}
+
+// Medidas de una sesión saliente: conexión (TCP o a través del proxy),
+// handshake ('version' enviado -> 'verack' recibido), RTT de nuestro 'ping' y
+// desfase del reloj del peer según su 'version'.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub connect_ms: Option<i32>,
+    pub handshake_ms: Option<i32>,
+    pub ping_ms: Option<i32>,
+    pub clock_offset_s: Option<i64>,
+}
+
+// Medianas por grupo (tipo de red, país o ASN) de los intentos recientes.
+// `skewed_nodes` cuenta los nodos con algún desfase de más de 10 minutos.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct LatencyStats {
+    pub group: Option<String>,
+    pub nodes: i64,
+    pub samples: i64,
+    pub connect_ms: Option<f64>,
+    pub handshake_ms: Option<f64>,
+    pub ping_ms: Option<f64>,
+    pub ping_ms_p90: Option<f64>,
+    pub clock_offset_s: Option<f64>,
+    pub skewed_nodes: i64,
+}
+
+// Nodos vencidos en BBDD de un tipo de dirección, a la espera de entrar en su
+// cola.
+#[derive(Debug)]
+pub struct BacklogCount {
+    pub address_type: String,
+    pub due: i64,
+    // Nodos alcanzables sin sondear dentro del plazo de frescura.
+    pub stale: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionAttempt {
+    pub node_id: i64,
+    pub attempted_at: chrono::DateTime<Utc>,
+    pub duration_ms: i32,
+    pub outcome: &'static str,
+    pub error: Option<String>,
+    pub vantage_failure: bool,
+    pub proxy_failure: bool,
+    pub version: Option<VersionInfo>,
+    pub v2_handshake: Option<bool>,
+    pub timings: Timings,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct ConnectionAttemptRecord {
+    // NULL si el nodo ya se ha retirado.
+    pub node_id: Option<i64>,
+    pub network: String,
+    pub address: String,
+    pub port: i32,
+    pub attempted_at: chrono::DateTime<Utc>,
+    pub duration_ms: i32,
+    pub outcome: String,
+    pub error: Option<String>,
+    pub vantage_failure: bool,
+    pub proxy_failure: bool,
+    pub user_agent: Option<String>,
+    pub services: Option<ServiceBits>,
+    pub protocol_version: Option<i32>,
+    pub start_height: Option<i32>,
+    pub relay: Option<bool>,
+    pub v2_handshake: Option<bool>,
+    pub connect_ms: Option<i32>,
+    pub handshake_ms: Option<i32>,
+    pub ping_ms: Option<i32>,
+    pub clock_offset_s: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HarvestRound {
+    pub node_id: i64,
+    pub harvested_at: chrono::DateTime<Utc>,
+    pub round: i32,
+    pub addr_messages: i32,
+    pub addresses: i32,
+    pub new_addresses: i32,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct HarvestRoundStats {
+    pub round: i32,
+    pub sessions: i64,
+    pub avg_addresses: f64,
+    pub avg_new_addresses: f64,
 }
 
 #[derive(Debug)]
 pub struct DiscoveredNode {
-    pub addr_type: String,
-    pub addr_str: String,
+    pub address: NetAddress,
     pub port: u16,
-    pub services: String,
+    pub services: ServiceBits,
+    // Marca de tiempo anunciada en 'addr', nunca en el futuro.
+    pub gossip_time: chrono::DateTime<Utc>,
+}
+
+// Peer que nos habló de un nodo y la marca de tiempo que anunció.
+#[derive(Debug, Clone, Copy)]
+pub struct Announcement {
+    pub announcer_id: i64,
+    pub gossip_time: chrono::DateTime<Utc>,
 }
 
 #[derive(Clone)]
 pub struct Database(pub sqlx::PgPool);
 
-pub enum NodeToScan {
-    Ip(SocketAddr),
-    Tor { address: String, port: u16 },
-    I2p { address: String, port: u16 },
+// Nodo retirado con su último estado conocido. `lifetime_hours` va de su
+// primera aparición a la retirada.
+#[derive(Serialize, Debug)]
+pub struct RetiredNode {
+    pub node_id: i64,
+    pub address: String,
+    pub port: i32,
+    pub address_type: Option<String>,
+    pub soft: Option<String>,
+    pub services: Option<ServiceBits>,
+    pub country: Option<String>,
+    pub asn: Option<String>,
+    pub first_seen: Option<chrono::DateTime<Utc>>,
+    pub detected: Option<chrono::DateTime<Utc>>,
+    pub last_gossip: Option<chrono::DateTime<Utc>>,
+    pub last_handshake: Option<chrono::DateTime<Utc>>,
+    pub announcers: Option<i32>,
+    pub scanned: Option<chrono::DateTime<Utc>>,
+    pub was_reachable: Option<bool>,
+    pub reliability_score: Option<i32>,
+    pub uptime_30d: Option<f32>,
+    pub longest_streak_seconds: Option<i64>,
+    pub retired_at: chrono::DateTime<Utc>,
+    pub reason: String,
+    pub lifetime_hours: Option<f64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChurnDay {
+    pub day: chrono::NaiveDate,
+    pub discovered: i64,
+    pub retired: i64,
+    pub retired_unseen: i64,
+    pub retired_unreachable: i64,
+    pub median_lifetime_hours: Option<f64>,
+}
+
+// Lo que necesita la política de reprogramación para decidir el siguiente
+// intento de un nodo.
+#[derive(Debug, Clone)]
+pub struct NodeHistory {
+    pub address: String,
+    pub address_type: Option<AddressType>,
+    pub reliability_score: i32,
+    pub consecutive_failures: i32,
+    pub uptime_7d: Option<f32>,
+}
+
+pub struct ScanTarget {
+    pub id: i64,
+    pub address: NetAddress,
+    pub port: u16,
+    pub services: Option<ServiceBits>,
+    pub archive_checked: Option<chrono::DateTime<Utc>>,
+    pub cfilter_checked: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Serialize, sqlx::FromRow, Debug)]
@@ -86,79 +490,74 @@ impl Database {
         Ok(Database(pool))
     }
 
-    pub async fn seed_nodes(&self) -> Result<()> {
-        let initial_nodes = crate::common::first_nodes();
+    pub async fn seed_nodes(&self, network: Network, extra_seeds: &[String]) -> Result<()> {
+        let initial_nodes = crate::common::first_nodes(network, extra_seeds);
 
         for node in initial_nodes {
-            let addr_type = if node.is_ipv4() { "ipv4" } else { "ipv6" };
-            let address = node.ip().to_string();
-            let port = node.port();
-
-            self.upsert_addrv2_node(addr_type, &address, port, "")
+            let address = NetAddress::from_ip(node.ip());
+            self.upsert_addrv2_node(network, &address, node.port(), None, None)
                 .await?;
         }
         Ok(())
     }
 
-    pub async fn get_nodes_to_scan(&self, limit: u32) -> Result<Vec<NodeToScan>> {
+    // Saca de la cola los nodos pendientes y los reserva durante `lease`
+    // adelantando su 'next_attempt_time', para que el siguiente relleno no los
+    // vuelva a elegir mientras se sondean. Al terminar la sesión el resultado
+    // fija el 'next_attempt_time' definitivo.
+    pub async fn claim_nodes_to_scan(
+        &self,
+        network: Network,
+        types: &[AddressType],
+        limit: u32,
+        lease: Duration,
+    ) -> Result<Vec<ScanTarget>> {
+        let type_names: Vec<String> = types.iter().map(|t| t.as_str().to_string()).collect();
         let records = sqlx::query!(
             r#"
-            SELECT address, port, type as "node_type"
-            FROM bnetwork 
-            WHERE (next_attempt_time < NOW() OR next_attempt_time IS NULL)
-            AND type IN ('ipv4', 'ipv6', 'onionv3', 'i2p')
-            ORDER BY next_attempt_time ASC NULLS FIRST
-            LIMIT $1
+            WITH due AS (
+                SELECT id
+                FROM bnetwork
+                WHERE network = $1
+                AND (next_attempt_time < NOW() OR next_attempt_time IS NULL)
+                AND type = ANY($2)
+                ORDER BY next_attempt_time ASC NULLS FIRST
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE bnetwork b
+            SET next_attempt_time = NOW() + make_interval(secs => $4)
+            FROM due
+            WHERE b.id = due.id
+            RETURNING b.id, b.address, b.port, b.type as "node_type",
+                      b.services AS "services: ServiceBits", b.archive_checked, b.cfilter_checked
             "#,
-            limit as i64
+            network_name(network),
+            &type_names[..],
+            limit as i64,
+            lease.as_secs_f64()
         )
         .fetch_all(&self.0)
         .await
         .context("Fallo al obtener nodos para escanear")?;
 
-        let nodes: Vec<NodeToScan> = records
+        let nodes: Vec<ScanTarget> = records
             .into_iter()
             .filter_map(|row| {
-                let addr = row.address.clone();
-                let port = row.port.clone();
-                let node_type = row.node_type.as_deref()?;
-
-                match node_type {
-                    "ipv4" | "ipv6" => {
-                        if let (Ok(ip), Some(port_val)) = (addr.parse::<IpAddr>(), port) {
-                            Some(NodeToScan::Ip(SocketAddr::new(ip, port_val as u16)))
-                        } else {
-                            tracing::warn!(
-                                "No se pudo parsear la dirección IP o puerto: {} {}",
-                                addr,
-                                port.unwrap_or(-1)
-                            );
-                            None
-                        }
-                    }
-                    "onionv2" | "onionv3" => {
-                        if let Some(port_val) = port {
-                            Some(NodeToScan::Tor {
-                                address: addr,
-                                port: port_val as u16,
-                            })
-                        } else {
-                            tracing::warn!("Puerto faltante para nodo Tor: {}", addr);
-                            None
-                        }
-                    }
-                    "i2p" => {
-                        if let Some(port_val) = port {
-                            Some(NodeToScan::I2p {
-                                address: addr,
-                                port: port_val as u16,
-                            })
-                        } else {
-                            tracing::warn!("Puerto faltante para nodo I2P: {}", addr);
-                            None
-                        }
+                let address_type = AddressType::from_name(row.node_type.as_deref()?)?;
+                match NetAddress::parse_as(&row.address, address_type) {
+                    Ok(address) => Some(ScanTarget {
+                        id: row.id,
+                        address,
+                        port: row.port as u16,
+                        services: row.services,
+                        archive_checked: row.archive_checked,
+                        cfilter_checked: row.cfilter_checked,
+                    }),
+                    Err(e) => {
+                        tracing::warn!("No se pudo interpretar el nodo {}: {}", row.id, e);
+                        None
                     }
-                    _ => None,
                 }
             })
             .collect();
@@ -166,31 +565,153 @@ impl Database {
         Ok(nodes)
     }
 
+    pub async fn get_scan_backlog(
+        &self,
+        network: Network,
+        freshness: Duration,
+    ) -> Result<Vec<BacklogCount>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT type AS "address_type!",
+                   COUNT(*) FILTER (
+                       WHERE next_attempt_time < NOW() OR next_attempt_time IS NULL
+                   ) AS "due!",
+                   COUNT(*) FILTER (
+                       WHERE incoming = TRUE
+                       AND scanned < NOW() - make_interval(secs => $2)
+                   ) AS "stale!"
+            FROM bnetwork
+            WHERE network = $1
+            AND type IS NOT NULL
+            GROUP BY type
+            "#,
+            network_name(network),
+            freshness.as_secs_f64()
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al contar nodos pendientes de escanear")?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| BacklogCount {
+                address_type: row.address_type,
+                due: row.due,
+                stale: row.stale,
+            })
+            .collect())
+    }
+
+    // `announcement` es None para los nodos semilla, que no vienen de ningún peer.
     pub async fn upsert_addrv2_node(
         &self,
-        addr_type: &str,
-        address: &str,
+        network: Network,
+        address: &NetAddress,
         port: u16,
-        services: &str,
+        services: Option<ServiceBits>,
+        announcement: Option<Announcement>,
     ) -> Result<()> {
         let now = Utc::now();
-        sqlx::query!(
+        let node_id = sqlx::query_scalar!(
             r#"
-            INSERT INTO bnetwork (address, type, port, services, added, detected)
-            VALUES ($1, $2, $3, $4, $5, $6)
-            ON CONFLICT (address) DO UPDATE SET
-            detected = $6
+            INSERT INTO bnetwork (network, address, type, port, services, added, detected, last_gossip)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (network, address, port) DO UPDATE SET
+            detected = $7,
+            last_gossip = GREATEST(bnetwork.last_gossip, EXCLUDED.last_gossip)
+            RETURNING id
             "#,
-            address,
-            addr_type,
+            network_name(network),
+            address.to_string(),
+            address.address_type().as_str(),
             port as i32,
-            services,
+            services.map(|s| s.0),
             now,
+            now,
+            announcement.map(|a| a.gossip_time)
+        )
+        .fetch_one(&self.0)
+        .await
+        .context(format!("Fallo en el upsert del nodo {}", address))?;
+
+        if let Some(announcement) = announcement {
+            self.record_announcements(announcement.announcer_id, &[node_id], now)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // Registra que `announcer_id` nos habló de `node_ids` y cuenta los peers
+    // nuevos en 'announcers'.
+    async fn record_announcements(
+        &self,
+        announcer_id: i64,
+        node_ids: &[i64],
+        now: chrono::DateTime<Utc>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            WITH announced AS (
+                INSERT INTO node_announcements (node_id, announcer_id, first_announced, last_announced)
+                SELECT node_id, $2, $3, $3
+                FROM UNNEST($1::int8[]) AS u(node_id)
+                ON CONFLICT (node_id, announcer_id) DO UPDATE SET
+                    last_announced = EXCLUDED.last_announced
+                RETURNING node_id, (xmax = 0) AS inserted
+            )
+            UPDATE bnetwork b
+            SET announcers = b.announcers + 1
+            FROM announced
+            WHERE b.id = announced.node_id AND announced.inserted
+            "#,
+            node_ids,
+            announcer_id,
             now
         )
         .execute(&self.0)
         .await
-        .context(format!("Fallo en el upsert del nodo {}", address))?;
+        .context(format!(
+            "Fallo al registrar los anuncios del nodo {}",
+            announcer_id
+        ))?;
+
+        Ok(())
+    }
+
+    // Los anuncios caducan a los 35 días y dejan de contar en 'announcers'.
+    // Tampoco cuentan los de anunciantes ya retirados, que se conservan con
+    // 'announcer_id' a NULL; por eso el recuento se rehace desde la tabla en
+    // vez de restar lo borrado.
+    pub async fn clean_announcements(&self) -> Result<()> {
+        sqlx::query!(
+            "DELETE FROM node_announcements WHERE last_announced < NOW() - INTERVAL '35 days'"
+        )
+        .execute(&self.0)
+        .await
+        .context("Error al limpiar node_announcements")?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE bnetwork b
+            SET announcers = c.announcers
+            FROM (
+                SELECT n.id, COUNT(a.announcer_id)::int4 AS announcers
+                FROM bnetwork n
+                LEFT JOIN node_announcements a ON a.node_id = n.id
+                GROUP BY n.id
+            ) c
+            WHERE b.id = c.id AND b.announcers <> c.announcers
+            "#
+        )
+        .execute(&self.0)
+        .await
+        .context("Error al recontar los anunciantes")?;
+
+        tracing::info!(
+            "[Mantenimiento] Recuento de anunciantes actualizado en {} nodos.",
+            result.rows_affected()
+        );
 
         Ok(())
     }
@@ -217,178 +738,1185 @@ impl Database {
         )
         .execute(&self.0)
         .await
-        .context(format!("Fallo al actualizar los detalles de la ip {}", ip))?;
+        .context(format!("Fallo al actualizar los detalles de la ip {}", ip))?;
+
+        Ok(())
+    }
+
+    // Pasa a 'retired_nodes' los nodos sin señales de vida en el plazo de la
+    // red y las onion con demasiados fallos seguidos. Cuenta como señal la
+    // marca anunciada en 'addr', un handshake o una conexión entrante; que un
+    // peer retransmita la dirección ('detected') no basta. Devuelve cuántos se
+    // retiraron por cada motivo.
+    pub async fn retire_nodes(
+        &self,
+        network: Network,
+        retention: &RetentionConfig,
+    ) -> Result<(usize, usize)> {
+        let onion_types: Vec<String> = AddressType::ALL
+            .iter()
+            .filter(|t| t.is_onion())
+            .map(|t| t.as_str().to_string())
+            .collect();
+        let reasons = sqlx::query_scalar!(
+            r#"
+            WITH expired AS (
+                DELETE FROM bnetwork
+                WHERE network = $1
+                AND (
+                    COALESCE(GREATEST(last_gossip, last_handshake, last_inbound), added)
+                        < NOW() - make_interval(secs => $2)
+                    OR (type = ANY($3) AND consecutive_failures >= $4)
+                )
+                RETURNING *,
+                    COALESCE(GREATEST(last_gossip, last_handshake, last_inbound), added)
+                        < NOW() - make_interval(secs => $2) AS unseen
+            )
+            INSERT INTO retired_nodes (
+                node_id, network, address, port, type, added, detected, scanned, soft,
+                services, protocol_version, start_height, country, region, city, isp, asn,
+                latitude, longitude, incoming, last_inbound, reliability_score,
+                consecutive_failures, uptime_30d, longest_streak_seconds,
+                last_gossip, last_handshake, announcers, reason
+            )
+            SELECT id, network, address, port, type, added, detected, scanned, soft,
+                   services, protocol_version, start_height, country, region, city, isp, asn,
+                   latitude, longitude, incoming, last_inbound, reliability_score,
+                   consecutive_failures, uptime_30d, longest_streak_seconds,
+                   last_gossip, last_handshake, announcers,
+                   CASE WHEN unseen THEN 'unseen' ELSE 'unreachable' END
+            FROM expired
+            RETURNING reason
+            "#,
+            network_name(network),
+            retention.unseen.as_secs_f64(),
+            &onion_types[..],
+            retention.onion_failures
+        )
+        .fetch_all(&self.0)
+        .await
+        .context(format!(
+            "Error al retirar nodos de {}",
+            network_name(network)
+        ))?;
+
+        let unseen = reasons.iter().filter(|reason| *reason == "unseen").count();
+        Ok((unseen, reasons.len() - unseen))
+    }
+
+    pub async fn purge_retired_nodes(&self, keep: Duration) -> Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM retired_nodes WHERE retired_at < NOW() - make_interval(secs => $1)",
+            keep.as_secs_f64()
+        )
+        .execute(&self.0)
+        .await
+        .context("Error al purgar nodos retirados")?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_retired_nodes(
+        &self,
+        network: Network,
+        address_type: Option<&str>,
+        reason: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<RetiredNode>> {
+        let nodes = sqlx::query_as!(
+            RetiredNode,
+            r#"
+            SELECT node_id, address, port, type AS address_type, soft,
+                   services AS "services: ServiceBits", country, asn,
+                   added AS first_seen, detected, last_gossip, last_handshake, announcers,
+                   scanned, incoming AS was_reachable,
+                   reliability_score, uptime_30d, longest_streak_seconds,
+                   retired_at, reason,
+                   (EXTRACT(EPOCH FROM retired_at - added) / 3600)::float8 AS lifetime_hours
+            FROM retired_nodes
+            WHERE network = $1
+            AND ($2::text IS NULL OR type = $2)
+            AND ($3::text IS NULL OR reason = $3)
+            ORDER BY retired_at DESC, id DESC
+            LIMIT $4 OFFSET $5
+            "#,
+            network_name(network),
+            address_type,
+            reason,
+            limit,
+            offset
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener nodos retirados")?;
+
+        Ok(nodes)
+    }
+
+    // Nodos descubiertos y retirados por día, con la mediana de vida de los
+    // retirados. Los descubiertos cuentan tanto los activos como los ya
+    // retirados.
+    pub async fn get_churn_stats(&self, network: Network, days: i32) -> Result<Vec<ChurnDay>> {
+        let stats = sqlx::query_as!(
+            ChurnDay,
+            r#"
+            WITH days AS (
+                SELECT generate_series(
+                    date_trunc('day', NOW()) - make_interval(days => $2 - 1),
+                    date_trunc('day', NOW()),
+                    INTERVAL '1 day'
+                ) AS day
+            ),
+            discovered AS (
+                SELECT date_trunc('day', added) AS day, COUNT(*) AS nodes
+                FROM (
+                    SELECT added FROM bnetwork WHERE network = $1
+                    UNION ALL
+                    SELECT added FROM retired_nodes WHERE network = $1
+                ) seen
+                WHERE added >= date_trunc('day', NOW()) - make_interval(days => $2 - 1)
+                GROUP BY 1
+            ),
+            retired AS (
+                SELECT date_trunc('day', retired_at) AS day,
+                       COUNT(*) AS nodes,
+                       COUNT(*) FILTER (WHERE reason = 'unseen') AS unseen,
+                       COUNT(*) FILTER (WHERE reason = 'unreachable') AS unreachable,
+                       percentile_cont(0.5) WITHIN GROUP (
+                           ORDER BY EXTRACT(EPOCH FROM retired_at - added) / 3600
+                       ) AS median_lifetime_hours
+                FROM retired_nodes
+                WHERE network = $1
+                AND retired_at >= date_trunc('day', NOW()) - make_interval(days => $2 - 1)
+                GROUP BY 1
+            )
+            SELECT d.day::date AS "day!",
+                   COALESCE(disc.nodes, 0) AS "discovered!",
+                   COALESCE(r.nodes, 0) AS "retired!",
+                   COALESCE(r.unseen, 0) AS "retired_unseen!",
+                   COALESCE(r.unreachable, 0) AS "retired_unreachable!",
+                   r.median_lifetime_hours
+            FROM days d
+            LEFT JOIN discovered disc ON disc.day = d.day
+            LEFT JOIN retired r ON r.day = d.day
+            ORDER BY d.day
+            "#,
+            network_name(network),
+            days
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener estadísticas de rotación")?;
+
+        Ok(stats)
+    }
+
+    pub async fn clean_connection_attempts(&self) -> Result<()> {
+        let result = sqlx::query!(
+            "DELETE FROM connection_attempts WHERE attempted_at < NOW() - INTERVAL '35 days'"
+        )
+        .execute(&self.0)
+        .await
+        .context("Error al limpiar connection_attempts")?;
+
+        tracing::info!(
+            "[Mantenimiento] Se eliminaron {} intentos de conexión antiguos.",
+            result.rows_affected()
+        );
+
+        Ok(())
+    }
+
+    pub async fn clean_harvest_rounds(&self) -> Result<()> {
+        let result = sqlx::query!(
+            "DELETE FROM addr_harvest_rounds WHERE harvested_at < NOW() - INTERVAL '35 days'"
+        )
+        .execute(&self.0)
+        .await
+        .context("Error al limpiar addr_harvest_rounds")?;
+
+        tracing::info!(
+            "[Mantenimiento] Se eliminaron {} rondas de recolección antiguas.",
+            result.rows_affected()
+        );
+
+        Ok(())
+    }
+
+    pub async fn record_harvest_round(&self, round: &HarvestRound) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO addr_harvest_rounds (
+                node_id, network, harvested_at, round, addr_messages, addresses, new_addresses
+            )
+            SELECT id, network, $2, $3, $4, $5, $6
+            FROM bnetwork
+            WHERE id = $1
+            "#,
+            round.node_id,
+            round.harvested_at,
+            round.round,
+            round.addr_messages,
+            round.addresses,
+            round.new_addresses
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al registrar la ronda {} de recolección del nodo {}",
+            round.round, round.node_id
+        ))?;
+
+        Ok(())
+    }
+
+    // Media de direcciones (totales y nuevas) por número de ronda, para decidir
+    // cuántas rondas compensa hacer.
+    pub async fn get_harvest_round_stats(
+        &self,
+        network: Network,
+        days: i32,
+    ) -> Result<Vec<HarvestRoundStats>> {
+        let stats = sqlx::query_as!(
+            HarvestRoundStats,
+            r#"
+            SELECT
+                round,
+                COUNT(*) AS "sessions!",
+                AVG(addresses)::float8 AS "avg_addresses!",
+                AVG(new_addresses)::float8 AS "avg_new_addresses!"
+            FROM addr_harvest_rounds
+            WHERE network = $1 AND harvested_at > NOW() - make_interval(days => $2)
+            GROUP BY round
+            ORDER BY round
+            "#,
+            network_name(network),
+            days
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener estadísticas de recolección")?;
+
+        Ok(stats)
+    }
+
+    pub async fn record_connection_attempt(&self, attempt: &ConnectionAttempt) -> Result<()> {
+        let version = attempt.version.as_ref();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO connection_attempts (
+                node_id, network, address, port, attempted_at, duration_ms, outcome, error,
+                vantage_failure, proxy_failure, user_agent, services, protocol_version,
+                start_height, relay, v2_handshake, connect_ms, handshake_ms, ping_ms,
+                clock_offset_s
+            )
+            SELECT id, network, address, port, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                   $13, $14, $15, $16, $17
+            FROM bnetwork
+            WHERE id = $1
+            "#,
+            attempt.node_id,
+            attempt.attempted_at,
+            attempt.duration_ms,
+            attempt.outcome,
+            attempt.error,
+            attempt.vantage_failure,
+            attempt.proxy_failure,
+            version.map(|v| v.user_agent.clone()),
+            version.map(|v| v.services.0),
+            version.map(|v| v.protocol_version),
+            version.map(|v| v.start_height),
+            version.map(|v| v.relay),
+            attempt.v2_handshake,
+            attempt.timings.connect_ms,
+            attempt.timings.handshake_ms,
+            attempt.timings.ping_ms,
+            attempt.timings.clock_offset_s
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al registrar el intento de conexión del nodo {}",
+            attempt.node_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn get_connection_attempts(
+        &self,
+        node_id: i64,
+        limit: i64,
+    ) -> Result<Vec<ConnectionAttemptRecord>> {
+        let attempts = sqlx::query_as!(
+            ConnectionAttemptRecord,
+            r#"
+            SELECT node_id, network, address, port, attempted_at, duration_ms, outcome, error,
+                   vantage_failure, proxy_failure, user_agent,
+                   services AS "services: ServiceBits",
+                   protocol_version, start_height, relay, v2_handshake,
+                   connect_ms, handshake_ms, ping_ms, clock_offset_s
+            FROM connection_attempts
+            WHERE node_id = $1
+            ORDER BY attempted_at DESC
+            LIMIT $2
+            "#,
+            node_id,
+            limit
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener los intentos de conexión")?;
+
+        Ok(attempts)
+    }
+
+    // `group` es 'type', 'country' o 'asn'; el llamador lo valida.
+    pub async fn get_latency_stats(
+        &self,
+        network: Network,
+        group: &str,
+        days: i32,
+    ) -> Result<Vec<LatencyStats>> {
+        let stats = sqlx::query_as!(
+            LatencyStats,
+            r#"
+            SELECT
+                CASE $2
+                    WHEN 'country' THEN b.country
+                    WHEN 'asn' THEN b.asn
+                    ELSE b.type
+                END AS "group",
+                COUNT(DISTINCT a.node_id) AS "nodes!",
+                COUNT(*) AS "samples!",
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY a.connect_ms) AS connect_ms,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY a.handshake_ms) AS handshake_ms,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY a.ping_ms) AS ping_ms,
+                percentile_cont(0.9) WITHIN GROUP (ORDER BY a.ping_ms) AS ping_ms_p90,
+                percentile_cont(0.5) WITHIN GROUP (ORDER BY a.clock_offset_s) AS clock_offset_s,
+                COUNT(DISTINCT a.node_id) FILTER (WHERE abs(a.clock_offset_s) > 600) AS "skewed_nodes!"
+            FROM connection_attempts a
+            JOIN bnetwork b ON b.id = a.node_id
+            WHERE a.network = $1
+              AND a.attempted_at > NOW() - make_interval(days => $3)
+              AND a.connect_ms IS NOT NULL
+              AND NOT a.vantage_failure
+              AND NOT a.proxy_failure
+            GROUP BY 1
+            ORDER BY 3 DESC
+            LIMIT 100
+            "#,
+            network_name(network),
+            group,
+            days
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener estadísticas de latencia")?;
+
+        Ok(stats)
+    }
+
+    pub async fn ip_info_list(&self) -> Result<Vec<String>> {
+        let ips = sqlx::query_scalar!(
+            "SELECT DISTINCT address FROM bnetwork WHERE type IN ('ipv4', 'ipv6') AND country IS NULL LIMIT 2000"
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener la lista de IPs para enriquecer")?;
+
+        Ok(ips)
+    }
+
+    pub async fn update_handshake_info(&self, node_id: i64, version: &VersionInfo) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork
+            SET
+                scanned = $1,
+                soft = $2,
+                services = $3,
+                protocol_version = $4,
+                start_height = $5,
+                relay = $6,
+                incoming = TRUE
+            WHERE id = $7
+            "#,
+            now,
+            version.user_agent,
+            version.services.0,
+            version.protocol_version,
+            version.start_height,
+            version.relay,
+            node_id
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al actualizar info del handshake para el nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+    pub async fn get_total_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM bnetwork WHERE network = $1",
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
+    }
+    pub async fn get_recent_nodes(
+        &self,
+        network: Network,
+        sort: NodeSort,
+        ascending: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<NodeInfo>> {
+        // La columna sale de una lista cerrada (NodeSort), nunca de la petición.
+        let query = format!(
+            "SELECT {}
+            FROM bnetwork
+            WHERE network = $1 AND scanned IS NOT NULL
+            ORDER BY {} {} NULLS LAST, id
+            LIMIT $2 OFFSET $3",
+            NODE_INFO_COLUMNS,
+            sort.column(),
+            if ascending { "ASC" } else { "DESC" }
+        );
+        let nodes = sqlx::query_as::<_, NodeInfo>(&query)
+            .bind(network_name(network))
+            .bind(limit)
+            .bind(offset)
+            .fetch_all(&self.0)
+            .await
+            .context("Fallo al obtener nodos recientes")?;
+
+        Ok(nodes)
+    }
+
+    pub async fn refresh_uptime_stats(&self) -> Result<u64> {
+        let now = Utc::now();
+
+        // Rachas: grupos de intentos consecutivos con handshake completo
+        // (gaps-and-islands). La duración de una racha va del primer al último
+        // éxito del grupo; la actual se cuenta hasta ahora.
+        let updated = sqlx::query!(
+            r#"
+            WITH attempts AS (
+                SELECT node_id, attempted_at,
+                       outcome = 'handshake_complete' AS online
+                FROM connection_attempts
+                WHERE attempted_at > $1::timestamptz - INTERVAL '30 days'
+                AND NOT vantage_failure AND NOT proxy_failure
+                AND node_id IS NOT NULL
+            ),
+            windows AS (
+                SELECT node_id,
+                    100.0 * AVG(online::int) FILTER (WHERE attempted_at > $1::timestamptz - INTERVAL '2 hours') AS up_2h,
+                    100.0 * AVG(online::int) FILTER (WHERE attempted_at > $1::timestamptz - INTERVAL '8 hours') AS up_8h,
+                    100.0 * AVG(online::int) FILTER (WHERE attempted_at > $1::timestamptz - INTERVAL '24 hours') AS up_24h,
+                    100.0 * AVG(online::int) FILTER (WHERE attempted_at > $1::timestamptz - INTERVAL '7 days') AS up_7d,
+                    100.0 * AVG(online::int) AS up_30d,
+                    (ARRAY_AGG(online ORDER BY attempted_at DESC))[1] AS last_online
+                FROM attempts
+                GROUP BY node_id
+            ),
+            islands AS (
+                SELECT node_id, attempted_at, online,
+                    ROW_NUMBER() OVER (PARTITION BY node_id ORDER BY attempted_at)
+                    - ROW_NUMBER() OVER (PARTITION BY node_id, online ORDER BY attempted_at) AS grp
+                FROM attempts
+            ),
+            runs AS (
+                SELECT node_id, MIN(attempted_at) AS run_start, MAX(attempted_at) AS run_end
+                FROM islands
+                WHERE online
+                GROUP BY node_id, grp
+            ),
+            streaks AS (
+                SELECT node_id,
+                    MAX(EXTRACT(EPOCH FROM (run_end - run_start)))::bigint AS longest,
+                    EXTRACT(EPOCH FROM ($1::timestamptz - MAX(run_start)))::bigint AS since_last_start
+                FROM runs
+                GROUP BY node_id
+            )
+            UPDATE bnetwork AS b SET
+                uptime_2h = w.up_2h::real,
+                uptime_8h = w.up_8h::real,
+                uptime_24h = w.up_24h::real,
+                uptime_7d = w.up_7d::real,
+                uptime_30d = w.up_30d::real,
+                current_streak_seconds = CASE
+                    WHEN w.last_online THEN COALESCE(s.since_last_start, 0)
+                    ELSE 0
+                END,
+                longest_streak_seconds = GREATEST(
+                    b.longest_streak_seconds,
+                    COALESCE(s.longest, 0),
+                    CASE WHEN w.last_online THEN COALESCE(s.since_last_start, 0) ELSE 0 END
+                ),
+                uptime_updated = $1
+            FROM windows w
+            LEFT JOIN streaks s ON s.node_id = w.node_id
+            WHERE b.id = w.node_id
+            "#,
+            now
+        )
+        .execute(&self.0)
+        .await
+        .context("Fallo al recalcular las métricas de uptime")?
+        .rows_affected();
+
+        // Nodos sin intentos en los últimos 30 días: sin datos de disponibilidad.
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork SET
+                uptime_2h = NULL, uptime_8h = NULL, uptime_24h = NULL,
+                uptime_7d = NULL, uptime_30d = NULL,
+                current_streak_seconds = 0,
+                uptime_updated = $1
+            WHERE uptime_updated IS NOT NULL AND uptime_updated < $1
+            "#,
+            now
+        )
+        .execute(&self.0)
+        .await
+        .context("Fallo al limpiar métricas de uptime obsoletas")?;
+
+        Ok(updated)
+    }
+    pub async fn find_nodes_by_address(
+        &self,
+        network: Network,
+        address: &str,
+        port: Option<u16>,
+    ) -> Result<Vec<NodeInfo>> {
+        let query = format!(
+            "SELECT {} FROM bnetwork
+            WHERE network = $1 AND address = $2 AND ($3::int4 IS NULL OR port = $3)
+            ORDER BY port",
+            NODE_INFO_COLUMNS
+        );
+        let nodes = sqlx::query_as::<_, NodeInfo>(&query)
+            .bind(network_name(network))
+            .bind(address)
+            .bind(port.map(i32::from))
+            .fetch_all(&self.0)
+            .await
+            .context("Fallo al buscar nodo por dirección")?;
+
+        Ok(nodes)
+    }
+
+    // Los nodos verificados cuentan según lo comprobado; el resto, según su
+    // flag NETWORK.
+    pub async fn get_archive_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND COALESCE(archive_served, services & 1 <> 0) AND incoming = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
+    }
+
+    pub async fn get_ipv4_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND type = 'ipv4' AND incoming = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
+    }
+    pub async fn get_ipv6_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND type = 'ipv6' AND incoming = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
+    }
+    pub async fn get_tor_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND type IN ('onionv2', 'onionv3') AND incoming = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
+    }
+    pub async fn update_capabilities(
+        &self,
+        node_id: i64,
+        capabilities: &Capabilities,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork
+            SET sendaddrv2 = $2,
+                wtxidrelay = $3,
+                sendheaders = $4,
+                sendcmpct_version = $5,
+                feefilter = $6,
+                capabilities_updated = NOW()
+            WHERE id = $1
+            "#,
+            node_id,
+            capabilities.sendaddrv2,
+            capabilities.wtxidrelay,
+            capabilities.sendheaders,
+            capabilities.sendcmpct_version,
+            capabilities.feefilter
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al guardar las capacidades del nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn update_v2_handshake(&self, node_id: i64, success: bool) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork
+            SET v2_handshake = $2,
+                v2_checked = NOW()
+            WHERE id = $1
+            "#,
+            node_id,
+            success
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al guardar el resultado v2 del nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+
+    // Una verificación sin respuesta deja el resultado en NULL; tras
+    // `failure_limit` seguidas el nodo cuenta como que no sirve el bloque.
+    pub async fn update_archive_check(
+        &self,
+        node_id: i64,
+        check: &ArchiveCheck,
+        failure_limit: i32,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            WITH verdict AS (
+                SELECT id, failures,
+                       CASE WHEN failures >= $4 THEN FALSE ELSE $2::boolean END AS served
+                FROM (
+                    SELECT id,
+                           CASE WHEN $2::boolean IS NULL THEN archive_failures + 1 ELSE 0 END AS failures
+                    FROM bnetwork
+                    WHERE id = $1
+                ) attempt
+            )
+            UPDATE bnetwork b
+            SET archive_served = v.served,
+                archive_latency_ms = $3,
+                archive_mismatch = (b.services & 1 <> 0) <> v.served,
+                archive_failures = v.failures,
+                archive_checked = NOW()
+            FROM verdict v
+            WHERE b.id = v.id
+            "#,
+            node_id,
+            check.served,
+            check.latency_ms,
+            failure_limit
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al guardar la verificación de archivo del nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn get_archive_stats(&self, network: Network) -> Result<ArchiveStats> {
+        let stats = sqlx::query_as!(
+            ArchiveStats,
+            r#"
+            SELECT
+                COUNT(*) FILTER (WHERE services & 1 <> 0) AS "advertised!",
+                COUNT(*) FILTER (WHERE archive_served IS NOT NULL) AS "checked!",
+                COUNT(*) FILTER (WHERE archive_served) AS "served!",
+                COUNT(*) FILTER (WHERE archive_mismatch) AS "mismatched!",
+                AVG(archive_latency_ms) FILTER (WHERE archive_served)::float8 AS avg_latency_ms
+            FROM bnetwork
+            WHERE network = $1 AND incoming = TRUE
+            "#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await
+        .context("Fallo al obtener estadísticas de nodos de archivo")?;
+
+        Ok(stats)
+    }
+
+    // Guarda la verificación y recalcula, entre los nodos con la misma parada,
+    // quién coincide con la mayoría. Sin mayoría absoluta queda sin decidir.
+    pub async fn update_filter_check(
+        &self,
+        network: Network,
+        node_id: i64,
+        check: &FilterCheck,
+        consensus_min: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork
+            SET cfilter_served = $2,
+                cfilter_stop_height = $3,
+                cfilter_stop_hash = $4,
+                cfilter_digest = $5,
+                cfilter_consensus = NULL,
+                cfilter_checked = NOW()
+            WHERE id = $1
+            "#,
+            node_id,
+            check.served,
+            check.stop_height,
+            check.stop_hash,
+            check.digest
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al guardar la verificación de filtros del nodo {}",
+            node_id
+        ))?;
+
+        let stop_hash = match &check.stop_hash {
+            Some(stop_hash) => stop_hash,
+            None => return Ok(()),
+        };
+        sqlx::query!(
+            r#"
+            WITH tally AS (
+                SELECT cfilter_digest AS digest,
+                       COUNT(*) AS node_count,
+                       SUM(COUNT(*)) OVER () AS total
+                FROM bnetwork
+                WHERE network = $1 AND cfilter_stop_hash = $2 AND cfilter_digest IS NOT NULL
+                GROUP BY cfilter_digest
+            ),
+            majority AS (
+                SELECT digest FROM tally WHERE node_count * 2 > total AND total >= $3::BIGINT
+            )
+            UPDATE bnetwork
+            SET cfilter_consensus = CASE
+                WHEN EXISTS (SELECT 1 FROM majority)
+                THEN cfilter_digest = (SELECT digest FROM majority)
+            END
+            WHERE network = $1 AND cfilter_stop_hash = $2 AND cfilter_digest IS NOT NULL
+            "#,
+            network_name(network),
+            stop_hash,
+            consensus_min
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al recalcular el consenso de filtros para la parada {}",
+            stop_hash
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn get_filter_stats(&self, network: Network) -> Result<FilterStats> {
+        let name = network_name(network);
+
+        let counts = sqlx::query!(
+            r#"
+            SELECT
+                COUNT(*) FILTER (WHERE services & 64 <> 0) AS "advertised!",
+                COUNT(*) FILTER (WHERE cfilter_served IS NOT NULL) AS "checked!",
+                COUNT(*) FILTER (WHERE cfilter_served) AS "served!",
+                COUNT(*) FILTER (WHERE cfilter_consensus) AS "consensus!",
+                COUNT(*) FILTER (WHERE NOT cfilter_consensus) AS "dissenting!"
+            FROM bnetwork
+            WHERE network = $1 AND incoming = TRUE
+            "#,
+            name
+        )
+        .fetch_one(&self.0)
+        .await
+        .context("Fallo al obtener estadísticas de filtros compactos")?;
+
+        let stop = sqlx::query!(
+            r#"
+            SELECT cfilter_stop_height AS "height!", cfilter_stop_hash AS "hash!"
+            FROM bnetwork
+            WHERE network = $1 AND cfilter_stop_hash IS NOT NULL
+            ORDER BY cfilter_stop_height DESC, cfilter_checked DESC
+            LIMIT 1
+            "#,
+            name
+        )
+        .fetch_optional(&self.0)
+        .await
+        .context("Fallo al obtener la parada de filtros más reciente")?;
+
+        let digests = match &stop {
+            Some(stop) => sqlx::query_as!(
+                FilterDigestCount,
+                r#"
+                SELECT cfilter_digest AS "digest!", COUNT(*) AS "node_count!"
+                FROM bnetwork
+                WHERE network = $1 AND cfilter_stop_hash = $2 AND cfilter_digest IS NOT NULL
+                GROUP BY cfilter_digest
+                ORDER BY 2 DESC
+                "#,
+                name,
+                stop.hash
+            )
+            .fetch_all(&self.0)
+            .await
+            .context("Fallo al obtener las respuestas 'cfcheckpt'")?,
+            None => Vec::new(),
+        };
+
+        Ok(FilterStats {
+            advertised: counts.advertised,
+            checked: counts.checked,
+            served: counts.served,
+            consensus: counts.consensus,
+            dissenting: counts.dissenting,
+            stop_height: stop.as_ref().map(|stop| stop.height),
+            stop_hash: stop.map(|stop| stop.hash),
+            digests,
+        })
+    }
+
+    pub async fn update_chain_tip(&self, node_id: i64, tip: &ChainTip) -> Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE bnetwork
+            SET tip_status = $2,
+                tip_hash = $3,
+                tip_height = $4,
+                tip_lag = $5,
+                tip_checked = NOW()
+            WHERE id = $1
+            "#,
+            node_id,
+            tip.status,
+            tip.hash,
+            tip.height,
+            tip.lag
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al guardar la punta de cadena del nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn update_chain_reference(
+        &self,
+        network: Network,
+        height: u32,
+        hash: &str,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO chain_reference (network, height, hash, updated_at)
+            VALUES ($1, $2, $3, NOW())
+            ON CONFLICT (network) DO UPDATE SET
+                height = EXCLUDED.height,
+                hash = EXCLUDED.hash,
+                updated_at = EXCLUDED.updated_at
+            "#,
+            network_name(network),
+            height as i32,
+            hash
+        )
+        .execute(&self.0)
+        .await
+        .context("Fallo al guardar la cadena de referencia")?;
 
         Ok(())
     }
 
-    pub async fn clean_db(&self) -> Result<()> {
-        let result = sqlx::query!(
+    pub async fn get_chain_tip_stats(&self, network: Network) -> Result<ChainTipStats> {
+        let name = network_name(network);
+
+        let reference = sqlx::query_as!(
+            ChainReference,
             r#"
-        DELETE FROM bnetwork 
-        WHERE 
-            detected < NOW() - INTERVAL '2 days'
-        "#
+            SELECT height, hash, updated_at
+            FROM chain_reference
+            WHERE network = $1
+            "#,
+            name
         )
-        .execute(&self.0)
+        .fetch_optional(&self.0)
         .await
-        .context("Error al limpiar la BBDD (clean_db)")?;
-
-        tracing::info!(
-            "[Mantenimiento] Limpieza de BBDD completada. Se eliminaron {} nodos antiguos.",
-            result.rows_affected()
-        );
+        .context("Fallo al obtener la cadena de referencia")?;
 
-        Ok(())
-    }
+        let statuses = sqlx::query_as!(
+            TipStatusCount,
+            r#"
+            SELECT tip_status AS "status!", COUNT(*) AS "node_count!"
+            FROM bnetwork
+            WHERE network = $1
+              AND incoming = TRUE
+              AND tip_checked > NOW() - INTERVAL '24 hours'
+            GROUP BY tip_status
+            ORDER BY 2 DESC
+            "#,
+            name
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener el estado de las puntas de cadena")?;
 
-    pub async fn ip_info_list(&self) -> Result<Vec<String>> {
-        let ips = sqlx::query_scalar!(
-            "SELECT address FROM bnetwork WHERE type LIKE '%ipv%' AND country IS NULL LIMIT 2000"
+        let tips = sqlx::query_as!(
+            ChainTipCount,
+            r#"
+            SELECT tip_hash AS "hash!", tip_height AS "height!", tip_status AS "status!",
+                   COUNT(*) AS "node_count!"
+            FROM bnetwork
+            WHERE network = $1
+              AND incoming = TRUE
+              AND tip_checked > NOW() - INTERVAL '24 hours'
+              AND tip_hash IS NOT NULL
+            GROUP BY tip_hash, tip_height, tip_status
+            ORDER BY 4 DESC, 2 DESC
+            LIMIT 50
+            "#,
+            name
         )
         .fetch_all(&self.0)
         .await
-        .context("Fallo al obtener la lista de IPs para enriquecer")?;
+        .context("Fallo al obtener la distribución de puntas de cadena")?;
 
-        Ok(ips)
+        let lagging = sqlx::query_as!(
+            TipLagCount,
+            r#"
+            SELECT tip_lag AS "lag!", COUNT(*) AS "node_count!"
+            FROM bnetwork
+            WHERE network = $1
+              AND incoming = TRUE
+              AND tip_checked > NOW() - INTERVAL '24 hours'
+              AND tip_status = 'lagging'
+            GROUP BY tip_lag
+            ORDER BY tip_lag
+            "#,
+            name
+        )
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener el retraso de los nodos")?;
+
+        Ok(ChainTipStats {
+            reference,
+            statuses,
+            tips,
+            lagging,
+        })
     }
 
-    pub async fn update_handshake_info(
-        &self,
-        address_with_port: &str,
-        agent: &str,
-        services: &str,
-        protocol_version: i32,
-        start_height: i32,
-        relay: bool,
-    ) -> Result<()> {
-        let address = if let Some((host, _port)) = address_with_port.rsplit_once(':') {
-            host
-        } else {
-            address_with_port
-        };
-        let now = Utc::now();
-        let address_str = address.to_string();
-        let address_str = address_str.trim_matches(|c| c == '[' || c == ']');
+    // Distribución de capacidades entre los nodos alcanzables. Las versiones de
+    // 'sendcmpct' y las tarifas de 'feefilter' se desglosan por familia de
+    // cliente para poder comparar la política de Core y Knots.
+    pub async fn get_capability_stats(&self, network: Network) -> Result<CapabilityStats> {
+        let name = network_name(network);
 
-        sqlx::query!(
+        let totals = sqlx::query!(
             r#"
-            UPDATE bnetwork
-            SET
-                scanned = $1,
-                soft = $2,
-                services = $3,
-                protocol_version = $4,
-                start_height = $5,
-                relay = $6,
-                incoming = TRUE
-            WHERE address = $7
+            SELECT
+                COUNT(*) AS "nodes!",
+                COUNT(*) FILTER (WHERE sendaddrv2) AS "sendaddrv2!",
+                COUNT(*) FILTER (WHERE wtxidrelay) AS "wtxidrelay!",
+                COUNT(*) FILTER (WHERE sendheaders) AS "sendheaders!"
+            FROM bnetwork
+            WHERE network = $1 AND incoming = TRUE AND capabilities_updated IS NOT NULL
             "#,
-            now,
-            agent,
-            services,
-            protocol_version,
-            start_height,
-            relay,
-            address_str
+            name
         )
-        .execute(&self.0)
+        .fetch_one(&self.0)
         .await
-        .context(format!(
-            "Fallo al actualizar info del handshake para {}",
-            address_str
-        ))?;
+        .context("Fallo al obtener el recuento de capacidades")?;
 
-        Ok(())
-    }
-    pub async fn get_total_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!("SELECT COUNT(*) FROM bnetwork")
-            .fetch_one(&self.0)
-            .await?
-            .unwrap_or(0);
-        Ok(count)
-    }
-    pub async fn get_recent_nodes(&self, limit: i64, offset: i64) -> Result<Vec<NodeInfo>> {
-        let nodes = sqlx::query_as!(
-            NodeInfo,
-            "SELECT address, soft, country, detected
+        let sendcmpct = sqlx::query_as!(
+            CapabilityCount,
+            r#"
+            SELECT
+                CASE
+                    WHEN soft LIKE '%Knots%' THEN 'knots'
+                    WHEN soft LIKE '/Satoshi:%' THEN 'core'
+                    ELSE 'other'
+                END AS "client!",
+                sendcmpct_version AS value,
+                COUNT(*) AS "node_count!"
             FROM bnetwork
-            WHERE scanned IS NOT NULL
-            ORDER BY scanned DESC
-            LIMIT $1 OFFSET $2",
-            limit,
-            offset
+            WHERE network = $1 AND incoming = TRUE AND capabilities_updated IS NOT NULL
+            GROUP BY 1, 2
+            ORDER BY 1, 2
+            "#,
+            name
         )
         .fetch_all(&self.0)
         .await
-        .context("Fallo al obtener nodos recientes")?;
+        .context("Fallo al obtener la distribución de sendcmpct")?;
 
-        Ok(nodes)
-    }
-    pub async fn find_node_by_address(&self, address: &str) -> Result<Option<NodeInfo>> {
-        let node = sqlx::query_as!(
-            NodeInfo,
-            "SELECT address, soft, country, detected
+        let feefilter = sqlx::query_as!(
+            CapabilityCount,
+            r#"
+            SELECT
+                CASE
+                    WHEN soft LIKE '%Knots%' THEN 'knots'
+                    WHEN soft LIKE '/Satoshi:%' THEN 'core'
+                    ELSE 'other'
+                END AS "client!",
+                feefilter AS value,
+                COUNT(*) AS "node_count!"
             FROM bnetwork
-            WHERE address = $1",
-            address
+            WHERE network = $1 AND incoming = TRUE AND capabilities_updated IS NOT NULL
+            GROUP BY 1, 2
+            ORDER BY 1, 2
+            "#,
+            name
         )
-        .fetch_optional(&self.0)
+        .fetch_all(&self.0)
         .await
-        .context("Fallo al buscar nodo por dirección")?;
-
-        Ok(node)
+        .context("Fallo al obtener la distribución de feefilter")?;
+
+        Ok(CapabilityStats {
+            nodes: totals.nodes,
+            sendaddrv2: totals.sendaddrv2,
+            wtxidrelay: totals.wtxidrelay,
+            sendheaders: totals.sendheaders,
+            sendcmpct,
+            feefilter,
+        })
     }
 
-    pub async fn get_archive_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork WHERE services ~ '(NETWORK\||NETWORK\))' AND incoming = TRUE"#
-        )
-        .fetch_one(&self.0)
-        .await?
-        .unwrap_or(0);
-        Ok(count)
-    }
+    // Nodos alcanzables con cada bit de servicio activo. Los bits con nombre
+    // aparecen siempre (aunque sea con 0 nodos); los desconocidos sólo si algún
+    // nodo los anuncia.
+    pub async fn get_service_stats(&self, network: Network) -> Result<ServiceStats> {
+        let name = network_name(network);
 
-    pub async fn get_ipv4_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork WHERE type = 'ipv4' AND incoming = TRUE"#
+        let nodes = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM bnetwork
+            WHERE network = $1 AND incoming = TRUE AND services IS NOT NULL
+            "#,
+            name
         )
         .fetch_one(&self.0)
-        .await?
-        .unwrap_or(0);
-        Ok(count)
-    }
-    pub async fn get_ipv6_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork WHERE type = 'ipv6' AND incoming = TRUE"#
+        .await
+        .context("Fallo al contar los nodos con servicios")?;
+
+        let rows = sqlx::query!(
+            r#"
+            SELECT bit AS "bit!", COUNT(*) AS "node_count!"
+            FROM bnetwork, generate_series(0, 63) AS bit
+            WHERE network = $1
+              AND incoming = TRUE
+              AND services IS NOT NULL
+              AND (services >> bit) & 1 = 1
+            GROUP BY bit
+            ORDER BY bit
+            "#,
+            name
         )
-        .fetch_one(&self.0)
-        .await?
-        .unwrap_or(0);
-        Ok(count)
+        .fetch_all(&self.0)
+        .await
+        .context("Fallo al obtener la distribución de bits de servicio")?;
+
+        let mut bits: Vec<ServiceBitCount> = NAMED_SERVICE_BITS
+            .iter()
+            .map(|(bit, flag)| ServiceBitCount {
+                bit: *bit as i32,
+                flag: flag.to_string(),
+                node_count: 0,
+            })
+            .collect();
+        for row in rows {
+            match bits.iter_mut().find(|b| b.bit == row.bit) {
+                Some(named) => named.node_count = row.node_count,
+                None => bits.push(ServiceBitCount {
+                    bit: row.bit,
+                    flag: service_bit_name(row.bit as u32),
+                    node_count: row.node_count,
+                }),
+            }
+        }
+        bits.sort_by_key(|b| b.bit);
+
+        Ok(ServiceStats { nodes, bits })
     }
-    pub async fn get_tor_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork  WHERE type IN ('onionv2', 'onionv3') AND incoming = TRUE"#
+
+    pub async fn get_transport_stats(&self, network: Network) -> Result<TransportStats> {
+        let stats = sqlx::query_as!(
+            TransportStats,
+            r#"
+            SELECT
+                COUNT(*) AS "nodes!",
+                COUNT(*) FILTER (WHERE (services >> 11) & 1 = 1) AS "advertised!",
+                COUNT(*) FILTER (WHERE v2_handshake IS NOT NULL) AS "checked!",
+                COUNT(*) FILTER (WHERE v2_handshake) AS "v2_nodes!"
+            FROM bnetwork
+            WHERE network = $1 AND incoming = TRUE
+            "#,
+            network_name(network)
         )
         .fetch_one(&self.0)
-        .await?
-        .unwrap_or(0);
-        Ok(count)
+        .await
+        .context("Fallo al obtener estadísticas de transporte v2")?;
+
+        Ok(stats)
     }
-    pub async fn get_knots_nodes_count(&self) -> Result<i64> {
+
+    pub async fn get_knots_nodes_count(&self, network: Network) -> Result<i64> {
         let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork  WHERE soft LIKE '%Knots%' AND incoming = TRUE"#
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND soft LIKE '%Knots%' AND incoming = TRUE"#,
+            network_name(network)
         )
         .fetch_one(&self.0)
         .await?
         .unwrap_or(0);
         Ok(count)
     }
-    pub async fn get_core30_nodes_count(&self) -> Result<i64> {
+    pub async fn get_core30_nodes_count(&self, network: Network) -> Result<i64> {
         let count = sqlx::query_scalar!(
-            r#"SELECT COUNT(*) FROM bnetwork  WHERE soft LIKE '/Satoshi:30%' AND incoming = TRUE"#
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND soft LIKE '/Satoshi:30%' AND incoming = TRUE"#,
+            network_name(network)
         )
         .fetch_one(&self.0)
         .await?
@@ -396,40 +1924,10 @@ impl Database {
         Ok(count)
     }
 
-    pub async fn handle_successful_connection(&self, address_with_port: &str) -> Result<()> {
+    // El siguiente intento lo decide la política de reprogramación al
+    // procesar el resultado de la sesión (`schedule_next_attempt`).
+    pub async fn handle_successful_connection(&self, node_id: i64) -> Result<()> {
         let now = Utc::now();
-        let address_part = if let Some((host, _port)) = address_with_port.rsplit_once(':') {
-            host
-        } else {
-            address_with_port
-        };
-        let address_str = address_part
-            .trim_matches(|c| c == '[' || c == ']')
-            .to_string();
-
-        let node_type_result =
-            sqlx::query_scalar!("SELECT type FROM bnetwork WHERE address = $1", address_str)
-                .fetch_optional(&self.0)
-                .await;
-
-        let node_type: Option<String> = match node_type_result {
-            Ok(opt_opt_string) => opt_opt_string.flatten(),
-            Err(e) => {
-                tracing::error!("Failed to fetch node type for {}: {}", address_str, e);
-                return Err(e.into());
-            }
-        };
-
-        let next_attempt = match node_type.as_deref() {
-            Some("onionv2") | Some("onionv3") | Some("i2p") => {
-                let mut rng = rand::rng();
-                let random_hours = rng.random_range(8..=15);
-                let random_minutes = rng.random_range(0..=59);
-                now + chrono::Duration::hours(random_hours)
-                    + chrono::Duration::minutes(random_minutes)
-            }
-            _ => now + chrono::Duration::hours(12),
-        };
 
         sqlx::query!(
             r#"
@@ -437,57 +1935,113 @@ impl Database {
             SET 
                 scanned = $1, 
                 incoming = TRUE,
+                inbound_only = FALSE,
                 consecutive_failures = 0,
-                reliability_score = reliability_score + 1,
-                next_attempt_time = $2
-            WHERE address = $3
+                reliability_score = reliability_score + 1
+            WHERE id = $2
             "#,
             now,
-            next_attempt,
-            address_str
+            node_id
         )
         .execute(&self.0)
         .await
         .context(format!(
-            "Fallo al manejar conexión exitosa para {}",
-            address_str
+            "Fallo al manejar conexión exitosa para el nodo {}",
+            node_id
         ))?;
 
         Ok(())
     }
 
-    pub async fn handle_failed_connection(&self, address_with_port: &str) -> Result<()> {
-        let address_part = if let Some((host, _port)) = address_with_port.rsplit_once(':') {
-            host
-        } else {
-            address_with_port
+    // El handshake sólo se da por completado al recibir el 'verack'.
+    pub async fn record_handshake(&self, node_id: i64) -> Result<()> {
+        sqlx::query!(
+            "UPDATE bnetwork SET last_handshake = NOW() WHERE id = $1",
+            node_id
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al registrar el handshake del nodo {}",
+            node_id
+        ))?;
+
+        Ok(())
+    }
+
+    pub async fn get_node_history(&self, node_id: i64) -> Result<Option<NodeHistory>> {
+        let row = sqlx::query!(
+            r#"
+            SELECT address, type AS "node_type", reliability_score, consecutive_failures, uptime_7d
+            FROM bnetwork
+            WHERE id = $1
+            "#,
+            node_id
+        )
+        .fetch_optional(&self.0)
+        .await
+        .context(format!(
+            "Fallo al obtener el historial del nodo {}",
+            node_id
+        ))?;
+
+        Ok(row.map(|row| NodeHistory {
+            address: row.address,
+            address_type: row.node_type.as_deref().and_then(AddressType::from_name),
+            reliability_score: row.reliability_score.unwrap_or(0),
+            consecutive_failures: row.consecutive_failures.unwrap_or(0),
+            uptime_7d: row.uptime_7d,
+        }))
+    }
+
+    // Tras una sesión con éxito, ya registrada por `handle_successful_connection`.
+    pub async fn schedule_next_attempt(
+        &self,
+        node_id: i64,
+        policy: &dyn SchedulePolicy,
+    ) -> Result<()> {
+        let history = match self.get_node_history(node_id).await? {
+            Some(history) => history,
+            None => return Ok(()),
         };
-        let address = address_part.trim_matches(|c| c == '[' || c == ']');
+        let next_attempt = Utc::now() + policy.next_attempt(&history);
 
-        let node_info: Option<(String, i32)> =
-            sqlx::query_as("SELECT type, consecutive_failures FROM bnetwork WHERE address = $1")
-                .bind(address)
-                .fetch_optional(&self.0)
-                .await?;
+        sqlx::query!(
+            "UPDATE bnetwork SET next_attempt_time = $1 WHERE id = $2",
+            next_attempt,
+            node_id
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al programar el siguiente intento de {}",
+            history.address
+        ))?;
+
+        Ok(())
+    }
 
-        let (node_type, current_failures) =
-            if let Some((fetched_type, fetched_failures)) = node_info {
-                (fetched_type, fetched_failures)
-            } else {
+    pub async fn handle_failed_connection(
+        &self,
+        node_id: i64,
+        policy: &dyn SchedulePolicy,
+    ) -> Result<()> {
+        let mut history = match self.get_node_history(node_id).await? {
+            Some(history) => history,
+            None => {
                 tracing::debug!(
                     "Fallo registrado para un nodo no existente en la BBDD: {}",
-                    address
+                    node_id
                 );
                 return Ok(());
-            };
+            }
+        };
 
-        let new_failures = current_failures + 1;
+        history.consecutive_failures += 1;
+        history.reliability_score -= 1;
+        let new_failures = history.consecutive_failures;
 
-        let next_attempt = {
-            let mut rng = rand::rng();
-            let random_seconds = rng.random_range((4 * 60 * 60)..=(24 * 60 * 60));
-            Utc::now() + chrono::Duration::seconds(random_seconds)
-        };
+        let next_attempt = Utc::now() + policy.next_attempt(&history);
 
         let set_incoming: Option<bool> = if new_failures >= 3 { Some(false) } else { None };
         let now = Utc::now();
@@ -498,47 +2052,72 @@ impl Database {
                     reliability_score = reliability_score - 1,
                     next_attempt_time = $2,
                     incoming = COALESCE($3, incoming),
+                    inbound_only = CASE
+                        WHEN last_inbound IS NOT NULL AND COALESCE($3, incoming) IS NOT TRUE THEN TRUE
+                        ELSE inbound_only
+                    END,
                     scanned = $4
-                WHERE address = $5
+                WHERE id = $5
                 "#,
             new_failures,
             next_attempt,
             set_incoming,
             now,
-            address
+            node_id
         )
         .execute(&self.0)
         .await
         .context(format!(
             "Fallo al actualizar estado tras fallo para {}",
-            address
+            history.address
         ))?;
 
-        if node_type.contains("onion") && new_failures >= 3 {
-            tracing::warn!(
-                "[Pruning] El nodo Tor {} ha fallado {} veces. Eliminando de la BBDD.",
-                address,
-                new_failures
-            );
-            sqlx::query!("DELETE FROM bnetwork WHERE address = $1", address)
-                .execute(&self.0)
-                .await
-                .context(format!("Fallo al borrar nodo Tor {}", address))?;
-        }
+        Ok(())
+    }
+
+    pub async fn handle_vantage_failure(&self, node_id: i64) -> Result<()> {
+        // Reintento pronto: el nodo no tiene la culpa de la caída.
+        let next_attempt = {
+            let mut rng = rand::rng();
+            let random_seconds = rng.random_range((10 * 60)..=(30 * 60));
+            Utc::now() + chrono::Duration::seconds(random_seconds)
+        };
+
+        sqlx::query!(
+            r#"
+                UPDATE bnetwork SET
+                    vantage_failures = vantage_failures + 1,
+                    last_vantage_failure = NOW(),
+                    next_attempt_time = $1
+                WHERE id = $2
+                "#,
+            next_attempt,
+            node_id
+        )
+        .execute(&self.0)
+        .await
+        .context(format!(
+            "Fallo al registrar fallo de conectividad local para el nodo {}",
+            node_id
+        ))?;
 
         Ok(())
     }
 
-    pub async fn get_software_version_stats(&self) -> Result<Vec<SoftwareVersionStat>> {
+    pub async fn get_software_version_stats(
+        &self,
+        network: Network,
+    ) -> Result<Vec<SoftwareVersionStat>> {
         let stats = sqlx::query_as!(
             SoftwareVersionStat,
             r#"
             SELECT soft, COUNT(*) as node_count
             FROM bnetwork
-            WHERE soft IS NOT NULL AND soft != '' AND incoming = TRUE
+            WHERE network = $1 AND soft IS NOT NULL AND soft != '' AND incoming = TRUE
             GROUP BY soft
             ORDER BY node_count DESC
-            "#
+            "#,
+            network_name(network)
         )
         .fetch_all(&self.0)
         .await
@@ -547,7 +2126,7 @@ impl Database {
         Ok(stats)
     }
 
-    pub async fn get_top_software_stats(&self) -> Result<Vec<SoftwareCount>> {
+    pub async fn get_top_software_stats(&self, network: Network) -> Result<Vec<SoftwareCount>> {
         let stats = sqlx::query_as!(
             SoftwareCount,
             r#"
@@ -555,11 +2134,12 @@ impl Database {
                 soft,
                 COUNT(*) as "node_count!"
             FROM bnetwork
-            WHERE soft IS NOT NULL AND soft != '' AND incoming = TRUE
+            WHERE network = $1 AND soft IS NOT NULL AND soft != '' AND incoming = TRUE
             GROUP BY soft
             ORDER BY COUNT(*) DESC
             LIMIT 10
-            "#
+            "#,
+            network_name(network)
         )
         .fetch_all(&self.0)
         .await
@@ -568,7 +2148,11 @@ impl Database {
         Ok(stats)
     }
 
-    pub async fn get_historical_stats_by_range(&self, range: &str) -> Result<Vec<HourlyStat>> {
+    pub async fn get_historical_stats_by_range(
+        &self,
+        network: Network,
+        range: &str,
+    ) -> Result<Vec<HourlyStat>> {
         let (interval, filter_condition) = match range {
             "1w" => ("'7 days'", "EXTRACT(HOUR FROM snapshot_time) % 6 = 0"),
             "1m" => ("'1 month'", "EXTRACT(HOUR FROM snapshot_time) = 0"),
@@ -583,15 +2167,20 @@ impl Database {
             r#"
             SELECT 
                 snapshot_time, 
+                network,
                 total_nodes, 
                 incoming_nodes, 
                 archive_nodes, 
                 ipv4_nodes, 
                 ipv6_nodes, 
                 onion_nodes, 
-                top_software
+                top_software,
+                capabilities,
+                service_bits,
+                transport
             FROM hourly_stats
-            WHERE snapshot_time > NOW() - INTERVAL {} 
+            WHERE network = $1
+            AND snapshot_time > NOW() - INTERVAL {} 
             AND {}
             ORDER BY snapshot_time ASC
             "#,
@@ -599,6 +2188,7 @@ impl Database {
         );
 
         let stats = sqlx::query_as::<_, HourlyStat>(&query)
+            .bind(network_name(network))
             .fetch_all(&self.0)
             .await
             .context("Fallo al obtener estadísticas históricas por rango")?;
@@ -606,59 +2196,81 @@ impl Database {
         Ok(stats)
     }
 
-    pub async fn update_inbound_node_info(
+    pub async fn upsert_inbound_peer(
         &self,
-        address_with_port: &str,
-        agent: &str,
-        services: &str,
-        protocol_version: i32,
-        start_height: i32,
-        relay: bool,
-    ) -> Result<()> {
-        let address_part = if let Some((host, _port)) = address_with_port.rsplit_once(':') {
-            host
-        } else {
-            address_with_port
-        };
-        let address_str = address_part
-            .trim_matches(|c| c == '[' || c == ']')
-            .to_string();
+        network: Network,
+        address: &NetAddress,
+        port: u16,
+        version: &VersionInfo,
+    ) -> Result<i64> {
+        let now = Utc::now();
 
-        sqlx::query!(
+        let node_id = sqlx::query_scalar!(
             r#"
-            UPDATE bnetwork
-            SET
-                soft = $1,
-                services = $2,
-                protocol_version = $3,
-                start_height = $4,
-                relay = $5
-            WHERE address = $6
-            "#,
-            agent,
-            services,
-            protocol_version,
-            start_height,
-            relay,
-            address_str
+            INSERT INTO bnetwork (
+                network, address, type, port, services, soft, protocol_version,
+                start_height, relay, added, detected, inbound_only, last_inbound
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $10, TRUE, $10)
+            ON CONFLICT (network, address, port) DO UPDATE SET
+                soft = EXCLUDED.soft,
+                services = EXCLUDED.services,
+                protocol_version = EXCLUDED.protocol_version,
+                start_height = EXCLUDED.start_height,
+                relay = EXCLUDED.relay,
+                detected = EXCLUDED.detected,
+                last_inbound = EXCLUDED.last_inbound,
+                inbound_only = bnetwork.incoming IS NOT TRUE
+            RETURNING id
+            "#,
+            network_name(network),
+            address.to_string(),
+            address.address_type().as_str(),
+            port as i32,
+            version.services.0,
+            version.user_agent,
+            version.protocol_version,
+            version.start_height,
+            version.relay,
+            now
         )
-        .execute(&self.0)
+        .fetch_one(&self.0)
         .await
-        .context(format!(
-            "Fallo al actualizar info (inbound) para {}",
-            address_str
-        ))?;
+        .context(format!("Fallo al registrar el par entrante {}", address))?;
 
-        Ok(())
+        Ok(node_id)
+    }
+
+    pub async fn get_inbound_only_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND inbound_only = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
+        Ok(count)
     }
-    pub async fn get_incoming_stats_by_protocol(&self) -> Result<Vec<ProtocolStats>> {
+
+    pub async fn get_incoming_stats_by_protocol(
+        &self,
+        network: Network,
+    ) -> Result<Vec<ProtocolStats>> {
         let mut final_stats: Vec<ProtocolStats> = Vec::new();
 
-        let simple_protocols = vec!["ipv4", "ipv6", "i2p", "cjdns", "yggdrasil"];
+        let simple_protocols = [
+            AddressType::Ipv4,
+            AddressType::Ipv6,
+            AddressType::I2p,
+            AddressType::Cjdns,
+            AddressType::Yggdrasil,
+        ];
 
-        for proto in simple_protocols {
+        for address_type in simple_protocols {
+            let proto = address_type.as_str();
             let total = sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM bnetwork WHERE incoming = TRUE AND type = $1",
+                "SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND incoming = TRUE AND type = $2",
+                network_name(network),
                 proto
             )
             .fetch_one(&self.0)
@@ -671,13 +2283,15 @@ impl Database {
                     SELECT soft as "client", COUNT(*) as "count!"
                     FROM bnetwork
                     WHERE 
-                        incoming = TRUE 
-                        AND type = $1 
+                        network = $1
+                        AND incoming = TRUE 
+                        AND type = $2 
                         AND soft IS NOT NULL AND soft != ''
                     GROUP BY soft 
                     ORDER BY COUNT(*) DESC
                     LIMIT 10
                     "#,
+                network_name(network),
                 proto
             )
             .fetch_all(&self.0)
@@ -691,11 +2305,12 @@ impl Database {
         }
 
         let onion_total = sqlx::query_scalar!(
-                "SELECT COUNT(*) FROM bnetwork WHERE incoming = TRUE AND type IN ('onionv2', 'onionv3')"
-            )
-            .fetch_one(&self.0)
-            .await?
-            .unwrap_or(0);
+            "SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND incoming = TRUE AND type IN ('onionv2', 'onionv3')",
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
 
         let onion_clients = sqlx::query_as!(
             ClientCount,
@@ -703,13 +2318,15 @@ impl Database {
                 SELECT soft as "client", COUNT(*) as "count!"
                 FROM bnetwork
                 WHERE 
-                    incoming = TRUE 
+                    network = $1
+                    AND incoming = TRUE 
                     AND type IN ('onionv2', 'onionv3') 
                     AND soft IS NOT NULL AND soft != ''
                 GROUP BY soft 
                 ORDER BY COUNT(*) DESC
                 LIMIT 10
-                "#
+                "#,
+            network_name(network)
         )
         .fetch_all(&self.0)
         .await?;
@@ -723,7 +2340,12 @@ impl Database {
         Ok(final_stats)
     }
 
-    pub async fn batch_upsert_addrv2_nodes(&self, nodes: &[DiscoveredNode]) -> Result<()> {
+    pub async fn batch_upsert_addrv2_nodes(
+        &self,
+        network: Network,
+        announcer_id: i64,
+        nodes: &[DiscoveredNode],
+    ) -> Result<()> {
         if nodes.is_empty() {
             return Ok(());
         }
@@ -732,64 +2354,80 @@ impl Database {
         let mut addresses = Vec::with_capacity(nodes.len());
         let mut ports = Vec::with_capacity(nodes.len());
         let mut services = Vec::with_capacity(nodes.len());
+        let mut gossip_times = Vec::with_capacity(nodes.len());
 
         for node in nodes {
-            types.push(node.addr_type.clone());
-            addresses.push(node.addr_str.clone());
+            types.push(node.address.address_type().as_str().to_string());
+            addresses.push(node.address.to_string());
             ports.push(node.port as i32);
-            services.push(node.services.clone());
+            services.push(node.services.0);
+            gossip_times.push(node.gossip_time);
         }
         let now = Utc::now();
 
-        sqlx::query!(
+        let node_ids = sqlx::query_scalar!(
             r#"
-            INSERT INTO bnetwork (address, type, port, services, added, detected)
+            INSERT INTO bnetwork (network, address, type, port, services, added, detected, last_gossip)
             SELECT 
+                $6,
                 u.address, 
                 u.type, 
                 u.port, 
                 u.services, 
                 $5,
-                $5
+                $5,
+                u.gossip_time
             FROM UNNEST(
-                $1::text[], $2::text[], $3::int4[], $4::text[]
-            ) AS u(address, type, port, services)
-            ON CONFLICT (address) DO UPDATE SET
-                detected = $5
+                $1::text[], $2::text[], $3::int4[], $4::int8[], $7::timestamptz[]
+            ) AS u(address, type, port, services, gossip_time)
+            ON CONFLICT (network, address, port) DO UPDATE SET
+                detected = $5,
+                last_gossip = GREATEST(bnetwork.last_gossip, EXCLUDED.last_gossip)
+            RETURNING id
             "#,
             &addresses[..],
             &types[..],
             &ports[..],
             &services[..],
-            now
+            now,
+            network_name(network),
+            &gossip_times[..]
         )
-        .execute(&self.0)
+        .fetch_all(&self.0)
         .await?;
 
+        self.record_announcements(announcer_id, &node_ids, now)
+            .await?;
+
         Ok(())
     }
-    pub async fn get_incoming_nodes_count(&self) -> Result<i64> {
-        let count = sqlx::query_scalar!(r#"SELECT COUNT(*) FROM bnetwork WHERE incoming = TRUE"#)
-            .fetch_one(&self.0)
-            .await?
-            .unwrap_or(0);
+    pub async fn get_incoming_nodes_count(&self, network: Network) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) FROM bnetwork WHERE network = $1 AND incoming = TRUE"#,
+            network_name(network)
+        )
+        .fetch_one(&self.0)
+        .await?
+        .unwrap_or(0);
         Ok(count)
     }
 
-    pub async fn search_nodes(&self, query: &str) -> Result<Vec<NodeInfo>> {
+    pub async fn search_nodes(&self, network: Network, query: &str) -> Result<Vec<NodeInfo>> {
         let pattern = format!("%{}%", query);
-        let nodes = sqlx::query_as!(
-            NodeInfo,
-            "SELECT address, soft, country, detected
+        let query = format!(
+            "SELECT {}
             FROM bnetwork
-            WHERE address ILIKE $1 OR soft ILIKE $1
+            WHERE network = $1 AND (address ILIKE $2 OR soft ILIKE $2)
             ORDER BY detected DESC NULLS LAST
             LIMIT 50",
-            pattern
-        )
-        .fetch_all(&self.0)
-        .await
-        .context("Fallo al buscar nodos")?;
+            NODE_INFO_COLUMNS
+        );
+        let nodes = sqlx::query_as::<_, NodeInfo>(&query)
+            .bind(network_name(network))
+            .bind(pattern)
+            .fetch_all(&self.0)
+            .await
+            .context("Fallo al buscar nodos")?;
 
         Ok(nodes)
     }
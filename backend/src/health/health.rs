@@ -0,0 +1,375 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::{ProxyConfig, VantageConfig};
+
+const GENERAL_FAILURE_THRESHOLD: u32 = 10;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProxyStatus {
+    pub name: String,
+    pub address: String,
+    pub healthy: bool,
+    pub since: DateTime<Utc>,
+    pub last_check: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub consecutive_general_failures: u32,
+}
+
+struct ProxyState {
+    healthy: bool,
+    verified: bool,
+    since: DateTime<Utc>,
+    last_check: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    consecutive_general_failures: u32,
+}
+
+pub struct ProxyHealth {
+    name: &'static str,
+    proxy: ProxyConfig,
+    state: Mutex<ProxyState>,
+}
+
+enum ProxyFailure {
+    Unreachable,
+    GeneralFailure,
+}
+
+impl ProxyHealth {
+    pub fn new(name: &'static str, proxy: ProxyConfig) -> Self {
+        ProxyHealth {
+            name,
+            proxy,
+            state: Mutex::new(ProxyState {
+                healthy: true,
+                verified: false,
+                since: Utc::now(),
+                last_check: None,
+                last_error: None,
+                consecutive_general_failures: 0,
+            }),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().healthy
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.verified = true;
+        state.consecutive_general_failures = 0;
+        if !state.healthy {
+            self.set_healthy(&mut state, true, None);
+        }
+    }
+
+    // Devuelve `true` si el fallo es atribuible al proxy y no debe penalizar al nodo.
+    pub fn record_failure(&self, error: &anyhow::Error) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        match classify_proxy_failure(error) {
+            Some(ProxyFailure::Unreachable) => {
+                if state.healthy {
+                    self.set_healthy(&mut state, false, Some(error.to_string()));
+                }
+                true
+            }
+            Some(ProxyFailure::GeneralFailure) => {
+                state.consecutive_general_failures += 1;
+                if state.healthy && state.consecutive_general_failures >= GENERAL_FAILURE_THRESHOLD
+                {
+                    self.set_healthy(&mut state, false, Some(error.to_string()));
+                }
+                !state.healthy || !state.verified
+            }
+            None => {
+                // El proxy ha completado el handshake SOCKS: el fallo es del nodo.
+                state.verified = true;
+                state.consecutive_general_failures = 0;
+                !state.healthy
+            }
+        }
+    }
+
+    pub async fn probe(&self) {
+        let result = probe_socks_greeting(&self.proxy).await;
+        let mut state = self.state.lock().unwrap();
+        state.last_check = Some(Utc::now());
+
+        match result {
+            Ok(()) => {
+                if !state.healthy {
+                    state.verified = false;
+                    state.consecutive_general_failures = 0;
+                    self.set_healthy(&mut state, true, None);
+                }
+            }
+            Err(e) => {
+                if state.healthy {
+                    self.set_healthy(&mut state, false, Some(e.to_string()));
+                } else {
+                    state.last_error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    pub fn status(&self) -> ProxyStatus {
+        let state = self.state.lock().unwrap();
+        ProxyStatus {
+            name: self.name.to_string(),
+            address: self.proxy.address.clone(),
+            healthy: state.healthy,
+            since: state.since,
+            last_check: state.last_check,
+            last_error: state.last_error.clone(),
+            consecutive_general_failures: state.consecutive_general_failures,
+        }
+    }
+
+    fn set_healthy(&self, state: &mut ProxyState, healthy: bool, error: Option<String>) {
+        state.healthy = healthy;
+        state.since = Utc::now();
+        if healthy {
+            tracing::info!(
+                "[Proxy] El proxy {} ({}) vuelve a estar operativo. Reanudando el sondeo.",
+                self.name,
+                self.proxy.address
+            );
+        } else {
+            tracing::warn!(
+                "[Proxy] El proxy {} ({}) no responde: {}. Pausando el sondeo de sus nodos.",
+                self.name,
+                self.proxy.address,
+                error.as_deref().unwrap_or("desconocido")
+            );
+            state.last_error = error;
+        }
+    }
+}
+
+pub struct ProxyMonitor {
+    pub tor: ProxyHealth,
+    pub i2p: ProxyHealth,
+}
+
+impl ProxyMonitor {
+    pub fn new(config: &crate::config::Config) -> Self {
+        ProxyMonitor {
+            tor: ProxyHealth::new("tor", config.tor_proxy.clone()),
+            i2p: ProxyHealth::new("i2p", config.i2p_proxy.clone()),
+        }
+    }
+
+    pub async fn probe_all(&self) {
+        tokio::join!(self.tor.probe(), self.i2p.probe());
+    }
+
+    pub fn statuses(&self) -> Vec<ProxyStatus> {
+        vec![self.tor.status(), self.i2p.status()]
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct VantageStatus {
+    pub healthy: bool,
+    pub since: DateTime<Utc>,
+    pub last_error: Option<String>,
+    pub suspended_attempts: u64,
+}
+
+struct VantageState {
+    healthy: bool,
+    since: DateTime<Utc>,
+    last_error: Option<String>,
+    suspended_attempts: u64,
+}
+
+// Detecta caídas de nuestra propia conectividad para no culpar a los nodos de ellas.
+pub struct VantageMonitor {
+    config: VantageConfig,
+    state: Mutex<VantageState>,
+}
+
+impl VantageMonitor {
+    pub fn new(config: &crate::config::Config) -> Self {
+        VantageMonitor {
+            config: config.vantage.clone(),
+            state: Mutex::new(VantageState {
+                healthy: true,
+                since: Utc::now(),
+                last_error: None,
+                suspended_attempts: 0,
+            }),
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.state.lock().unwrap().healthy
+    }
+
+    // Antes de lanzar un lote estando caídos: solo se reanuda si algún canario responde.
+    pub async fn ready_for_batch(&self) -> bool {
+        if self.is_healthy() {
+            return true;
+        }
+        match self.check_canaries().await {
+            Some(false) => false,
+            _ => {
+                self.set_healthy(true, None);
+                true
+            }
+        }
+    }
+
+    // Evalúa el resultado de un lote. Devuelve `true` si los fallos deben
+    // registrarse como fallos del punto de observación y no de los nodos.
+    pub async fn assess_batch(&self, successes: usize, failures: usize) -> bool {
+        if failures == 0 {
+            if successes > 0 && !self.is_healthy() {
+                self.set_healthy(true, None);
+            }
+            return false;
+        }
+
+        let attempts = successes + failures;
+        let failure_rate = failures as f64 / attempts as f64;
+
+        // Una ventana casi toda fallida es normal con direcciones recién
+        // anunciadas, que suelen estar muertas; sólo es una caída nuestra si
+        // además no responde ningún canario.
+        let high_failure_rate =
+            attempts >= self.config.min_batch && failure_rate >= self.config.failure_threshold;
+        let reason = if (high_failure_rate || successes == 0)
+            && self.check_canaries().await == Some(false)
+        {
+            Some(format!(
+                "{} de {} conexiones fallidas en el lote y ningún canario de conectividad responde",
+                failures, attempts
+            ))
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                if self.is_healthy() {
+                    self.set_healthy(false, Some(reason));
+                }
+                self.state.lock().unwrap().suspended_attempts += failures as u64;
+                true
+            }
+            None => {
+                if successes > 0 && !self.is_healthy() {
+                    self.set_healthy(true, None);
+                }
+                false
+            }
+        }
+    }
+
+    pub fn status(&self) -> VantageStatus {
+        let state = self.state.lock().unwrap();
+        VantageStatus {
+            healthy: state.healthy,
+            since: state.since,
+            last_error: state.last_error.clone(),
+            suspended_attempts: state.suspended_attempts,
+        }
+    }
+
+    // `None` si no hay canarios configurados.
+    async fn check_canaries(&self) -> Option<bool> {
+        if self.config.canaries.is_empty() {
+            return None;
+        }
+
+        let timeout = Duration::from_secs(3);
+        let checks = self.config.canaries.iter().map(|canary| async move {
+            matches!(
+                tokio::time::timeout(timeout, TcpStream::connect(canary.as_str())).await,
+                Ok(Ok(_))
+            )
+        });
+        let results = futures::future::join_all(checks).await;
+
+        Some(results.into_iter().any(|reachable| reachable))
+    }
+
+    fn set_healthy(&self, healthy: bool, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        if state.healthy == healthy {
+            return;
+        }
+        state.healthy = healthy;
+        state.since = Utc::now();
+        if healthy {
+            tracing::info!(
+                "[Vantage] Conectividad local recuperada. Reanudando la puntuación de nodos."
+            );
+        } else {
+            tracing::warn!(
+                "[Vantage] Posible caída de la conectividad local: {}. Suspendiendo la puntuación de nodos.",
+                error.as_deref().unwrap_or("desconocido")
+            );
+            state.last_error = error;
+        }
+    }
+}
+
+fn classify_proxy_failure(error: &anyhow::Error) -> Option<ProxyFailure> {
+    for cause in error.chain() {
+        if let Some(socks_error) = cause.downcast_ref::<tokio_socks::Error>() {
+            return match socks_error {
+                tokio_socks::Error::ProxyServerUnreachable
+                | tokio_socks::Error::Io(_)
+                | tokio_socks::Error::InvalidResponseVersion
+                | tokio_socks::Error::NoAcceptableAuthMethods
+                | tokio_socks::Error::PasswordAuthFailure(_) => Some(ProxyFailure::Unreachable),
+                tokio_socks::Error::GeneralSocksServerFailure => Some(ProxyFailure::GeneralFailure),
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+async fn probe_socks_greeting(proxy: &ProxyConfig) -> anyhow::Result<()> {
+    let timeout = Duration::from_secs(5);
+
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(proxy.address.as_str()))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timeout al conectar con el proxy {}", proxy.address))??;
+
+    let greeting: &[u8] = if proxy.username.is_some() || proxy.stream_isolation {
+        &[0x05, 0x02, 0x00, 0x02]
+    } else {
+        &[0x05, 0x01, 0x00]
+    };
+    stream.write_all(greeting).await?;
+
+    let mut reply = [0u8; 2];
+    tokio::time::timeout(timeout, stream.read_exact(&mut reply))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!("Timeout esperando la respuesta SOCKS de {}", proxy.address)
+        })??;
+
+    if reply[0] != 0x05 || reply[1] == 0xff {
+        anyhow::bail!(
+            "Respuesta SOCKS inesperada de {}: {:02x}{:02x}",
+            proxy.address,
+            reply[0],
+            reply[1]
+        );
+    }
+
+    Ok(())
+}
@@ -1,18 +1,23 @@
 use anyhow::Result;
 use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::{routing::get, Json, Router};
+use bitcoin::p2p::ServiceFlags;
+use bitcoin::Network;
 use flate2::read::GzDecoder;
-use futures::future::join_all;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sqlx::postgres::PgPoolOptions;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs::File;
 use std::net::IpAddr;
 use std::sync::Arc;
 use tar::Archive;
 use tokio::sync::broadcast;
-use tokio::sync::Semaphore;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::{sleep, Duration};
 use tokio_cron_scheduler::{Job, JobScheduler};
 use tower_http::cors::CorsLayer;
@@ -20,16 +25,24 @@ use tower_http::services::ServeDir;
 
 #[path = "common/common.rs"]
 pub mod common;
+#[path = "config/config.rs"]
+pub mod config;
+#[path = "crawler/crawler.rs"]
+pub mod crawler;
 #[path = "database/db.rs"]
 pub mod db;
+#[path = "health/health.rs"]
+pub mod health;
 #[path = "p2p/p2p.rs"]
 pub mod p2p;
-use crate::db::NodeToScan;
+use crate::common::{AddressType, NetAddress};
 
 #[derive(Serialize)]
 struct Stats {
+    network: String,
     total_nodes: i64,
     incoming_nodes: i64,
+    inbound_only_nodes: i64,
     archive_nodes: i64,
     ipv4_nodes: i64,
     ipv6_nodes: i64,
@@ -38,6 +51,18 @@ struct Stats {
     knots_nodes: i64,
 }
 
+enum ScanStatus {
+    Success,
+    NodeFailure(anyhow::Error),
+    ProxyFailure(anyhow::Error),
+}
+
+struct ScanResult {
+    address: String,
+    status: ScanStatus,
+    attempt: db::ConnectionAttempt,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
@@ -45,6 +70,17 @@ async fn main() -> anyhow::Result<()> {
     let database_url =
         env::var("DATABASE_URL").expect("La variable de entorno DATABASE_URL debe estar definida");
 
+    let config = Arc::new(config::Config::from_env()?);
+    tracing::info!(
+        "Redes habilitadas: {}",
+        config
+            .networks
+            .iter()
+            .map(|n| common::network_name(*n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
     let (shutdown_tx, _) = broadcast::channel(1);
 
     let pool = PgPoolOptions::new()
@@ -52,52 +88,87 @@ async fn main() -> anyhow::Result<()> {
         .connect(&database_url)
         .await?;
 
-    let semaphore = Arc::new(Semaphore::new(100));
-
     let db = Arc::new(crate::db::Database(pool));
 
     tracing::info!("Ejecutando migraciones de la base de datos...");
     sqlx::migrate!().run(&db.0).await?;
     tracing::info!("Migraciones completadas.");
 
-    match db.get_total_nodes_count().await {
-        Ok(count) => {
-            if count == 0 {
-                tracing::info!("Base de datos vacía. Añadiendo nodos semilla...");
-                let initial_nodes = crate::common::first_nodes();
-                tracing::info!("Insertando {} nodos semilla...", initial_nodes.len());
-
-                for node in initial_nodes {
-                    let addr_type = if node.is_ipv4() { "ipv4" } else { "ipv6" };
-                    let address = node.ip().to_string();
-                    let port = node.port();
-
-                    if let Err(e) = db.upsert_addrv2_node(addr_type, &address, port, "").await {
-                        tracing::warn!("Fallo al insertar nodo semilla {}: {}", address, e);
+    for &network in &config.networks {
+        let network_str = common::network_name(network);
+        match db.get_total_nodes_count(network).await {
+            Ok(count) => {
+                if count == 0 {
+                    tracing::info!(
+                        "Base de datos vacía para {}. Añadiendo nodos semilla...",
+                        network_str
+                    );
+                    let initial_nodes =
+                        crate::common::first_nodes(network, config.seeds_for(network));
+                    tracing::info!(
+                        "Insertando {} nodos semilla ({})...",
+                        initial_nodes.len(),
+                        network_str
+                    );
+
+                    for node in initial_nodes {
+                        let address = NetAddress::from_ip(node.ip());
+
+                        if let Err(e) = db
+                            .upsert_addrv2_node(network, &address, node.port(), None, None)
+                            .await
+                        {
+                            tracing::warn!("Fallo al insertar nodo semilla {}: {}", address, e);
+                        }
                     }
+                    tracing::info!("Nodos semilla insertados ({}).", network_str);
+                } else {
+                    tracing::info!(
+                        "La base de datos ya existe y contiene {} nodos de {}.",
+                        count,
+                        network_str
+                    );
                 }
-                tracing::info!("Nodos semilla insertados.");
-            } else {
-                tracing::info!("La base de datos ya existe y contiene {} nodos.", count);
             }
-        }
-        Err(e) => {
-            anyhow::bail!("Fallo crítico al contar nodos en la BBDD: {}", e);
+            Err(e) => {
+                anyhow::bail!("Fallo crítico al contar nodos en la BBDD: {}", e);
+            }
         }
     }
 
     let app_state = db.clone();
+    let proxy_monitor = Arc::new(health::ProxyMonitor::new(&config));
+    let vantage_monitor = Arc::new(health::VantageMonitor::new(&config));
+    let queue_monitor = Arc::new(crawler::QueueMonitor::new(&config));
 
     let app = Router::new()
         .route("/api/stats", get(get_stats))
         .route("/api/nodes", get(get_recent_nodes_api))
         .route("/api/node/{address}", get(find_node_api))
+        .route("/api/node/{address}/attempts", get(get_node_attempts_api))
         .route("/api/software_stats", get(get_software_stats))
         .route("/api/incoming_stats", get(get_incoming_stats_api))
         .route("/api/stats/history", get(get_historical_stats))
+        .route("/api/stats/harvest", get(get_harvest_stats_api))
+        .route("/api/stats/capabilities", get(get_capability_stats_api))
+        .route("/api/stats/services", get(get_service_stats_api))
+        .route("/api/stats/transport", get(get_transport_stats_api))
+        .route("/api/stats/chain_tips", get(get_chain_tip_stats_api))
+        .route("/api/stats/archive", get(get_archive_stats_api))
+        .route("/api/stats/latency", get(get_latency_stats_api))
+        .route("/api/stats/cfilters", get(get_filter_stats_api))
         .route("/api/nodes/search", get(search_nodes_api))
+        .route("/api/retired_nodes", get(get_retired_nodes_api))
+        .route("/api/stats/churn", get(get_churn_stats_api))
+        .route("/api/proxy_health", get(get_proxy_health_api))
+        .route("/api/vantage_health", get(get_vantage_health_api))
+        .route("/api/crawler/queues", get(get_crawler_queues_api))
         .layer(CorsLayer::permissive())
         .layer(axum::Extension(app_state))
+        .layer(axum::Extension(config.clone()))
+        .layer(axum::Extension(proxy_monitor.clone()))
+        .layer(axum::Extension(vantage_monitor.clone()))
+        .layer(axum::Extension(queue_monitor.clone()))
         .fallback_service(ServeDir::new("public"));
 
     let addr = std::net::SocketAddr::from(([0, 0, 0, 0], 3000));
@@ -110,40 +181,60 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    let db_clone_listener = db.clone();
-    let shutdown_rx_listener = shutdown_tx.subscribe();
-    tokio::spawn(async move {
-        run_listener_task(
-            crate::db::Database(db_clone_listener.0.clone()),
-            shutdown_rx_listener,
-        )
-        .await;
-    });
-
-    let db_clone_crawler = db.clone();
-    let shutdown_rx_crawler = shutdown_tx.subscribe();
-    let semaphore_clone = semaphore.clone();
-    tokio::spawn(async move {
-        run_crawler_task(
-            crate::db::Database(db_clone_crawler.0.clone()),
-            semaphore_clone,
-            shutdown_rx_crawler,
-        )
-        .await;
-    });
+    for &network in &config.networks {
+        let db_clone_listener = db.clone();
+        let shutdown_rx_listener = shutdown_tx.subscribe();
+        tokio::spawn(async move {
+            run_listener_task(
+                crate::db::Database(db_clone_listener.0.clone()),
+                network,
+                shutdown_rx_listener,
+            )
+            .await;
+        });
+
+        let db_clone_crawler = db.clone();
+        let shutdown_rx_crawler = shutdown_tx.subscribe();
+        let queue_monitor_clone = queue_monitor.clone();
+        let config_clone = config.clone();
+        let proxy_monitor_clone = proxy_monitor.clone();
+        let vantage_monitor_clone = vantage_monitor.clone();
+        tokio::spawn(async move {
+            run_crawler_task(
+                crate::db::Database(db_clone_crawler.0.clone()),
+                network,
+                config_clone,
+                proxy_monitor_clone,
+                vantage_monitor_clone,
+                queue_monitor_clone,
+                shutdown_rx_crawler,
+            )
+            .await;
+        });
+    }
 
     let geo_ip_reader = common::GeoIpReader::new()?;
     let asn_reader = common::GeoIpAsnReader::new()?;
 
     let sched = JobScheduler::new().await?;
     let db_clone_snapshot = db.clone();
+    let snapshot_networks = config.networks.clone();
 
     sched
         .add(Job::new_async("0 0 * * * *", move |_uuid, _l| {
             let db = db_clone_snapshot.clone();
+            let networks = snapshot_networks.clone();
             Box::pin(async move {
-                if let Err(e) = take_hourly_snapshot(crate::db::Database(db.0.clone())).await {
-                    tracing::error!("Fallo al tomar snapshot horario: {}", e);
+                for network in networks {
+                    if let Err(e) =
+                        take_hourly_snapshot(crate::db::Database(db.0.clone()), network).await
+                    {
+                        tracing::error!(
+                            "Fallo al tomar snapshot horario ({}): {}",
+                            common::network_name(network),
+                            e
+                        );
+                    }
                 }
             })
         })?)
@@ -151,7 +242,16 @@ async fn main() -> anyhow::Result<()> {
 
     sched.start().await?;
 
-    tokio::spawn(run_db_cleanup_task((*db).clone(), shutdown_tx.subscribe()));
+    tokio::spawn(run_db_cleanup_task(
+        (*db).clone(),
+        config.clone(),
+        shutdown_tx.subscribe(),
+    ));
+    tokio::spawn(run_uptime_task((*db).clone(), shutdown_tx.subscribe()));
+    tokio::spawn(run_proxy_health_task(
+        proxy_monitor.clone(),
+        shutdown_tx.subscribe(),
+    ));
     tokio::spawn(run_ip_enrichment_task(
         (*db).clone(),
         geo_ip_reader.clone(),
@@ -175,14 +275,41 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn run_db_cleanup_task(db: db::Database, mut shutdown_rx: broadcast::Receiver<()>) {
+async fn run_db_cleanup_task(
+    db: db::Database,
+    config: Arc<config::Config>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
     let mut interval = tokio::time::interval(Duration::from_secs(3600));
     loop {
         tokio::select! {
             _ = interval.tick() => {
                 tracing::info!("[Mantenimiento] Ejecutando limpieza de la base de datos...");
-                if let Err(e) = db.clean_db().await {
-                    tracing::error!("[Mantenimiento] Fallo al limpiar la base de datos: {}", e);
+                for &network in &config.networks {
+                    match db.retire_nodes(network, config.retention_for(network)).await {
+                        Ok((unseen, unreachable)) => tracing::info!(
+                            "[Mantenimiento] Nodos retirados de {}: {} sin anunciar, {} inalcanzables.",
+                            common::network_name(network),
+                            unseen,
+                            unreachable
+                        ),
+                        Err(e) => tracing::error!("[Mantenimiento] Fallo al retirar nodos: {}", e),
+                    }
+                }
+                if let Some(keep) = config.retired_keep {
+                    match db.purge_retired_nodes(keep).await {
+                        Ok(purged) => tracing::info!("[Mantenimiento] Se eliminaron {} nodos retirados antiguos.", purged),
+                        Err(e) => tracing::error!("[Mantenimiento] Fallo al purgar los nodos retirados: {}", e),
+                    }
+                }
+                if let Err(e) = db.clean_connection_attempts().await {
+                    tracing::error!("[Mantenimiento] Fallo al limpiar los intentos de conexión: {}", e);
+                }
+                if let Err(e) = db.clean_announcements().await {
+                    tracing::error!("[Mantenimiento] Fallo al limpiar los anuncios: {}", e);
+                }
+                if let Err(e) = db.clean_harvest_rounds().await {
+                    tracing::error!("[Mantenimiento] Fallo al limpiar las rondas de recolección: {}", e);
                 }
             }
             _ = shutdown_rx.recv() => {
@@ -193,6 +320,46 @@ async fn run_db_cleanup_task(db: db::Database, mut shutdown_rx: broadcast::Recei
     }
 }
 
+async fn run_uptime_task(db: db::Database, mut shutdown_rx: broadcast::Receiver<()>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(600));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match db.refresh_uptime_stats().await {
+                    Ok(updated) => {
+                        tracing::info!("[Uptime] Métricas de disponibilidad recalculadas para {} nodos.", updated);
+                    }
+                    Err(e) => {
+                        tracing::error!("[Uptime] Fallo al recalcular las métricas de disponibilidad: {}", e);
+                    }
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                tracing::info!("[Uptime] Tarea de métricas de disponibilidad terminando...");
+                break;
+            }
+        }
+    }
+}
+
+async fn run_proxy_health_task(
+    monitor: Arc<health::ProxyMonitor>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                monitor.probe_all().await;
+            }
+            _ = shutdown_rx.recv() => {
+                tracing::info!("[Proxy] Tarea de monitorización de proxies terminando...");
+                break;
+            }
+        }
+    }
+}
+
 async fn run_ip_enrichment_task(
     db: db::Database,
     city_reader: common::GeoIpReader,
@@ -355,23 +522,44 @@ async fn download_and_update_maxmind_db(
     Ok(())
 }
 
+#[derive(Deserialize)]
+struct NetworkParams {
+    network: Option<String>,
+}
+
+fn resolve_network(config: &config::Config, params: &NetworkParams) -> Result<Network, StatusCode> {
+    match params.network.as_deref() {
+        None => Ok(config.default_network()),
+        Some(name) => match common::network_from_name(name) {
+            Some(network) if config.networks.contains(&network) => Ok(network),
+            _ => Err(StatusCode::BAD_REQUEST),
+        },
+    }
+}
+
 async fn get_stats(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
-) -> impl axum::response::IntoResponse {
-    let (total, incoming, archive, ipv4, ipv6, tor, core30, knots) = tokio::join!(
-        db.get_total_nodes_count(),
-        db.get_incoming_nodes_count(),
-        db.get_archive_nodes_count(),
-        db.get_ipv4_nodes_count(),
-        db.get_ipv6_nodes_count(),
-        db.get_tor_nodes_count(),
-        db.get_core30_nodes_count(),
-        db.get_knots_nodes_count()
+) -> Result<Json<Stats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let (total, incoming, inbound_only, archive, ipv4, ipv6, tor, core30, knots) = tokio::join!(
+        db.get_total_nodes_count(network),
+        db.get_incoming_nodes_count(network),
+        db.get_inbound_only_nodes_count(network),
+        db.get_archive_nodes_count(network),
+        db.get_ipv4_nodes_count(network),
+        db.get_ipv6_nodes_count(network),
+        db.get_tor_nodes_count(network),
+        db.get_core30_nodes_count(network),
+        db.get_knots_nodes_count(network)
     );
 
     let stats = Stats {
+        network: common::network_name(network).to_string(),
         total_nodes: total.unwrap_or(0),
         incoming_nodes: incoming.unwrap_or(0),
+        inbound_only_nodes: inbound_only.unwrap_or(0),
         archive_nodes: archive.unwrap_or(0),
         ipv4_nodes: ipv4.unwrap_or(0),
         ipv6_nodes: ipv6.unwrap_or(0),
@@ -380,47 +568,274 @@ async fn get_stats(
         knots_nodes: knots.unwrap_or(0),
     };
 
-    Json(stats)
+    Ok(Json(stats))
+}
+
+async fn get_capability_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::CapabilityStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_capability_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de capacidades: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_service_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::ServiceStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_service_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de servicios: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_archive_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::ArchiveStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_archive_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de nodos de archivo: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_filter_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::FilterStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_filter_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de filtros compactos: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_chain_tip_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::ChainTipStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_chain_tip_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de puntas de cadena: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn get_transport_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<db::TransportStats>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+
+    match db.get_transport_stats(network).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de transporte v2: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct NodeParams {
+    port: Option<u16>,
+}
+
+// Acepta "host", "host:puerto" o "[ipv6]:puerto"; `?port=` tiene prioridad.
+// La dirección se normaliza con NetAddress (400 si no es válida). Si tiene
+// varios puertos y no se indica ninguno, responde 409 con los candidatos.
+async fn resolve_node(
+    db: &db::Database,
+    network: Network,
+    address: &str,
+    port: Option<u16>,
+) -> Result<db::NodeInfo, Response> {
+    let (host, url_port) = common::split_host_port(address);
+    let port = port.or(url_port);
+    let host = NetAddress::parse(&host)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+    let mut nodes = db
+        .find_nodes_by_address(network, &host.to_string(), port)
+        .await
+        .map_err(|e| {
+            tracing::error!("Fallo al buscar el nodo {}: {}", address, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+
+    match nodes.len() {
+        0 => Err(StatusCode::NOT_FOUND.into_response()),
+        1 => Ok(nodes.remove(0)),
+        _ => Err((StatusCode::CONFLICT, Json(nodes)).into_response()),
+    }
 }
 
 async fn find_node_api(
+    Query(network_params): Query<NetworkParams>,
+    Query(node_params): Query<NodeParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
     Path(address): Path<String>,
-) -> impl axum::response::IntoResponse {
+) -> Result<Json<db::NodeInfo>, Response> {
+    let network = resolve_network(&config, &network_params).map_err(|s| s.into_response())?;
     tracing::debug!(
         "Buscando nodo. IP recibida de la URL: '{}', longitud: {}",
         address,
         address.len()
     );
 
-    match db.find_node_by_address(&address).await {
-        Ok(Some(node)) => Ok(Json(node)),
-        Ok(None) => Err(axum::http::StatusCode::NOT_FOUND),
-        Err(_) => Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR),
+    let node = resolve_node(&db, network, &address, node_params.port).await?;
+    Ok(Json(node))
+}
+
+#[derive(Deserialize)]
+struct AttemptsParams {
+    limit: Option<i64>,
+}
+
+async fn get_node_attempts_api(
+    Query(network_params): Query<NetworkParams>,
+    Query(node_params): Query<NodeParams>,
+    Query(params): Query<AttemptsParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+    Path(address): Path<String>,
+) -> Result<Json<Vec<db::ConnectionAttemptRecord>>, Response> {
+    let network = resolve_network(&config, &network_params).map_err(|s| s.into_response())?;
+    let limit = params.limit.unwrap_or(100).clamp(1, 1000);
+    let node = resolve_node(&db, network, &address, node_params.port).await?;
+
+    match db.get_connection_attempts(node.id, limit).await {
+        Ok(attempts) => Ok(Json(attempts)),
+        Err(e) => {
+            tracing::error!(
+                "Fallo al obtener intentos de conexión de {}: {}",
+                address,
+                e
+            );
+            Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HarvestStatsParams {
+    days: Option<i32>,
+}
+
+async fn get_harvest_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    Query(params): Query<HarvestStatsParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<Vec<db::HarvestRoundStats>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let days = params.days.unwrap_or(7).clamp(1, 35);
+
+    match db.get_harvest_round_stats(network, days).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de recolección: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LatencyStatsParams {
+    group: Option<String>,
+    days: Option<i32>,
+}
+
+async fn get_latency_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    Query(params): Query<LatencyStatsParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<Vec<db::LatencyStats>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let group = match params.group.as_deref().unwrap_or("type") {
+        group @ ("type" | "country" | "asn") => group,
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+    let days = params.days.unwrap_or(7).clamp(1, 35);
+
+    match db.get_latency_stats(network, group, days).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de latencia: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
     }
 }
 
 async fn get_software_stats(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
 ) -> impl axum::response::IntoResponse {
-    match db.get_software_version_stats().await {
-        Ok(stats) => (axum::http::StatusCode::OK, Json(stats)),
+    let network = match resolve_network(&config, &network_params) {
+        Ok(network) => network,
+        Err(status) => return (status, Json(vec![])),
+    };
+    match db.get_software_version_stats(network).await {
+        Ok(stats) => (StatusCode::OK, Json(stats)),
         Err(e) => {
             tracing::error!("Fallo al obtener stats de software: {:?}", e);
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
         }
     }
 }
 
-async fn run_listener_task(db: db::Database, mut shutdown_rx: broadcast::Receiver<()>) {
-    let listener = match tokio::net::TcpListener::bind("0.0.0.0:8333").await {
+async fn run_listener_task(
+    db: db::Database,
+    network: Network,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let port = common::default_port(network);
+    let listener = match tokio::net::TcpListener::bind(("0.0.0.0", port)).await {
         Ok(l) => l,
         Err(e) => {
-            tracing::error!("[Listener] Fallo al iniciar listener en puerto 8333: {}. La escucha pasiva está desactivada.", e);
+            tracing::error!("[Listener] Fallo al iniciar listener en puerto {}: {}. La escucha pasiva está desactivada.", port, e);
             return;
         }
     };
-    tracing::info!("[Listener] Escuchando conexiones Bitcoin entrantes en el puerto 8333...");
+    tracing::info!(
+        "[Listener] Escuchando conexiones Bitcoin entrantes ({}) en el puerto {}...",
+        common::network_name(network),
+        port
+    );
 
     loop {
         tokio::select! {
@@ -429,7 +844,7 @@ async fn run_listener_task(db: db::Database, mut shutdown_rx: broadcast::Receive
                 let db_clone = db.clone();
 
                 tokio::spawn(async move {
-                    if let Err(e) = p2p::handle_connection(&db_clone, socket, addr).await {
+                    if let Err(e) = p2p::handle_connection(&db_clone, network, socket, addr).await {
                          tracing::warn!("[Listener] Conversación entrante con {} falló: {}", addr, e);
                     }
                 });
@@ -442,124 +857,645 @@ async fn run_listener_task(db: db::Database, mut shutdown_rx: broadcast::Receive
     }
 }
 
+// Lo que comparten todas las tareas de sondeo de una red.
+struct CrawlContext {
+    db: db::Database,
+    network: Network,
+    config: Arc<config::Config>,
+    proxy_monitor: Arc<health::ProxyMonitor>,
+    vantage_monitor: Arc<health::VantageMonitor>,
+    // Cadena de referencia para sondear la punta de cada nodo.
+    chain: Option<Arc<p2p::chain::ChainTracker>>,
+    archive: Option<Arc<p2p::archive::ArchiveProbe>>,
+    schedule: Arc<dyn crawler::schedule::SchedulePolicy>,
+}
+
+// Cada cola guarda hasta el doble de las sesiones que permite su presupuesto
+// y se rellena cuando baja de la mitad.
+const QUEUE_DEPTH_FACTOR: usize = 2;
+// Espera antes de volver a consultar la BBDD si no había nodos suficientes o
+// no hay conectividad.
+const REFILL_IDLE: Duration = Duration::from_secs(10);
+// Los resultados se procesan en ventanas: cuando se juntan tantos o cuando
+// vence el plazo, lo que ocurra antes. La ventana es también la muestra con la
+// que se evalúa el punto de observación.
+const RESULT_WINDOW: usize = 100;
+const RESULT_WINDOW_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Una cola por tipo de dirección, cada una con su presupuesto; los resultados
+// de todas llegan por un canal y se procesan por ventanas sin esperar al peer
+// más lento.
 async fn run_crawler_task(
     db: db::Database,
-    semaphore: Arc<Semaphore>,
+    network: Network,
+    config: Arc<config::Config>,
+    proxy_monitor: Arc<health::ProxyMonitor>,
+    vantage_monitor: Arc<health::VantageMonitor>,
+    queue_monitor: Arc<crawler::QueueMonitor>,
     mut shutdown_rx: broadcast::Receiver<()>,
 ) {
-    tracing::info!("[Crawler] Tarea de sondeo activo iniciada.");
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    tracing::info!(
+        "[Crawler] Tarea de sondeo activo iniciada ({}).",
+        common::network_name(network)
+    );
+    let chain = config
+        .chain_tip_probe
+        .then(|| Arc::new(p2p::chain::ChainTracker::new(network)));
+    let ctx = Arc::new(CrawlContext {
+        archive: config
+            .archive_probe_interval
+            .and(chain.clone())
+            .map(|chain| Arc::new(p2p::archive::ArchiveProbe::new(chain))),
+        chain,
+        schedule: crawler::schedule::from_config(&config.schedule),
+        db,
+        network,
+        config,
+        proxy_monitor,
+        vantage_monitor,
+    });
+
+    tracing::info!(
+        "[Crawler] Política de reprogramación ({}): {}",
+        common::network_name(network),
+        ctx.schedule.name()
+    );
+
+    let (results_tx, mut results_rx) = mpsc::channel(RESULT_WINDOW);
+    for lane in queue_monitor.lanes() {
+        let stats = queue_monitor.register(network, lane.clone());
+        tokio::spawn(run_queue(
+            ctx.clone(),
+            lane.clone(),
+            stats,
+            results_tx.clone(),
+            shutdown_rx.resubscribe(),
+        ));
+    }
+    drop(results_tx);
+
+    let mut window = Vec::with_capacity(RESULT_WINDOW);
+    let mut window_tick = tokio::time::interval(RESULT_WINDOW_TIMEOUT);
+    // La punta de la referencia se publica en BBDD con cada ventana si ha cambiado.
+    let mut published_tip = None;
 
     loop {
         tokio::select! {
-            _ = interval.tick() => {
-                match db.get_nodes_to_scan(100).await {
-                    Ok(nodes) if !nodes.is_empty() => {
-                        let num_nodes = nodes.len();
-                        tracing::info!("[Crawler] Lanzando análisis para {} nodos en paralelo.", num_nodes);
-
-                        let mut tasks = Vec::with_capacity(num_nodes);
-
-                        for node in nodes {
-                            let db_clone = db.clone();
-                            let sem_clone = semaphore.clone();
-
-                            let task_handle = tokio::spawn(async move {
-                                let _permit = sem_clone.acquire_owned().await.unwrap();
-
-                                let connection_result = match node {
-                                    NodeToScan::Ip(socket_addr) => {
-                                        let addr_str = socket_addr.to_string();
-                                        match p2p::converse(&db_clone, socket_addr).await {
-                                            Ok(_) => Ok(addr_str),
-                                            Err(e) => {
-                                                tracing::debug!("[Task] La conexión con {} falló: {}", addr_str, e);
-                                                Err((addr_str, e))
-                                            }
-                                        }
-                                    },
-                                    NodeToScan::Tor { address, port } => {
-                                        let full_address = format!("{}:{}", address, port);
-                                        match p2p::converse_tor(&db_clone, &address, port).await {
-                                            Ok(_) => Ok(full_address),
-                                            Err(e) => {
-                                                tracing::debug!("[Task] La conexión Tor con {} falló: {}", full_address, e);
-                                                Err((full_address, e))
-                                            }
-                                        }
-                                    }
-                                    NodeToScan::I2p { address, port } => {
-                                        let full_address = format!("{}:{}", address, port);
-                                        match p2p::converse_i2p(&db_clone, &address, port).await {
-                                            Ok(_) => Ok(full_address),
-                                            Err(e) => {
-                                                tracing::debug!("[Task] La conexión I2P con {} falló: {}", full_address, e);
-                                                Err((full_address, e))
-                                            }
-                                        }
-                                    }
-                                };
-
-                                connection_result
-                            });
-
-                            tasks.push(task_handle);
+            Some(scan) = results_rx.recv() => {
+                window.push(scan);
+                if window.len() >= RESULT_WINDOW {
+                    tokio::spawn(process_results(ctx.clone(), std::mem::take(&mut window)));
+                    window_tick.reset();
+                }
+            }
+            _ = window_tick.tick() => {
+                if !window.is_empty() {
+                    tokio::spawn(process_results(ctx.clone(), std::mem::take(&mut window)));
+                }
+                if let Some(chain) = &ctx.chain {
+                    let tip = chain.tip();
+                    if published_tip != Some(tip) {
+                        match ctx.db.update_chain_reference(network, tip.0, &tip.1.to_string()).await {
+                            Ok(()) => published_tip = Some(tip),
+                            Err(e) => tracing::error!("[Crawler DB] Fallo de BBDD (chain reference): {}", e),
                         }
+                    }
+                }
+            }
+            _ = shutdown_rx.recv() => {
+                tracing::info!("[Crawler] Señal de apagado recibida, terminando...");
+                break;
+            }
+        }
+    }
+}
+
+// Cola de un tipo de dirección: se rellena desde `claim_nodes_to_scan` según
+// se vacía y cada nodo se lanza en cuanto su presupuesto deja hueco.
+async fn run_queue(
+    ctx: Arc<CrawlContext>,
+    lane: Arc<crawler::Lane>,
+    stats: Arc<crawler::QueueStats>,
+    results: mpsc::Sender<ScanResult>,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
+    let capacity = lane.concurrency() * QUEUE_DEPTH_FACTOR;
+    let mut queue: VecDeque<db::ScanTarget> = VecDeque::with_capacity(capacity);
+    let mut overlays = common::OverlayInterfaces::detect();
+    let mut next_refill = tokio::time::Instant::now();
+    let mut workers = JoinSet::new();
+    // Nodo que sondea cada tarea en curso.
+    let mut in_flight: HashMap<tokio::task::Id, i64> = HashMap::new();
 
-                        tracing::debug!("[Crawler] Esperando que terminen {} tareas...", tasks.len());
-                        let results = join_all(tasks).await;
-                        tracing::info!("[Crawler] Lote de {} nodos completado.", num_nodes);
-
-                        for result in results {
-                            match result {
-                                Ok(Ok(_addr_str)) => {
-                                }
-                                Ok(Err((addr_str, _error))) => {
-                                    if let Err(db_err) = db.handle_failed_connection(&addr_str).await {
-                                        tracing::error!("[Crawler DB] Fallo de BBDD (failure) {}: {}", addr_str, db_err);
-                                    }
-                                }
-                                Err(join_err) => {
-                                    tracing::error!("[Crawler Task] Fallo al ejecutar la tarea de conexión: {}", join_err);
-                                }
-                            }
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_refill), if queue.len() < capacity / 2 => {
+                let wait = refill_queue(&ctx, &lane, &mut queue, &in_flight, &mut overlays, &stats).await;
+                next_refill = tokio::time::Instant::now() + wait;
+            }
+            permit = lane.acquire(), if !queue.is_empty() => {
+                let target = queue.pop_front().unwrap();
+                stats.record_start(queue.len());
+                let node_id = target.id;
+                let ctx = ctx.clone();
+                let lane = lane.clone();
+                let handle = workers.spawn(async move {
+                    let _permit = permit;
+                    lane.pace().await;
+                    scan_target(&ctx, &lane, overlays, target).await
+                });
+                in_flight.insert(handle.id(), node_id);
+            }
+            Some(joined) = workers.join_next_with_id() => {
+                match joined {
+                    Ok((id, scan)) => {
+                        in_flight.remove(&id);
+                        stats.record_finish(matches!(scan.status, ScanStatus::Success));
+                        if results.send(scan).await.is_err() {
+                            break;
                         }
                     }
-                    Ok(_) => {
-                        tracing::info!("[Crawler] No hay nodos disponibles. Esperando próximo ciclo.");
-                    }
-                    Err(e) => {
-                        tracing::error!("[Crawler] Fallo al obtener nodos de la BBDD: {}", e);
+                    Err(join_err) => {
+                        in_flight.remove(&join_err.id());
+                        stats.record_finish(false);
+                        tracing::error!("[Crawler Task] Fallo al ejecutar la tarea de conexión: {}", join_err);
                     }
                 }
             }
             _ = shutdown_rx.recv() => {
-                tracing::info!("[Crawler] Señal de apagado recibida, terminando...");
+                tracing::debug!("[Crawler] Cola {} cerrada con {} sesiones en curso.", lane.address_type.as_str(), workers.len());
                 break;
             }
         }
     }
 }
 
-async fn take_hourly_snapshot(db: db::Database) -> Result<()> {
+// Los tipos que dependen de un proxy o de una interfaz local sólo se sondean
+// cuando están disponibles.
+fn lane_available(
+    ctx: &CrawlContext,
+    address_type: AddressType,
+    overlays: &common::OverlayInterfaces,
+) -> bool {
+    match address_type {
+        AddressType::OnionV2 | AddressType::OnionV3 => ctx.proxy_monitor.tor.is_healthy(),
+        AddressType::I2p => ctx.proxy_monitor.i2p.is_healthy(),
+        AddressType::Cjdns => overlays.cjdns.is_some(),
+        AddressType::Yggdrasil => overlays.yggdrasil.is_some(),
+        AddressType::Ipv4 | AddressType::Ipv6 => true,
+    }
+}
+
+// Pide a la BBDD los nodos que faltan para llenar la cola y devuelve cuánto
+// esperar antes del siguiente relleno: nada si la BBDD tenía de sobra.
+async fn refill_queue(
+    ctx: &CrawlContext,
+    lane: &crawler::Lane,
+    queue: &mut VecDeque<db::ScanTarget>,
+    in_flight: &HashMap<tokio::task::Id, i64>,
+    overlays: &mut common::OverlayInterfaces,
+    stats: &crawler::QueueStats,
+) -> Duration {
+    let address_type = lane.address_type;
+    *overlays = common::OverlayInterfaces::detect();
+    let available = lane_available(ctx, address_type, overlays);
+    stats.set_enabled(available);
+    if !available {
+        return REFILL_IDLE;
+    }
+    if !ctx.vantage_monitor.ready_for_batch().await {
+        tracing::warn!(
+            "[Crawler] Sin conectividad local. Se pausa la cola {}.",
+            address_type.as_str()
+        );
+        return REFILL_IDLE;
+    }
+
+    let wanted = lane.concurrency() * QUEUE_DEPTH_FACTOR - queue.len();
+    let claimed = ctx
+        .db
+        .claim_nodes_to_scan(
+            ctx.network,
+            &[address_type],
+            wanted as u32,
+            ctx.config.crawl.lease,
+        )
+        .await;
+    let wait = match claimed {
+        Ok(nodes) => {
+            let claimed = nodes.len();
+            // Una sesión más larga que la reserva puede hacer que su nodo
+            // vuelva a salir; no se lanza dos veces.
+            let busy: HashSet<i64> = in_flight
+                .values()
+                .copied()
+                .chain(queue.iter().map(|queued| queued.id))
+                .collect();
+            queue.extend(
+                nodes
+                    .into_iter()
+                    .filter(|target| !busy.contains(&target.id)),
+            );
+            if claimed > 0 {
+                tracing::debug!(
+                    "[Crawler] Cola {} rellenada con {} nodos ({} en cola, {} en curso).",
+                    address_type.as_str(),
+                    claimed,
+                    queue.len(),
+                    in_flight.len()
+                );
+            }
+            if claimed < wanted {
+                REFILL_IDLE
+            } else {
+                Duration::ZERO
+            }
+        }
+        Err(e) => {
+            tracing::error!("[Crawler] Fallo al obtener nodos de la BBDD: {}", e);
+            REFILL_IDLE
+        }
+    };
+    stats.set_queued(queue.len());
+    wait
+}
+
+async fn scan_target(
+    ctx: &CrawlContext,
+    lane: &crawler::Lane,
+    overlays: common::OverlayInterfaces,
+    target: db::ScanTarget,
+) -> ScanResult {
+    let config = &ctx.config;
+    let attempted_at = chrono::Utc::now();
+    let started = std::time::Instant::now();
+    let harvest = config.harvest.enabled.then(|| config.harvest.clone());
+    let mut session = p2p::Session::new(harvest);
+    session.try_v2 = target.services.is_some_and(|s| s.has(ServiceFlags::P2P_V2));
+    session.chain = ctx.chain.clone();
+    session.archive = ctx
+        .archive
+        .clone()
+        .filter(|_| probe_due(config.archive_probe_interval, target.archive_checked));
+    session.filters = probe_due(config.filter_probe_interval, target.cfilter_checked);
+    session.filter_consensus_min = config.filter_consensus_min;
+
+    let node_id = target.id;
+    let (address, proxy, result) = converse_target(
+        &ctx.db,
+        ctx.network,
+        config,
+        &ctx.proxy_monitor,
+        &overlays,
+        &target,
+        &mut session,
+    )
+    .await;
+
+    let outcome = p2p::classify_attempt(&result, session.reached);
+    let status = match result {
+        Ok(_) => {
+            if let Some(proxy) = proxy {
+                proxy.record_success();
+            }
+            ScanStatus::Success
+        }
+        Err(e) => {
+            tracing::debug!(
+                "[Task] La conexión con {} falló ({}): {}",
+                address,
+                outcome.as_str(),
+                e
+            );
+            match proxy {
+                Some(proxy) if proxy.record_failure(&e) => ScanStatus::ProxyFailure(e),
+                _ => ScanStatus::NodeFailure(e),
+            }
+        }
+    };
+    let error = match &status {
+        ScanStatus::Success => None,
+        ScanStatus::NodeFailure(e) | ScanStatus::ProxyFailure(e) => Some(format!("{:#}", e)),
+    };
+    let proxy_failure = matches!(status, ScanStatus::ProxyFailure(_));
+
+    let duration_ms = started.elapsed().as_millis() as i32;
+
+    if matches!(status, ScanStatus::Success) && session.harvest.is_some() {
+        harvest_rounds(ctx, lane, &overlays, &target, &session).await;
+    }
+
+    ScanResult {
+        address,
+        status,
+        attempt: db::ConnectionAttempt {
+            node_id,
+            attempted_at,
+            duration_ms,
+            outcome: outcome.as_str(),
+            error,
+            vantage_failure: false,
+            proxy_failure,
+            version: session.handshake,
+            v2_handshake: session.v2_handshake,
+            timings: session.timings,
+        },
+    }
+}
+
+// Evalúa el punto de observación con la ventana de resultados y guarda cada
+// intento. Los fallos de una ventana atribuida a una caída local no penalizan
+// a los nodos.
+async fn process_results(ctx: Arc<CrawlContext>, results: Vec<ScanResult>) {
+    let successes = results
+        .iter()
+        .filter(|r| matches!(r.status, ScanStatus::Success))
+        .count();
+    let failures = results
+        .iter()
+        .filter(|r| matches!(r.status, ScanStatus::NodeFailure(_)))
+        .count();
+    let vantage_failure = ctx.vantage_monitor.assess_batch(successes, failures).await;
+    tracing::info!(
+        "[Crawler] {} sesiones terminadas: {} con éxito, {} fallidas.",
+        results.len(),
+        successes,
+        failures
+    );
+
+    for mut scan in results {
+        let addr_str = scan.address.clone();
+        let node_id = scan.attempt.node_id;
+
+        match &scan.status {
+            ScanStatus::Success => {
+                // Sólo una sesión completa cuenta como éxito: un nodo que
+                // envía 'version' y luego se queda callado sigue acumulando
+                // fallos.
+                if let Err(db_err) = ctx.db.handle_successful_connection(node_id).await {
+                    tracing::error!(
+                        "[Crawler DB] Fallo de BBDD (success) {}: {}",
+                        addr_str,
+                        db_err
+                    );
+                }
+                if let Err(db_err) = ctx
+                    .db
+                    .schedule_next_attempt(node_id, ctx.schedule.as_ref())
+                    .await
+                {
+                    tracing::error!(
+                        "[Crawler DB] Fallo de BBDD (schedule) {}: {}",
+                        addr_str,
+                        db_err
+                    );
+                }
+            }
+            ScanStatus::NodeFailure(_) if vantage_failure => {
+                scan.attempt.vantage_failure = true;
+                if let Err(db_err) = ctx.db.handle_vantage_failure(node_id).await {
+                    tracing::error!(
+                        "[Crawler DB] Fallo de BBDD (vantage) {}: {}",
+                        addr_str,
+                        db_err
+                    );
+                }
+            }
+            ScanStatus::NodeFailure(_) => {
+                if let Err(db_err) = ctx
+                    .db
+                    .handle_failed_connection(node_id, ctx.schedule.as_ref())
+                    .await
+                {
+                    tracing::error!(
+                        "[Crawler DB] Fallo de BBDD (failure) {}: {}",
+                        addr_str,
+                        db_err
+                    );
+                }
+            }
+            ScanStatus::ProxyFailure(error) => {
+                tracing::debug!(
+                    "[Crawler] Fallo de {} atribuido al proxy, no se penaliza al nodo: {}",
+                    addr_str,
+                    error
+                );
+            }
+        }
+
+        if let Err(db_err) = ctx.db.record_connection_attempt(&scan.attempt).await {
+            tracing::error!(
+                "[Crawler DB] Fallo de BBDD (attempt) {}: {}",
+                addr_str,
+                db_err
+            );
+        }
+    }
+}
+
+// Las verificaciones periódicas (archivo, filtros) se repiten por nodo cada
+// `interval`; `checked` es la última.
+fn probe_due(interval: Option<Duration>, checked: Option<chrono::DateTime<chrono::Utc>>) -> bool {
+    let interval = match interval {
+        Some(interval) => interval,
+        None => return false,
+    };
+    checked.is_none_or(|checked| {
+        chrono::Utc::now() - checked > chrono::Duration::from_std(interval).unwrap_or_default()
+    })
+}
+
+async fn converse_target<'a>(
+    db: &db::Database,
+    network: Network,
+    config: &config::Config,
+    proxy_monitor: &'a health::ProxyMonitor,
+    overlays: &common::OverlayInterfaces,
+    target: &db::ScanTarget,
+    session: &mut p2p::Session,
+) -> (String, Option<&'a health::ProxyHealth>, Result<()>) {
+    let node_id = target.id;
+    let port = target.port;
+    match target.address {
+        NetAddress::Ipv4(_) | NetAddress::Ipv6(_) => {
+            let socket_addr = std::net::SocketAddr::new(target.address.ip().unwrap(), port);
+            let result = p2p::converse(db, network, node_id, socket_addr, session).await;
+            (socket_addr.to_string(), None, result)
+        }
+        NetAddress::TorV2(_) | NetAddress::TorV3(_) => {
+            let address = target.address.to_string();
+            let result = p2p::converse_tor(
+                db,
+                network,
+                &config.tor_proxy,
+                node_id,
+                &address,
+                port,
+                session,
+            )
+            .await;
+            (
+                format!("{}:{}", address, port),
+                Some(&proxy_monitor.tor),
+                result,
+            )
+        }
+        NetAddress::I2p(_) => {
+            let address = target.address.to_string();
+            let result = p2p::converse_i2p(
+                db,
+                network,
+                &config.i2p_proxy,
+                node_id,
+                &address,
+                port,
+                session,
+            )
+            .await;
+            (
+                format!("{}:{}", address, port),
+                Some(&proxy_monitor.i2p),
+                result,
+            )
+        }
+        NetAddress::Cjdns(ip) => {
+            let socket_addr = std::net::SocketAddr::new(IpAddr::V6(ip), port);
+            let result =
+                converse_overlay_node(db, network, node_id, socket_addr, overlays.cjdns, session)
+                    .await;
+            (socket_addr.to_string(), None, result)
+        }
+        NetAddress::Yggdrasil(ip) => {
+            let socket_addr = std::net::SocketAddr::new(IpAddr::V6(ip), port);
+            let result = converse_overlay_node(
+                db,
+                network,
+                node_id,
+                socket_addr,
+                overlays.yggdrasil,
+                session,
+            )
+            .await;
+            (socket_addr.to_string(), None, result)
+        }
+    }
+}
+
+async fn converse_overlay_node(
+    db: &db::Database,
+    network: Network,
+    node_id: i64,
+    socket_addr: std::net::SocketAddr,
+    local_addr: Option<std::net::Ipv6Addr>,
+    session: &mut p2p::Session,
+) -> Result<()> {
+    let local_addr = local_addr
+        .ok_or_else(|| anyhow::anyhow!("Interfaz overlay no disponible para {}", socket_addr))?;
+
+    p2p::converse_overlay(db, network, node_id, socket_addr, local_addr, session).await
+}
+
+// La primera ronda es la conversación normal del crawler; las siguientes
+// reconectan para muestrear otras partes del addrman del nodo. De cada ronda se
+// guarda cuántas direcciones no habían salido en las anteriores. Las rondas
+// extra respetan el ritmo de la cola y no tocan la contabilidad del nodo, que
+// ya se hizo con la primera.
+async fn harvest_rounds(
+    ctx: &CrawlContext,
+    lane: &crawler::Lane,
+    overlays: &common::OverlayInterfaces,
+    target: &db::ScanTarget,
+    first: &p2p::Session,
+) {
+    let harvest = match &first.harvest {
+        Some(harvest) => harvest,
+        None => return,
+    };
+
+    let mut seen: HashSet<(NetAddress, u16)> = HashSet::new();
+    let mut addr_messages = first.addr_messages;
+    let mut addresses = first.addresses.clone();
+    let mut round = 1;
+
+    loop {
+        let new_addresses = addresses.iter().filter(|a| !seen.contains(*a)).count();
+        let record = db::HarvestRound {
+            node_id: target.id,
+            harvested_at: chrono::Utc::now(),
+            round: round as i32,
+            addr_messages: addr_messages as i32,
+            addresses: addresses.len() as i32,
+            new_addresses: new_addresses as i32,
+        };
+        if let Err(e) = ctx.db.record_harvest_round(&record).await {
+            tracing::error!(
+                "[Crawler DB] Fallo de BBDD (harvest) nodo {}: {}",
+                target.id,
+                e
+            );
+        }
+        seen.extend(addresses);
+
+        if round >= harvest.rounds {
+            break;
+        }
+        round += 1;
+
+        let mut session = p2p::Session::new(Some(harvest.clone()));
+        session.reconnect = true;
+        session.try_v2 = first.v2_handshake == Some(true);
+        lane.pace().await;
+        let (address, _, result) = converse_target(
+            &ctx.db,
+            ctx.network,
+            &ctx.config,
+            &ctx.proxy_monitor,
+            overlays,
+            target,
+            &mut session,
+        )
+        .await;
+        if let Err(e) = result {
+            tracing::debug!("[Harvest] Ronda {} con {} fallida: {}", round, address, e);
+            break;
+        }
+        addr_messages = session.addr_messages;
+        addresses = session.addresses;
+    }
+
+    tracing::debug!(
+        "[Harvest] Nodo {}: {} rondas, {} direcciones únicas",
+        target.id,
+        round,
+        seen.len()
+    );
+}
+
+async fn take_hourly_snapshot(db: db::Database, network: Network) -> Result<()> {
     let now = chrono::Utc::now();
 
-    let (total_res, incoming_res, archive_res, ipv4_res, ipv6_res, onion_res, top_software_res): (
-        _,
-        _,
-        _,
-        _,
-        _,
-        _,
-        Result<Vec<db::SoftwareCount>, _>,
-    ) = tokio::join!(
-        db.get_total_nodes_count(),
-        db.get_incoming_nodes_count(),
-        db.get_archive_nodes_count(),
-        db.get_ipv4_nodes_count(),
-        db.get_ipv6_nodes_count(),
-        db.get_tor_nodes_count(),
-        db.get_top_software_stats()
+    let (
+        total_res,
+        incoming_res,
+        archive_res,
+        ipv4_res,
+        ipv6_res,
+        onion_res,
+        top_software_res,
+        capabilities_res,
+        services_res,
+        transport_res,
+    ): (_, _, _, _, _, _, Result<Vec<db::SoftwareCount>, _>, _, _, _) = tokio::join!(
+        db.get_total_nodes_count(network),
+        db.get_incoming_nodes_count(network),
+        db.get_archive_nodes_count(network),
+        db.get_ipv4_nodes_count(network),
+        db.get_ipv6_nodes_count(network),
+        db.get_tor_nodes_count(network),
+        db.get_top_software_stats(network),
+        db.get_capability_stats(network),
+        db.get_service_stats(network),
+        db.get_transport_stats(network)
     );
 
     let top_software_json = match top_software_res {
@@ -573,30 +1509,68 @@ async fn take_hourly_snapshot(db: db::Database) -> Result<()> {
         }
     };
 
+    let capabilities_json = match capabilities_res {
+        Ok(stats) => json!(stats),
+        Err(e) => {
+            tracing::error!(
+                "[Snapshot] Fallo al obtener estadísticas de capacidades: {:?}. Usando null.",
+                e
+            );
+            serde_json::Value::Null
+        }
+    };
+
+    let services_json = match services_res {
+        Ok(stats) => json!(stats),
+        Err(e) => {
+            tracing::error!(
+                "[Snapshot] Fallo al obtener estadísticas de servicios: {:?}. Usando null.",
+                e
+            );
+            serde_json::Value::Null
+        }
+    };
+
+    let transport_json = match transport_res {
+        Ok(stats) => json!(stats),
+        Err(e) => {
+            tracing::error!(
+                "[Snapshot] Fallo al obtener estadísticas de transporte v2: {:?}. Usando null.",
+                e
+            );
+            serde_json::Value::Null
+        }
+    };
+
     sqlx::query!(
         r#"
         INSERT INTO hourly_stats (
-            snapshot_time, total_nodes, incoming_nodes, archive_nodes,
+            snapshot_time, network, total_nodes, incoming_nodes, archive_nodes,
             ipv4_nodes, ipv6_nodes, onion_nodes,
-            top_software 
+            top_software, capabilities, service_bits, transport
         )
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8) 
-        ON CONFLICT (snapshot_time) DO NOTHING
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) 
+        ON CONFLICT (snapshot_time, network) DO NOTHING
         "#,
         now,
+        common::network_name(network),
         total_res.unwrap_or(0),
         incoming_res.unwrap_or(0),
         archive_res.unwrap_or(0),
         ipv4_res.unwrap_or(0),
         ipv6_res.unwrap_or(0),
         onion_res.unwrap_or(0),
-        top_software_json
+        top_software_json,
+        capabilities_json,
+        services_json,
+        transport_json
     )
     .execute(&db.0)
     .await?;
 
     tracing::info!(
-        "[Snapshot] Instantánea horaria guardada correctamente (incluyendo top 10 software)."
+        "[Snapshot] Instantánea horaria de {} guardada correctamente (incluyendo top 10 software).",
+        common::network_name(network)
     );
     Ok(())
 }
@@ -605,18 +1579,102 @@ async fn take_hourly_snapshot(db: db::Database) -> Result<()> {
 struct PaginationParams {
     page: Option<i64>,
     limit: Option<i64>,
+    sort: Option<String>,
+    order: Option<String>,
 }
 
 async fn get_recent_nodes_api(
     Query(params): Query<PaginationParams>,
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
-) -> Json<Vec<db::NodeInfo>> {
+) -> Result<Json<Vec<db::NodeInfo>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
     let limit = params.limit.unwrap_or(20).clamp(1, 100);
     let page = params.page.unwrap_or(1).max(1);
     let offset = (page - 1) * limit;
+    let sort = match params.sort.as_deref() {
+        Some(name) => db::NodeSort::from_name(name).ok_or(StatusCode::BAD_REQUEST)?,
+        None => db::NodeSort::Scanned,
+    };
+    let ascending = match params.order.as_deref() {
+        Some("asc") => true,
+        Some("desc") | None => false,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
 
-    let nodes = db.get_recent_nodes(limit, offset).await.unwrap_or_default();
-    Json(nodes)
+    let nodes = db
+        .get_recent_nodes(network, sort, ascending, limit, offset)
+        .await
+        .unwrap_or_default();
+    Ok(Json(nodes))
+}
+
+#[derive(Deserialize)]
+struct RetiredParams {
+    page: Option<i64>,
+    limit: Option<i64>,
+    #[serde(rename = "type")]
+    address_type: Option<String>,
+    reason: Option<String>,
+}
+
+async fn get_retired_nodes_api(
+    Query(params): Query<RetiredParams>,
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<Vec<db::RetiredNode>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    let page = params.page.unwrap_or(1).max(1);
+    let offset = (page - 1) * limit;
+    let address_type = match params.address_type.as_deref() {
+        Some(name) => Some(
+            AddressType::from_name(name)
+                .ok_or(StatusCode::BAD_REQUEST)?
+                .as_str(),
+        ),
+        None => None,
+    };
+    let reason = match params.reason.as_deref() {
+        reason @ (None | Some("unseen") | Some("unreachable")) => reason,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    match db
+        .get_retired_nodes(network, address_type, reason, limit, offset)
+        .await
+    {
+        Ok(nodes) => Ok(Json(nodes)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener nodos retirados: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ChurnParams {
+    days: Option<i32>,
+}
+
+async fn get_churn_stats_api(
+    Query(params): Query<ChurnParams>,
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+) -> Result<Json<Vec<db::ChurnDay>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let days = params.days.unwrap_or(30).clamp(1, 365);
+
+    match db.get_churn_stats(network, days).await {
+        Ok(stats) => Ok(Json(stats)),
+        Err(e) => {
+            tracing::error!("Fallo al obtener estadísticas de rotación: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -626,28 +1684,78 @@ struct SearchParams {
 
 async fn search_nodes_api(
     Query(params): Query<SearchParams>,
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
-) -> Json<Vec<db::NodeInfo>> {
+) -> Result<Json<Vec<db::NodeInfo>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
     let query = params.q.unwrap_or_default();
     if query.trim().is_empty() {
-        return Json(vec![]);
+        return Ok(Json(vec![]));
     }
-    let nodes = db.search_nodes(&query).await.unwrap_or_default();
-    Json(nodes)
+    let nodes = db.search_nodes(network, &query).await.unwrap_or_default();
+    Ok(Json(nodes))
 }
 
 async fn get_incoming_stats_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
 ) -> impl axum::response::IntoResponse {
-    match db.get_incoming_stats_by_protocol().await {
-        Ok(stats) => (axum::http::StatusCode::OK, Json(stats)),
+    let network = match resolve_network(&config, &network_params) {
+        Ok(network) => network,
+        Err(status) => return (status, Json(vec![])),
+    };
+    match db.get_incoming_stats_by_protocol(network).await {
+        Ok(stats) => (StatusCode::OK, Json(stats)),
         Err(e) => {
             tracing::error!("Fallo al obtener incoming_stats: {:?}", e);
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
         }
     }
 }
 
+async fn get_proxy_health_api(
+    axum::Extension(monitor): axum::Extension<Arc<health::ProxyMonitor>>,
+) -> Json<Vec<health::ProxyStatus>> {
+    Json(monitor.statuses())
+}
+
+async fn get_vantage_health_api(
+    axum::Extension(monitor): axum::Extension<Arc<health::VantageMonitor>>,
+) -> Json<health::VantageStatus> {
+    Json(monitor.status())
+}
+
+async fn get_crawler_queues_api(
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
+    axum::Extension(db): axum::Extension<Arc<db::Database>>,
+    axum::Extension(monitor): axum::Extension<Arc<crawler::QueueMonitor>>,
+) -> Result<Json<Vec<crawler::QueueStatus>>, StatusCode> {
+    let network = resolve_network(&config, &network_params)?;
+    let backlog = match db
+        .get_scan_backlog(network, config.schedule.freshness)
+        .await
+    {
+        Ok(backlog) => backlog,
+        Err(e) => {
+            tracing::error!("Fallo al obtener el estado de las colas: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut statuses = monitor.statuses(network);
+    for status in &mut statuses {
+        let count = backlog
+            .iter()
+            .find(|count| count.address_type == status.address_type);
+        status.backlog = Some(count.map_or(0, |count| count.due));
+        status.stale = Some(count.map_or(0, |count| count.stale));
+    }
+    Ok(Json(statuses))
+}
+
 #[derive(Deserialize)]
 struct HistoryParams {
     range: Option<String>,
@@ -655,14 +1763,20 @@ struct HistoryParams {
 
 async fn get_historical_stats(
     Query(params): Query<HistoryParams>,
+    Query(network_params): Query<NetworkParams>,
+    axum::Extension(config): axum::Extension<Arc<config::Config>>,
     axum::Extension(db): axum::Extension<Arc<db::Database>>,
 ) -> impl axum::response::IntoResponse {
+    let network = match resolve_network(&config, &network_params) {
+        Ok(network) => network,
+        Err(status) => return (status, Json(vec![])),
+    };
     let range = params.range.unwrap_or_else(|| "24h".to_string());
-    match db.get_historical_stats_by_range(&range).await {
-        Ok(stats) => (axum::http::StatusCode::OK, Json(stats)),
+    match db.get_historical_stats_by_range(network, &range).await {
+        Ok(stats) => (StatusCode::OK, Json(stats)),
         Err(e) => {
             tracing::error!("Fallo al obtener estadísticas históricas: {:?}", e);
-            (axum::http::StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(vec![]))
         }
     }
 }
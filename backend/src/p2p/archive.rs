@@ -0,0 +1,53 @@
+// Verificación de nodos de archivo: se pide por 'getdata' el bloque 1, que
+// ningún nodo podado conserva. Su hash sale de la cadena de referencia, no de
+// lo que diga un peer suelto.
+
+use bitcoin::block::Block;
+use bitcoin::BlockHash;
+
+use std::sync::Arc;
+
+use super::chain::ChainTracker;
+use crate::db::ArchiveCheck;
+
+// Verificaciones sin respuesta seguidas tras las que el nodo cuenta como que
+// no sirve el bloque. Un nodo podado suele desconectar, pero también lo hace
+// uno sobrecargado o detrás de una conexión inestable.
+pub const FAILURE_LIMIT: i32 = 3;
+
+// 'notfound' o un bloque que no es el pedido.
+pub fn not_served() -> ArchiveCheck {
+    ArchiveCheck {
+        served: Some(false),
+        latency_ms: None,
+    }
+}
+
+// Plazo vencido o desconexión: no se sabe si tiene el bloque.
+pub fn inconclusive() -> ArchiveCheck {
+    ArchiveCheck {
+        served: None,
+        latency_ms: None,
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveProbe {
+    chain: Arc<ChainTracker>,
+}
+
+impl ArchiveProbe {
+    pub fn new(chain: Arc<ChainTracker>) -> Self {
+        ArchiveProbe { chain }
+    }
+
+    // Hash del bloque a pedir, si la referencia ya llega a la altura 1.
+    pub fn target(&self) -> Option<BlockHash> {
+        self.chain.hash_at(1)
+    }
+
+    // El bloque recibido es el pedido y su contenido corresponde a la cabecera.
+    pub fn verify(&self, block: &Block) -> bool {
+        Some(block.block_hash()) == self.target() && block.check_merkle_root()
+    }
+}
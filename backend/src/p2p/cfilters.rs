@@ -0,0 +1,65 @@
+// Verificación de filtros compactos (BIP157): a los nodos que anuncian
+// COMPACT_FILTERS se les pide con 'getcfcheckpt' la cabecera de filtro de cada
+// bloque múltiplo de 1000 hasta una parada común, tomada de la cadena de
+// referencia. Cada respuesta se resume en un hash y la comparación con la
+// mayoría se hace en la base de datos, entre los nodos con la misma parada.
+
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::p2p::message_filter::CFCheckpt;
+use bitcoin::BlockHash;
+
+use crate::db::FilterCheck;
+
+use super::chain::ChainTracker;
+
+// Filtro básico, el único definido en BIP158.
+pub const BASIC_FILTER: u8 = 0;
+
+const CHECKPOINT_INTERVAL: u32 = 1000;
+
+// La parada queda al menos estos bloques por debajo de la referencia para que
+// los nodos con algo de retraso (o con el índice aún al día) la tengan.
+const STOP_DEPTH: u32 = 100;
+
+pub fn not_served() -> FilterCheck {
+    FilterCheck {
+        served: false,
+        stop_height: None,
+        stop_hash: None,
+        digest: None,
+    }
+}
+
+// Último múltiplo de 1000 bajo la referencia menos `STOP_DEPTH`. Mientras la
+// referencia no llegue al primero no hay nada que pedir.
+pub fn stop(chain: &ChainTracker) -> Option<(u32, BlockHash)> {
+    let (tip, _) = chain.tip();
+    let height = tip.saturating_sub(STOP_DEPTH) / CHECKPOINT_INTERVAL * CHECKPOINT_INTERVAL;
+    if height == 0 {
+        return None;
+    }
+    chain.hash_at(height).map(|hash| (height, hash))
+}
+
+// Una respuesta sólo cuenta como servida si corresponde a lo pedido: mismo
+// filtro, misma parada y una cabecera por cada múltiplo de 1000.
+pub fn check(stop: (u32, BlockHash), response: &CFCheckpt) -> FilterCheck {
+    let (height, hash) = stop;
+    if response.filter_type != BASIC_FILTER
+        || response.stop_hash != hash
+        || response.filter_headers.len() != (height / CHECKPOINT_INTERVAL) as usize
+    {
+        return not_served();
+    }
+
+    let mut engine = sha256::Hash::engine();
+    for header in &response.filter_headers {
+        engine.input(header.as_byte_array());
+    }
+    FilterCheck {
+        served: true,
+        stop_height: Some(height as i32),
+        stop_hash: Some(hash.to_string()),
+        digest: Some(sha256::Hash::from_engine(engine).to_string()),
+    }
+}
@@ -0,0 +1,603 @@
+// Cadena de cabeceras de referencia para sondear la punta de cada nodo con
+// 'getheaders'. No hay nodo completo detrás: la cadena arranca en el génesis y
+// se extiende con las cabeceras que devuelven los propios peers, siempre que
+// encadenen y tengan prueba de trabajo válida para un objetivo que no supere
+// el límite de la red (el ajuste de dificultad no se comprueba). Ante dos ramas
+// se queda con la de más trabajo acumulado, sumando el de todos los lotes que
+// envía el peer y no sólo el del último.
+
+use bitcoin::block::Header;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::params::Params;
+use bitcoin::pow::{CompactTarget, Target, Work};
+use bitcoin::{BlockHash, Network};
+
+use std::sync::RwLock;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::db::ChainTip;
+
+// Máximo de cabeceras por respuesta a 'getheaders'.
+pub const MAX_HEADERS: usize = 2000;
+
+// Lotes seguidos que puede pedir una misma sesión mientras la referencia se
+// pone al día (2000 cabeceras por lote).
+pub const MAX_CATCH_UP_BATCHES: u32 = 250;
+
+// El localizador empieza unos bloques por debajo de nuestra punta para que un
+// nodo sincronizado conteste con cabeceras y no con una respuesta vacía.
+const PROBE_DEPTH: u32 = 6;
+
+// Un bloque de diferencia se considera sincronizado: puede haber llegado
+// mientras durase el sondeo.
+const SYNC_TOLERANCE: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TipStatus {
+    Synced,
+    Lagging,
+    Forked,
+    NoResponse,
+}
+
+impl TipStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TipStatus::Synced => "synced",
+            TipStatus::Lagging => "lagging",
+            TipStatus::Forked => "forked",
+            TipStatus::NoResponse => "no_response",
+        }
+    }
+}
+
+// Los nodos en IBD no contestan a 'getheaders'.
+pub fn no_response() -> ChainTip {
+    ChainTip {
+        status: TipStatus::NoResponse.as_str(),
+        hash: None,
+        height: None,
+        lag: None,
+    }
+}
+
+pub type Locator = Vec<(u32, BlockHash)>;
+
+pub fn hashes(locator: &[(u32, BlockHash)]) -> Vec<BlockHash> {
+    locator.iter().map(|(_, hash)| *hash).collect()
+}
+
+pub enum Probe {
+    Tip(ChainTip),
+    // Respuesta completa: el peer tiene más cabeceras que pedir.
+    More(Locator),
+}
+
+// Rama de un peer que compite con la referencia y todavía no la supera en
+// trabajo. Se acumula entre lotes de la misma sesión: si sólo contara el
+// último, una rama barata ya incorporada (cabeceras al objetivo mínimo)
+// ganaría a cualquier rama honesta más larga que un lote.
+#[derive(Debug)]
+pub struct Branch {
+    // Altura de la primera cabecera de la rama y hash de su padre en la
+    // referencia.
+    split: u32,
+    parent: BlockHash,
+    hashes: Vec<BlockHash>,
+    bits: Vec<CompactTarget>,
+}
+
+impl Branch {
+    fn hash_at(&self, chain: &Chain, height: u32) -> BlockHash {
+        match height.checked_sub(self.split) {
+            Some(i) => self.hashes[i as usize],
+            None => chain.hashes[height as usize],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Chain {
+    hashes: Vec<BlockHash>,
+    bits: Vec<CompactTarget>,
+}
+
+impl Chain {
+    fn tip_height(&self) -> u32 {
+        (self.hashes.len() - 1) as u32
+    }
+
+    fn locator_from(&self, start: u32) -> Locator {
+        locator_from(start, |height| self.hashes[height as usize])
+    }
+
+    fn work_from(&self, height: u32) -> Option<Work> {
+        chain_work(self.bits[height as usize..].iter().copied())
+    }
+
+    fn extend(
+        &mut self,
+        height: u32,
+        bits: impl IntoIterator<Item = CompactTarget>,
+        hashes: &[BlockHash],
+    ) {
+        self.hashes.truncate(height as usize);
+        self.bits.truncate(height as usize);
+        self.hashes.extend_from_slice(hashes);
+        self.bits.extend(bits);
+    }
+
+    fn tip_at(&self, height: u32, hash: BlockHash) -> ChainTip {
+        let lag = self.tip_height() as i64 - height as i64;
+        let status = if lag <= SYNC_TOLERANCE {
+            TipStatus::Synced
+        } else {
+            TipStatus::Lagging
+        };
+        ChainTip {
+            status: status.as_str(),
+            hash: Some(hash.to_string()),
+            height: Some(height as i32),
+            lag: Some(lag as i32),
+        }
+    }
+}
+
+fn locator_from(start: u32, hash_at: impl Fn(u32) -> BlockHash) -> Locator {
+    let mut locator = Vec::new();
+    let mut height = start;
+    let mut step = 1;
+    loop {
+        locator.push((height, hash_at(height)));
+        if height == 0 {
+            break;
+        }
+        if locator.len() >= 10 {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+    locator
+}
+
+fn chain_work(bits: impl Iterator<Item = CompactTarget>) -> Option<Work> {
+    bits.map(|bits| Target::from_compact(bits).to_work())
+        .reduce(|a, b| a + b)
+}
+
+#[derive(Debug)]
+pub struct ChainTracker {
+    network: Network,
+    // Objetivo más fácil que admite la red; una cabecera puede declarar el
+    // que quiera, así que sin este límite cualquiera fabricaría una cadena.
+    max_target: Target,
+    chain: RwLock<Chain>,
+    // Sólo una sesión a la vez descarga lotes completos para ponerse al día.
+    catch_up: Mutex<()>,
+}
+
+impl ChainTracker {
+    pub fn new(network: Network) -> Self {
+        let genesis = genesis_block(network).header;
+        ChainTracker {
+            network,
+            max_target: Params::new(network).max_attainable_target,
+            chain: RwLock::new(Chain {
+                hashes: vec![genesis.block_hash()],
+                bits: vec![genesis.bits],
+            }),
+            catch_up: Mutex::new(()),
+        }
+    }
+
+    pub fn tip(&self) -> (u32, BlockHash) {
+        let chain = self.chain.read().unwrap();
+        let height = chain.tip_height();
+        (height, chain.hashes[height as usize])
+    }
+
+    pub fn hash_at(&self, height: u32) -> Option<BlockHash> {
+        self.chain
+            .read()
+            .unwrap()
+            .hashes
+            .get(height as usize)
+            .copied()
+    }
+
+    pub fn locator(&self) -> Locator {
+        let chain = self.chain.read().unwrap();
+        chain.locator_from(chain.tip_height().saturating_sub(PROBE_DEPTH))
+    }
+
+    pub fn try_catch_up(&self) -> Option<MutexGuard<'_, ()>> {
+        self.catch_up.try_lock().ok()
+    }
+
+    // Interpreta la respuesta a un 'getheaders' enviado con `locator`. Las
+    // cabeceras que prolongan la referencia (o una rama con más trabajo) se
+    // incorporan; el resto describe la punta del peer respecto a la nuestra.
+    // `branch` guarda entre lotes de la sesión la rama del peer que aún no
+    // supera a la referencia.
+    pub fn classify(
+        &self,
+        locator: &[(u32, BlockHash)],
+        headers: &[Header],
+        start_height: Option<i32>,
+        branch: &mut Option<Branch>,
+    ) -> Result<Probe, String> {
+        let mut chain = self.chain.write().unwrap();
+        let pending = branch.take();
+
+        let first = match headers.first() {
+            Some(first) => first,
+            None => {
+                // El peer no tiene nada después de la primera entrada del
+                // localizador que conoce, así que su punta es esa entrada. Su
+                // 'start_height' dice cuál; si no coincide con ninguna, la
+                // más alta.
+                let (height, hash) = locator
+                    .iter()
+                    .find(|(height, _)| Some(*height as i32) == start_height)
+                    .or_else(|| locator.first())
+                    .copied()
+                    .ok_or_else(|| "localizador vacío".to_string())?;
+                // Tras un lote completo de una rama pendiente, su punta es la
+                // de la rama.
+                if pending
+                    .as_ref()
+                    .is_some_and(|pending| pending.hashes.contains(&hash))
+                {
+                    return Ok(Probe::Tip(forked(&chain, height, hash)));
+                }
+                return Ok(Probe::Tip(chain.tip_at(height, hash)));
+            }
+        };
+
+        let fork = locator
+            .iter()
+            .find(|(_, hash)| *hash == first.prev_blockhash)
+            .map(|(height, _)| *height)
+            .ok_or_else(|| "las cabeceras no parten del localizador".to_string())?;
+
+        let mut hashes = Vec::with_capacity(headers.len());
+        let mut prev = first.prev_blockhash;
+        for header in headers {
+            if header.prev_blockhash != prev {
+                return Err("cabeceras no encadenadas".to_string());
+            }
+            let target = header.target();
+            if target > self.max_target {
+                return Err("objetivo por encima del límite de la red".to_string());
+            }
+            prev = header
+                .validate_pow(target)
+                .map_err(|e| format!("prueba de trabajo inválida: {}", e))?;
+            hashes.push(prev);
+        }
+
+        let last_height = fork + headers.len() as u32;
+        let full = headers.len() == MAX_HEADERS;
+
+        // El lote prolonga la rama pendiente: se compara la rama entera.
+        if let Some(mut pending) =
+            pending
+                .filter(|pending| fork >= pending.split)
+                .filter(|pending| {
+                    pending.hashes.get((fork - pending.split) as usize)
+                        == Some(&first.prev_blockhash)
+                })
+        {
+            if chain.hashes.get(pending.split as usize - 1) != Some(&pending.parent) {
+                return Err("la cadena de referencia cambió durante el sondeo".to_string());
+            }
+            let keep = (fork + 1 - pending.split) as usize;
+            pending.hashes.truncate(keep);
+            pending.bits.truncate(keep);
+            pending.hashes.extend_from_slice(&hashes);
+            pending
+                .bits
+                .extend(headers.iter().map(|header| header.bits));
+
+            let theirs = chain_work(pending.bits.iter().copied());
+            if theirs <= chain.work_from(pending.split) {
+                if full {
+                    let next = locator_from(last_height, |height| pending.hash_at(&chain, height));
+                    *branch = Some(pending);
+                    return Ok(Probe::More(next));
+                }
+                return Ok(Probe::Tip(forked(&chain, last_height, prev)));
+            }
+            self.log_reorg(pending.split, last_height);
+            chain.extend(pending.split, pending.bits, &pending.hashes);
+        } else {
+            if chain.hashes.get(fork as usize) != Some(&first.prev_blockhash) {
+                return Err("la cadena de referencia cambió durante el sondeo".to_string());
+            }
+
+            // Primera cabecera que no coincide con nuestra cadena.
+            let diverge = hashes
+                .iter()
+                .enumerate()
+                .position(|(i, hash)| chain.hashes.get(fork as usize + 1 + i) != Some(hash));
+
+            if let Some(i) = diverge {
+                let split = fork + 1 + i as u32;
+                if split <= chain.tip_height() {
+                    let theirs = chain_work(headers[i..].iter().map(|header| header.bits));
+                    if theirs <= chain.work_from(split) {
+                        if full {
+                            let pending = Branch {
+                                split,
+                                parent: chain.hashes[split as usize - 1],
+                                hashes: hashes[i..].to_vec(),
+                                bits: headers[i..].iter().map(|header| header.bits).collect(),
+                            };
+                            let next =
+                                locator_from(last_height, |height| pending.hash_at(&chain, height));
+                            *branch = Some(pending);
+                            return Ok(Probe::More(next));
+                        }
+                        return Ok(Probe::Tip(forked(&chain, last_height, prev)));
+                    }
+                    self.log_reorg(split, last_height);
+                }
+                let bits = headers[i..].iter().map(|header| header.bits);
+                chain.extend(split, bits, &hashes[i..]);
+            }
+        }
+
+        if full {
+            let start = last_height.min(chain.tip_height());
+            return Ok(Probe::More(chain.locator_from(start)));
+        }
+        Ok(Probe::Tip(chain.tip_at(last_height, prev)))
+    }
+
+    fn log_reorg(&self, split: u32, last_height: u32) {
+        tracing::info!(
+            "[Chain] Reorganización de la referencia ({}): nueva rama desde la altura {} hasta {}",
+            crate::common::network_name(self.network),
+            split,
+            last_height
+        );
+    }
+}
+
+// Punta de un peer en una rama con menos trabajo que la referencia.
+fn forked(chain: &Chain, height: u32, hash: BlockHash) -> ChainTip {
+    ChainTip {
+        status: TipStatus::Forked.as_str(),
+        hash: Some(hash.to_string()),
+        height: Some(height as i32),
+        lag: Some((chain.tip_height() as i64 - height as i64) as i32),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::block::Version;
+    use bitcoin::hashes::Hash;
+    use bitcoin::TxMerkleNode;
+
+    // Objetivo mínimo de regtest y otro con el doble de trabajo por cabecera.
+    const EASY: u32 = 0x207fffff;
+    const HARD: u32 = 0x203fffff;
+
+    // Mina `count` cabeceras sobre `prev`; `time` distingue ramas paralelas.
+    fn mine(prev: BlockHash, count: usize, bits: u32, time: u32) -> Vec<Header> {
+        let mut headers = Vec::with_capacity(count);
+        let mut prev = prev;
+        for _ in 0..count {
+            let mut header = Header {
+                version: Version::ONE,
+                prev_blockhash: prev,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time,
+                bits: CompactTarget::from_consensus(bits),
+                nonce: 0,
+            };
+            while header.validate_pow(header.target()).is_err() {
+                header.nonce += 1;
+            }
+            prev = header.block_hash();
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn genesis() -> BlockHash {
+        genesis_block(Network::Regtest).header.block_hash()
+    }
+
+    fn expect_tip(probe: Result<Probe, String>) -> ChainTip {
+        match probe {
+            Ok(Probe::Tip(tip)) => tip,
+            Ok(Probe::More(_)) => panic!("se esperaba una punta y se pidió otro lote"),
+            Err(e) => panic!("respuesta rechazada: {}", e),
+        }
+    }
+
+    fn expect_more(probe: Result<Probe, String>) -> Locator {
+        match probe {
+            Ok(Probe::More(locator)) => locator,
+            Ok(Probe::Tip(tip)) => {
+                panic!("se esperaba otro lote y llegó la punta {:?}", tip.status)
+            }
+            Err(e) => panic!("respuesta rechazada: {}", e),
+        }
+    }
+
+    // Respuesta de un peer en otra rama: las cabeceras comunes tras el
+    // localizador y después las suyas.
+    fn response(common: &[Header], theirs: &[Header]) -> Vec<Header> {
+        common.iter().chain(theirs).copied().collect()
+    }
+
+    // Referencia de regtest con `headers` ya incorporadas por lotes.
+    fn tracker_with(headers: &[Header]) -> ChainTracker {
+        let tracker = ChainTracker::new(Network::Regtest);
+        let mut branch = None;
+        let mut locator = tracker.locator();
+        for batch in headers.chunks(MAX_HEADERS) {
+            match tracker.classify(&locator, batch, None, &mut branch) {
+                Ok(Probe::More(next)) => locator = next,
+                Ok(Probe::Tip(_)) => {}
+                Err(e) => panic!("respuesta rechazada: {}", e),
+            }
+        }
+        assert_eq!(tracker.tip().0 as usize, headers.len());
+        tracker
+    }
+
+    #[test]
+    fn extend() {
+        let tracker = ChainTracker::new(Network::Regtest);
+        let headers = mine(genesis(), 10, EASY, 1);
+        let tip = expect_tip(tracker.classify(&tracker.locator(), &headers, None, &mut None));
+        assert_eq!(tip.status, "synced");
+        assert_eq!(tip.height, Some(10));
+        assert_eq!(tip.lag, Some(0));
+        assert_eq!(tracker.tip(), (10, headers[9].block_hash()));
+    }
+
+    #[test]
+    fn full_batch_asks_for_more() {
+        let tracker = ChainTracker::new(Network::Regtest);
+        let headers = mine(genesis(), MAX_HEADERS, EASY, 1);
+        let locator = expect_more(tracker.classify(&tracker.locator(), &headers, None, &mut None));
+        assert_eq!(locator[0], (2000, headers[1999].block_hash()));
+    }
+
+    #[test]
+    fn lagging() {
+        let headers = mine(genesis(), 20, EASY, 1);
+        let tracker = tracker_with(&headers);
+        let locator = tracker.locator();
+        assert_eq!(locator[0].0, 14);
+
+        // Peer en la altura 17: contesta con las que le faltan al localizador.
+        let tip = expect_tip(tracker.classify(&locator, &headers[14..17], None, &mut None));
+        assert_eq!(tip.status, "lagging");
+        assert_eq!(tip.height, Some(17));
+        assert_eq!(tip.lag, Some(3));
+
+        // Un bloque por detrás cuenta como sincronizado.
+        let tip = expect_tip(tracker.classify(&locator, &headers[14..19], None, &mut None));
+        assert_eq!(tip.status, "synced");
+
+        // Peer por debajo del localizador: respuesta vacía y 'start_height'.
+        let tip = expect_tip(tracker.classify(&locator, &[], Some(12), &mut None));
+        assert_eq!(tip.status, "lagging");
+        assert_eq!(tip.height, Some(12));
+        assert_eq!(tip.hash, Some(headers[11].block_hash().to_string()));
+        assert_eq!(tip.lag, Some(8));
+        assert_eq!(tracker.tip().0, 20);
+    }
+
+    #[test]
+    fn forked() {
+        let headers = mine(genesis(), 20, EASY, 1);
+        let tracker = tracker_with(&headers);
+
+        // Rama más corta sobre la altura 16: no desplaza a la referencia.
+        let theirs = mine(headers[15].block_hash(), 2, EASY, 2);
+        let tip = expect_tip(tracker.classify(
+            &tracker.locator(),
+            &response(&headers[14..16], &theirs),
+            None,
+            &mut None,
+        ));
+        assert_eq!(tip.status, "forked");
+        assert_eq!(tip.height, Some(18));
+        assert_eq!(tip.hash, Some(theirs[1].block_hash().to_string()));
+        assert_eq!(tip.lag, Some(2));
+        assert_eq!(tracker.tip(), (20, headers[19].block_hash()));
+    }
+
+    #[test]
+    fn reorg() {
+        let headers = mine(genesis(), 20, EASY, 1);
+        let tracker = tracker_with(&headers);
+
+        // Rama más larga sobre la altura 16: pasa a ser la referencia.
+        let theirs = mine(headers[15].block_hash(), 6, EASY, 2);
+        let tip = expect_tip(tracker.classify(
+            &tracker.locator(),
+            &response(&headers[14..16], &theirs),
+            None,
+            &mut None,
+        ));
+        assert_eq!(tip.status, "synced");
+        assert_eq!(tip.height, Some(22));
+        assert_eq!(tracker.tip(), (22, theirs[5].block_hash()));
+        assert_eq!(tracker.hash_at(17), Some(theirs[0].block_hash()));
+    }
+
+    #[test]
+    fn reorg_by_work_not_length() {
+        let headers = mine(genesis(), 20, EASY, 1);
+        let tracker = tracker_with(&headers);
+
+        // Misma longitud pero el doble de trabajo por cabecera.
+        let theirs = mine(headers[15].block_hash(), 4, HARD, 2);
+        let tip = expect_tip(tracker.classify(
+            &tracker.locator(),
+            &response(&headers[14..16], &theirs),
+            None,
+            &mut None,
+        ));
+        assert_eq!(tip.status, "synced");
+        assert_eq!(tracker.tip(), (20, theirs[3].block_hash()));
+    }
+
+    #[test]
+    fn branch_work_accumulates_across_batches() {
+        // Referencia envenenada con una cadena larga de cabeceras baratas.
+        let cheap = mine(genesis(), 5000, EASY, 1);
+        let tracker = tracker_with(&cheap);
+
+        // La rama honesta tiene más trabajo en total, pero su primer lote
+        // (2000 cabeceras de doble trabajo) no supera a las 5000 baratas.
+        let honest = mine(genesis(), 3000, HARD, 2);
+        let mut branch = None;
+        let locator =
+            expect_more(tracker.classify(&tracker.locator(), &honest[..2000], None, &mut branch));
+        assert_eq!(locator[0], (2000, honest[1999].block_hash()));
+        assert!(branch.is_some());
+        assert_eq!(tracker.tip().1, cheap[4999].block_hash());
+
+        let tip = expect_tip(tracker.classify(&locator, &honest[2000..], None, &mut branch));
+        assert_eq!(tip.status, "synced");
+        assert_eq!(tracker.tip(), (3000, honest[2999].block_hash()));
+        assert_eq!(tracker.hash_at(1), Some(honest[0].block_hash()));
+    }
+
+    #[test]
+    fn losing_branch_is_forked_after_last_batch() {
+        let headers = mine(genesis(), 5000, EASY, 1);
+        let tracker = tracker_with(&headers);
+
+        let theirs = mine(genesis(), 2500, EASY, 2);
+        let mut branch = None;
+        let locator =
+            expect_more(tracker.classify(&tracker.locator(), &theirs[..2000], None, &mut branch));
+        let tip = expect_tip(tracker.classify(&locator, &theirs[2000..], None, &mut branch));
+        assert_eq!(tip.status, "forked");
+        assert_eq!(tip.height, Some(2500));
+        assert_eq!(tip.lag, Some(2500));
+        assert!(branch.is_none());
+        assert_eq!(tracker.tip().1, headers[4999].block_hash());
+    }
+
+    #[test]
+    fn rejects_target_above_limit() {
+        let tracker = ChainTracker::new(Network::Regtest);
+        let headers = mine(genesis(), 1, 0x2100ffff, 1);
+        assert!(tracker
+            .classify(&tracker.locator(), &headers, None, &mut None)
+            .is_err());
+        assert_eq!(tracker.tip().0, 0);
+    }
+}
@@ -0,0 +1,507 @@
+use bitcoin::consensus::{deserialize, encode, serialize};
+use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::Network;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::v2::{self, V2Cipher};
+
+use std::fmt;
+
+pub const HEADER_SIZE: usize = 24;
+
+// Igual que MAX_PROTOCOL_MESSAGE_LENGTH de Bitcoin Core.
+pub const MAX_PAYLOAD_SIZE: usize = 4_000_000;
+
+const MAX_ADDR_ENTRIES: usize = 1000;
+const MAX_INV_ENTRIES: usize = 50_000;
+const MAX_HEADERS_ENTRIES: usize = 2000;
+const MAX_LOCATOR_HASHES: usize = 101;
+
+// Tamaño máximo del payload para cada comando conocido. Los límites se derivan
+// del número máximo de entradas que acepta Bitcoin Core para cada mensaje; los
+// comandos desconocidos sólo están acotados por MAX_PAYLOAD_SIZE.
+pub fn payload_limit(command: &str) -> usize {
+    match command {
+        "verack" | "getaddr" | "sendaddrv2" | "sendheaders" | "wtxidrelay" | "mempool"
+        | "filterclear" => 0,
+        "ping" | "pong" | "feefilter" => 8,
+        "sendcmpct" => 9,
+        // version fijo (~86 bytes) + user agent de hasta 256 bytes.
+        "version" => 1024,
+        // compact size + (time, services, ip, port) por entrada.
+        "addr" => 9 + MAX_ADDR_ENTRIES * 30,
+        // compact size + (time, services, network id, address, port) por entrada,
+        // con direcciones de hasta 512 bytes.
+        "addrv2" => 9 + MAX_ADDR_ENTRIES * (4 + 9 + 1 + 9 + 512 + 2),
+        "inv" | "getdata" | "notfound" => 9 + MAX_INV_ENTRIES * 36,
+        "headers" => 9 + MAX_HEADERS_ENTRIES * 81,
+        "getheaders" | "getblocks" => 4 + 9 + MAX_LOCATOR_HASHES * 32 + 32,
+        _ => MAX_PAYLOAD_SIZE,
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(std::io::Error),
+    BadMagic {
+        expected: [u8; 4],
+        received: [u8; 4],
+    },
+    InvalidCommand([u8; 12]),
+    PayloadTooLarge {
+        command: String,
+        size: usize,
+        limit: usize,
+    },
+    BadChecksum {
+        command: String,
+        declared: [u8; 4],
+        computed: [u8; 4],
+    },
+    Decode {
+        command: String,
+        error: encode::Error,
+    },
+    // Fallo del handshake BIP324 (respuesta v1, sin terminador de basura...).
+    Handshake(String),
+    // Un paquete v2 no pasó la autenticación.
+    Decrypt,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "Error de E/S: {}", e),
+            CodecError::BadMagic { expected, received } => write!(
+                f,
+                "Magic inesperado: {} (esperado {})",
+                hex::encode(received),
+                hex::encode(expected)
+            ),
+            CodecError::InvalidCommand(raw) => {
+                write!(f, "Comando inválido en la cabecera: {}", hex::encode(raw))
+            }
+            CodecError::PayloadTooLarge {
+                command,
+                size,
+                limit,
+            } => write!(
+                f,
+                "Payload de '{}' demasiado grande: {} bytes (máximo {})",
+                command, size, limit
+            ),
+            CodecError::BadChecksum {
+                command,
+                declared,
+                computed,
+            } => write!(
+                f,
+                "Checksum incorrecto en '{}': la cabecera indica {} y el payload da {}",
+                command,
+                hex::encode(declared),
+                hex::encode(computed)
+            ),
+            CodecError::Decode { command, error } => {
+                write!(f, "No se pudo decodificar '{}': {}", command, error)
+            }
+            CodecError::Handshake(reason) => write!(f, "Handshake v2 fallido: {}", reason),
+            CodecError::Decrypt => write!(f, "Paquete v2 con autenticación inválida"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            CodecError::Decode { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(e: std::io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+// El comando son 12 bytes ASCII imprimibles rellenados con ceros por la derecha.
+fn parse_command(raw: &[u8; 12]) -> Result<String, CodecError> {
+    let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    if len == 0
+        || raw[len..].iter().any(|&b| b != 0)
+        || !raw[..len].iter().all(|b| b.is_ascii_graphic())
+    {
+        return Err(CodecError::InvalidCommand(*raw));
+    }
+    Ok(String::from_utf8_lossy(&raw[..len]).into_owned())
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = sha256d::Hash::hash(payload).to_byte_array();
+    let mut computed = [0u8; 4];
+    computed.copy_from_slice(&digest[0..4]);
+    computed
+}
+
+// Decodifica un payload ya validado reconstruyendo la trama v1 completa, que es
+// lo que sabe deserializar rust-bitcoin.
+fn decode_payload(
+    network: Network,
+    command: String,
+    payload: &[u8],
+) -> Result<NetworkMessage, CodecError> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.extend_from_slice(&network.magic().to_bytes());
+    let mut raw_command = [0u8; 12];
+    raw_command[..command.len()].copy_from_slice(command.as_bytes());
+    message.extend_from_slice(&raw_command);
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(&checksum(payload));
+    message.extend_from_slice(payload);
+
+    let raw: RawNetworkMessage =
+        deserialize(&message).map_err(|error| CodecError::Decode { command, error })?;
+    Ok(raw.into_payload())
+}
+
+enum Transport {
+    V1,
+    V2(Box<V2Cipher>),
+}
+
+// Envuelve un stream y lee/escribe mensajes P2P completos de una red concreta,
+// ya sea con tramas v1 en claro o con paquetes cifrados v2 (BIP324).
+pub struct MessageStream<S> {
+    inner: S,
+    network: Network,
+    transport: Transport,
+}
+
+impl<S> MessageStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: S, network: Network) -> Self {
+        MessageStream {
+            inner,
+            network,
+            transport: Transport::V1,
+        }
+    }
+
+    // Negocia el transporte v2 como iniciador antes de intercambiar mensajes.
+    pub async fn handshake_v2(mut inner: S, network: Network) -> Result<Self, CodecError> {
+        let cipher = v2::initiate(&mut inner, network).await?;
+        Ok(MessageStream {
+            inner,
+            network,
+            transport: Transport::V2(Box::new(cipher)),
+        })
+    }
+
+    pub fn transport(&self) -> &'static str {
+        match self.transport {
+            Transport::V1 => "v1",
+            Transport::V2(_) => "v2",
+        }
+    }
+
+    pub async fn read_message(&mut self) -> Result<NetworkMessage, CodecError> {
+        self.read_message_within(MAX_PAYLOAD_SIZE).await
+    }
+
+    // Como read_message, pero aplicando además un tope global al payload (útil
+    // para el primer mensaje de un peer entrante, del que aún no sabemos nada).
+    pub async fn read_message_within(
+        &mut self,
+        max_payload: usize,
+    ) -> Result<NetworkMessage, CodecError> {
+        match self.transport {
+            Transport::V1 => self.read_v1(max_payload).await,
+            Transport::V2(_) => self.read_v2(max_payload).await,
+        }
+    }
+
+    async fn read_v1(&mut self, max_payload: usize) -> Result<NetworkMessage, CodecError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.inner.read_exact(&mut header).await?;
+
+        let expected_magic = self.network.magic().to_bytes();
+        let mut received_magic = [0u8; 4];
+        received_magic.copy_from_slice(&header[0..4]);
+        if received_magic != expected_magic {
+            return Err(CodecError::BadMagic {
+                expected: expected_magic,
+                received: received_magic,
+            });
+        }
+
+        let mut raw_command = [0u8; 12];
+        raw_command.copy_from_slice(&header[4..16]);
+        let command = parse_command(&raw_command)?;
+
+        let mut size_bytes = [0u8; 4];
+        size_bytes.copy_from_slice(&header[16..20]);
+        let size = u32::from_le_bytes(size_bytes) as usize;
+        let limit = payload_limit(&command).min(max_payload);
+        if size > limit {
+            return Err(CodecError::PayloadTooLarge {
+                command,
+                size,
+                limit,
+            });
+        }
+
+        let mut payload = vec![0u8; size];
+        self.inner.read_exact(&mut payload).await?;
+
+        let mut declared = [0u8; 4];
+        declared.copy_from_slice(&header[20..24]);
+        let computed = checksum(&payload);
+        if declared != computed {
+            return Err(CodecError::BadChecksum {
+                command,
+                declared,
+                computed,
+            });
+        }
+
+        decode_payload(self.network, command, &payload)
+    }
+
+    // En v2 no hay checksum (lo cubre el AEAD); los paquetes señuelo y los de
+    // identificador corto desconocido se descartan, y los límites por comando
+    // se aplican tras descifrar.
+    async fn read_v2(&mut self, max_payload: usize) -> Result<NetworkMessage, CodecError> {
+        let cipher = match &mut self.transport {
+            Transport::V2(cipher) => cipher,
+            Transport::V1 => unreachable!(),
+        };
+        loop {
+            let mut length = [0u8; v2::LENGTH_LEN];
+            self.inner.read_exact(&mut length).await?;
+            let length = cipher.decrypt_length(length);
+            // El contenido incluye hasta 13 bytes de comando antes del payload.
+            if length > max_payload.min(MAX_PAYLOAD_SIZE) + 13 {
+                return Err(CodecError::PayloadTooLarge {
+                    command: "v2".to_string(),
+                    size: length,
+                    limit: max_payload.min(MAX_PAYLOAD_SIZE) + 13,
+                });
+            }
+            let mut ciphertext = vec![0u8; v2::HEADER_LEN + length + v2::TAG_LEN];
+            self.inner.read_exact(&mut ciphertext).await?;
+            let contents = match cipher.decrypt_packet(&[], &ciphertext)? {
+                Some(contents) => contents,
+                None => continue,
+            };
+
+            let (command, payload) = match contents.first() {
+                None => return Err(CodecError::InvalidCommand([0u8; 12])),
+                Some(0) if contents.len() >= 13 => {
+                    let mut raw_command = [0u8; 12];
+                    raw_command.copy_from_slice(&contents[1..13]);
+                    (parse_command(&raw_command)?, &contents[13..])
+                }
+                Some(0) => return Err(CodecError::InvalidCommand([0u8; 12])),
+                // BIP324 reserva el resto de identificadores para mensajes
+                // futuros, que se ignoran como los comandos desconocidos en v1.
+                Some(&id) => match v2::command_for_short_id(id) {
+                    Some(command) => (command.to_string(), &contents[1..]),
+                    None => {
+                        tracing::debug!(target: "p2p", "Ignorando mensaje v2 con identificador corto desconocido {}", id);
+                        continue;
+                    }
+                },
+            };
+
+            let limit = payload_limit(&command).min(max_payload);
+            if payload.len() > limit {
+                return Err(CodecError::PayloadTooLarge {
+                    command,
+                    size: payload.len(),
+                    limit,
+                });
+            }
+            return decode_payload(self.network, command, payload);
+        }
+    }
+
+    pub async fn write_message(&mut self, payload: NetworkMessage) -> Result<(), CodecError> {
+        let raw = RawNetworkMessage::new(self.network.magic(), payload);
+        let bytes = match &mut self.transport {
+            Transport::V1 => serialize(&raw),
+            Transport::V2(cipher) => {
+                let command = raw.command();
+                let mut contents = match v2::short_id(command.as_ref()) {
+                    Some(id) => vec![id],
+                    None => {
+                        let mut raw_command = [0u8; 13];
+                        raw_command[1..1 + command.as_ref().len()]
+                            .copy_from_slice(command.as_ref().as_bytes());
+                        raw_command.to_vec()
+                    }
+                };
+                contents.extend_from_slice(&serialize(&raw)[HEADER_SIZE..]);
+                cipher.encrypt_packet(&contents, &[], false)
+            }
+        };
+        self.inner.write_all(&bytes).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::p2p::Magic;
+    use tokio::io::duplex;
+
+    // Trama v1 con la cabecera indicada; `size` y `sum` permiten falsearla.
+    fn frame(magic: Magic, command: &str, payload: &[u8], size: u32, sum: [u8; 4]) -> Vec<u8> {
+        let mut frame = magic.to_bytes().to_vec();
+        let mut raw_command = [0u8; 12];
+        raw_command[..command.len()].copy_from_slice(command.as_bytes());
+        frame.extend_from_slice(&raw_command);
+        frame.extend_from_slice(&size.to_le_bytes());
+        frame.extend_from_slice(&sum);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn valid_frame(command: &str, payload: &[u8]) -> Vec<u8> {
+        frame(
+            Network::Bitcoin.magic(),
+            command,
+            payload,
+            payload.len() as u32,
+            checksum(payload),
+        )
+    }
+
+    async fn read(bytes: Vec<u8>) -> Result<NetworkMessage, CodecError> {
+        let (local, mut remote) = duplex(64 * 1024);
+        remote.write_all(&bytes).await.unwrap();
+        MessageStream::new(local, Network::Bitcoin)
+            .read_message()
+            .await
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let (local, remote) = duplex(64 * 1024);
+        let mut ours = MessageStream::new(local, Network::Bitcoin);
+        let mut theirs = MessageStream::new(remote, Network::Bitcoin);
+
+        let messages = vec![
+            NetworkMessage::Verack,
+            NetworkMessage::Ping(42),
+            NetworkMessage::GetAddr,
+            NetworkMessage::Unknown {
+                command: bitcoin::p2p::message::CommandString::try_from_static("futuro").unwrap(),
+                payload: vec![1, 2, 3],
+            },
+        ];
+        for message in messages {
+            ours.write_message(message.clone()).await.unwrap();
+            assert_eq!(theirs.read_message().await.unwrap(), message);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_valid_frame() {
+        let message = read(valid_frame("ping", &7u64.to_le_bytes())).await;
+        assert_eq!(message.unwrap(), NetworkMessage::Ping(7));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_magic() {
+        let bytes = frame(Network::Testnet.magic(), "verack", &[], 0, checksum(&[]));
+        match read(bytes).await {
+            Err(CodecError::BadMagic { expected, received }) => {
+                assert_eq!(expected, Network::Bitcoin.magic().to_bytes());
+                assert_eq!(received, Network::Testnet.magic().to_bytes());
+            }
+            other => panic!("esperaba BadMagic, llegó {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_checksum() {
+        let payload = 7u64.to_le_bytes();
+        let bytes = frame(Network::Bitcoin.magic(), "ping", &payload, 8, [0, 0, 0, 0]);
+        match read(bytes).await {
+            Err(CodecError::BadChecksum {
+                command,
+                declared,
+                computed,
+            }) => {
+                assert_eq!(command, "ping");
+                assert_eq!(declared, [0, 0, 0, 0]);
+                assert_eq!(computed, checksum(&payload));
+            }
+            other => panic!("esperaba BadChecksum, llegó {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_command() {
+        let mut bytes = valid_frame("verack", &[]);
+        bytes[4] = b' ';
+        assert!(matches!(
+            read(bytes).await,
+            Err(CodecError::InvalidCommand(_))
+        ));
+    }
+
+    // El tamaño declarado se comprueba antes de leer el payload, así que basta
+    // con la cabecera.
+    #[tokio::test]
+    async fn rejects_payload_over_command_limit() {
+        let cases = [
+            ("verack", 1),
+            ("ping", 9),
+            ("version", 1025),
+            ("headers", payload_limit("headers") + 1),
+            ("futuro", MAX_PAYLOAD_SIZE + 1),
+        ];
+        for (command, size) in cases.iter() {
+            let bytes = frame(Network::Bitcoin.magic(), command, &[], *size as u32, [0; 4]);
+            match read(bytes).await {
+                Err(CodecError::PayloadTooLarge {
+                    command: rejected,
+                    size: declared,
+                    limit,
+                }) => {
+                    assert_eq!(rejected, *command);
+                    assert_eq!(declared, *size);
+                    assert_eq!(limit, payload_limit(command));
+                }
+                other => panic!(
+                    "esperaba PayloadTooLarge para '{}', llegó {:?}",
+                    command, other
+                ),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_global_limit() {
+        let (local, mut remote) = duplex(64 * 1024);
+        remote
+            .write_all(&valid_frame("ping", &7u64.to_le_bytes()))
+            .await
+            .unwrap();
+        let result = MessageStream::new(local, Network::Bitcoin)
+            .read_message_within(4)
+            .await;
+        assert!(matches!(
+            result,
+            Err(CodecError::PayloadTooLarge { limit: 4, .. })
+        ));
+    }
+}
@@ -1,530 +1,1380 @@
 extern crate bitcoin;
-extern crate data_encoding;
 extern crate rand;
 
-use crate::common::type_address;
+use crate::common::{default_port, NetAddress};
+use crate::config::{HarvestConfig, ProxyConfig};
 use anyhow::{Context, Result};
 use rand::Rng;
 
-use std::convert::TryInto;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::Duration;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use self::bitcoin::consensus::{deserialize, serialize};
-use self::bitcoin::p2p::{address, message, message_network, ServiceFlags};
-use bitcoin::Network;
+use self::bitcoin::hashes::Hash;
+use self::bitcoin::p2p::{
+    address, message, message_blockdata, message_filter, message_network, ServiceFlags,
+};
+use bitcoin::{BlockHash, Network};
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpSocket, TcpStream};
 use tokio_socks::tcp::Socks5Stream;
 
-use sha3::{Digest, Sha3_256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+#[path = "archive.rs"]
+pub mod archive;
+#[path = "cfilters.rs"]
+pub mod cfilters;
+#[path = "chain.rs"]
+pub mod chain;
+#[path = "codec.rs"]
+pub mod codec;
+#[path = "v2.rs"]
+pub mod v2;
+use self::archive::ArchiveProbe;
+use self::chain::{ChainTracker, Probe};
+use self::codec::{CodecError, MessageStream};
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    NoVersion(String),
+    UnexpectedMessage {
+        phase: SessionPhase,
+        command: String,
+    },
+    PhaseTimeout(SessionPhase),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::NoVersion(command) => {
+                write!(f, "Se esperaba 'version' y se recibió '{}'", command)
+            }
+            ProtocolError::UnexpectedMessage { phase, command } => {
+                write!(f, "Mensaje '{}' inesperado en la fase '{}'", command, phase)
+            }
+            ProtocolError::PhaseTimeout(phase) => {
+                write!(f, "Timeout en la fase '{}'", phase)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttemptOutcome {
+    Refused,
+    Timeout,
+    Reset,
+    SocksError,
+    BadMagic,
+    NoVersion,
+    NoVerack,
+    Malformed,
+    HandshakeComplete,
+    Other,
+}
+
+impl AttemptOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttemptOutcome::Refused => "refused",
+            AttemptOutcome::Timeout => "timeout",
+            AttemptOutcome::Reset => "reset",
+            AttemptOutcome::SocksError => "socks_error",
+            AttemptOutcome::BadMagic => "bad_magic",
+            AttemptOutcome::NoVersion => "no_version",
+            AttemptOutcome::NoVerack => "no_verack",
+            AttemptOutcome::Malformed => "malformed",
+            AttemptOutcome::HandshakeComplete => "handshake_complete",
+            AttemptOutcome::Other => "other",
+        }
+    }
+}
+
+// Un intento cuenta como handshake completo si recibimos el 'verack', aunque la
+// conversación fallara después (p. ej. sin respuesta a 'getaddr'). Si el peer
+// envió 'version' pero no 'verack', el intento es 'no_verack'.
+pub fn classify_attempt(result: &Result<()>, reached: Option<SessionPhase>) -> AttemptOutcome {
+    if reached.is_some_and(|phase| phase.verack_received()) {
+        return AttemptOutcome::HandshakeComplete;
+    }
+    let error = match result {
+        Ok(()) => return AttemptOutcome::HandshakeComplete,
+        Err(e) => e,
+    };
+    if reached == Some(SessionPhase::AwaitVerack) {
+        return AttemptOutcome::NoVerack;
+    }
+
+    for cause in error.chain() {
+        if let Some(protocol_error) = cause.downcast_ref::<ProtocolError>() {
+            return match protocol_error {
+                ProtocolError::NoVersion(_) => AttemptOutcome::NoVersion,
+                ProtocolError::UnexpectedMessage { .. } => AttemptOutcome::Other,
+                ProtocolError::PhaseTimeout(_) => AttemptOutcome::Timeout,
+            };
+        }
+        if let Some(codec_error) = cause.downcast_ref::<CodecError>() {
+            match codec_error {
+                CodecError::BadMagic { .. } => return AttemptOutcome::BadMagic,
+                CodecError::InvalidCommand(_)
+                | CodecError::PayloadTooLarge { .. }
+                | CodecError::BadChecksum { .. }
+                | CodecError::Decode { .. }
+                | CodecError::Decrypt => return AttemptOutcome::Malformed,
+                // Sólo llega aquí si falló también la vuelta a v1.
+                CodecError::Handshake(_) => return AttemptOutcome::Other,
+                // Los errores de E/S se clasifican con su causa, más abajo.
+                CodecError::Io(_) => {}
+            }
+        }
+        if cause.downcast_ref::<tokio_socks::Error>().is_some() {
+            return AttemptOutcome::SocksError;
+        }
+        if cause
+            .downcast_ref::<tokio::time::error::Elapsed>()
+            .is_some()
+        {
+            return AttemptOutcome::Timeout;
+        }
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            match io_error.kind() {
+                std::io::ErrorKind::ConnectionRefused => return AttemptOutcome::Refused,
+                std::io::ErrorKind::TimedOut => return AttemptOutcome::Timeout,
+                std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe => return AttemptOutcome::Reset,
+                // El nodo aceptó la conexión pero la cerró sin enviar 'version'.
+                std::io::ErrorKind::UnexpectedEof => return AttemptOutcome::NoVersion,
+                _ => {}
+            }
+        }
+    }
+
+    AttemptOutcome::Other
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    AwaitVersion,
+    AwaitVerack,
+    AwaitHeaders,
+    AwaitCfCheckpt,
+    AwaitBlock,
+    AwaitAddr,
+    Done,
+}
+
+impl SessionPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SessionPhase::AwaitVersion => "await_version",
+            SessionPhase::AwaitVerack => "await_verack",
+            SessionPhase::AwaitHeaders => "await_headers",
+            SessionPhase::AwaitCfCheckpt => "await_cfcheckpt",
+            SessionPhase::AwaitBlock => "await_block",
+            SessionPhase::AwaitAddr => "await_addr",
+            SessionPhase::Done => "done",
+        }
+    }
+
+    // Fases posteriores al 'verack' del peer.
+    pub fn verack_received(&self) -> bool {
+        matches!(
+            self,
+            SessionPhase::AwaitHeaders
+                | SessionPhase::AwaitCfCheckpt
+                | SessionPhase::AwaitBlock
+                | SessionPhase::AwaitAddr
+                | SessionPhase::Done
+        )
+    }
+
+    fn after_handshake(&self) -> bool {
+        !matches!(
+            self,
+            SessionPhase::AwaitVersion | SessionPhase::AwaitVerack | SessionPhase::Done
+        )
+    }
+
+    // Tiempo máximo que puede durar cada fase. El de la conexión depende del
+    // transporte y lo fija cada conector.
+    fn timeout(&self) -> Duration {
+        match self {
+            SessionPhase::AwaitVersion => Duration::from_secs(20),
+            SessionPhase::AwaitVerack => Duration::from_secs(10),
+            SessionPhase::AwaitHeaders => Duration::from_secs(20),
+            SessionPhase::AwaitCfCheckpt => Duration::from_secs(20),
+            SessionPhase::AwaitBlock => Duration::from_secs(30),
+            SessionPhase::AwaitAddr => Duration::from_secs(30),
+            SessionPhase::Done => Duration::ZERO,
+        }
+    }
+}
+
+impl fmt::Display for SessionPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// BIP155 y BIP339: 'sendaddrv2' y 'wtxidrelay' sólo se envían a peers con
+// versión >= 70016 y siempre antes de nuestro 'verack'.
+const FEATURE_NEGOTIATION_VERSION: u32 = 70016;
+
+async fn send_verack<S>(
+    stream: &mut MessageStream<S>,
+    peer_version: u32,
+    address_str: &str,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut messages = Vec::with_capacity(3);
+    if peer_version >= FEATURE_NEGOTIATION_VERSION {
+        messages.push(message::NetworkMessage::WtxidRelay);
+        messages.push(message::NetworkMessage::SendAddrV2);
+    }
+    messages.push(message::NetworkMessage::Verack);
+
+    for msg in messages {
+        let cmd = msg.cmd();
+        stream
+            .write_message(msg)
+            .await
+            .context(format!("Fallo al enviar '{}' a {}", cmd, address_str))?;
+    }
+    Ok(())
+}
 
-async fn handle_stream<S>(
+// Estado de una conversación con un peer: opciones de recolección de entrada
+// y lo que se ha obtenido de él (handshake y direcciones) de salida.
+#[derive(Debug, Default)]
+pub struct Session {
+    pub harvest: Option<HarvestConfig>,
+    // Ronda extra de recolección: el nodo ya se contabilizó en la primera
+    // sesión, así que no se vuelven a guardar handshake ni capacidades.
+    pub reconnect: bool,
+    // Intentar primero el transporte v2 (el nodo anuncia P2P_V2).
+    pub try_v2: bool,
+    // Resultado del handshake v2, si se intentó.
+    pub v2_handshake: Option<bool>,
+    // Cadena de referencia con la que sondear la punta del peer.
+    pub chain: Option<Arc<ChainTracker>>,
+    pub chain_tip: Option<crate::db::ChainTip>,
+    // Verificación de filtros compactos, sólo cuando le toca al nodo. Usa la
+    // misma cadena de referencia para elegir la parada.
+    pub filters: bool,
+    // Nodos que deben haber respondido a la misma parada antes de decidir
+    // quién coincide con el consenso.
+    pub filter_consensus_min: i64,
+    pub filter_check: Option<crate::db::FilterCheck>,
+    // Verificación de archivo, sólo cuando le toca al nodo.
+    pub archive: Option<Arc<ArchiveProbe>>,
+    pub archive_check: Option<crate::db::ArchiveCheck>,
+    // Tiempos y desfase de reloj medidos en las sesiones salientes.
+    pub timings: crate::db::Timings,
+    pub handshake: Option<crate::db::VersionInfo>,
+    pub capabilities: crate::db::Capabilities,
+    pub addr_messages: u32,
+    pub addresses: HashSet<(NetAddress, u16)>,
+    // Última fase alcanzada por la sesión, si llegó a empezar.
+    pub reached: Option<SessionPhase>,
+}
+
+impl Session {
+    pub fn new(harvest: Option<HarvestConfig>) -> Self {
+        Session {
+            harvest,
+            ..Default::default()
+        }
+    }
+
+    // Los 'addr' se cuentan en cualquier fase, pero sólo en 'await_addr'
+    // deciden el final. Sin modo recolección la sesión termina con el primer
+    // 'addr'; con él se sigue escuchando hasta recibir una respuesta grande
+    // (la de 'getaddr').
+    fn after_addr(&mut self, phase: SessionPhase, count: usize) -> SessionPhase {
+        self.addr_messages += 1;
+        if phase != SessionPhase::AwaitAddr {
+            return phase;
+        }
+        match &self.harvest {
+            Some(harvest) if count < harvest.large_response => SessionPhase::AwaitAddr,
+            _ => SessionPhase::Done,
+        }
+    }
+}
+
+async fn run_session<S>(
     db: &crate::db::Database,
+    network: Network,
+    node_id: i64,
     address_str: String,
-    mut stream: S,
+    stream: MessageStream<S>,
+    session: &mut Session,
+    phase: SessionPhase,
 ) -> Result<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
-    loop {
-        let mut header_buf = [0u8; 24];
+    let mut reached = phase;
+    let result = drive_session(
+        db,
+        network,
+        node_id,
+        &address_str,
+        stream,
+        session,
+        &mut reached,
+    )
+    .await;
+
+    // Un fallo de v2 sólo cuenta si el nodo respondió después en v1; si no,
+    // no sabemos si el problema era el transporte o el propio nodo.
+    if reached != SessionPhase::AwaitVersion && !session.reconnect {
+        if let Some(success) = session.v2_handshake {
+            if let Err(e) = db.update_v2_handshake(node_id, success).await {
+                tracing::error!("Fallo de BBDD (v2) para {}: {}", address_str, e);
+            }
+        }
+    }
+
+    if let Some(check) = &session.archive_check {
+        if let Err(e) = db
+            .update_archive_check(node_id, check, archive::FAILURE_LIMIT)
+            .await
+        {
+            tracing::error!("Fallo de BBDD (archive) para {}: {}", address_str, e);
+        }
+    }
+
+    if let Some(check) = &session.filter_check {
+        if let Err(e) = db
+            .update_filter_check(network, node_id, check, session.filter_consensus_min)
+            .await
+        {
+            tracing::error!("Fallo de BBDD (cfilters) para {}: {}", address_str, e);
+        }
+    }
 
-        if let Err(e) = stream.read_exact(&mut header_buf).await {
-            tracing::warn!(target: "p2p", "Fallo al leer el header desde {}: {}. Conexión cerrada.", address_str, e);
-            return Err(anyhow::Error::from(e)
-                .context(format!("Fallo al leer header desde {}", address_str)));
+    if let Some(tip) = &session.chain_tip {
+        if let Err(e) = db.update_chain_tip(node_id, tip).await {
+            tracing::error!("Fallo de BBDD (chain tip) para {}: {}", address_str, e);
         }
+    }
 
-        let payload_size_bytes: [u8; 4] = header_buf[16..20]
-            .try_into()
-            .context("No se pudieron leer los bytes del tamaño del payload")?;
-        let payload_size = u32::from_le_bytes(payload_size_bytes) as usize;
-
-        if payload_size > 1_000_000 {
-            tracing::warn!(target: "p2p", "Payload demasiado grande ({}) recibido desde {}. Descartando.", payload_size, address_str);
-            return Err(anyhow::anyhow!(
-                "Payload demasiado grande ({}) desde {}",
-                payload_size,
-                address_str
-            ));
+    // Las capacidades se anuncian alrededor del 'verack', así que sólo se
+    // guardan si el peer llegó a enviarlo.
+    if reached.verack_received() && !session.reconnect {
+        if let Err(e) = db.update_capabilities(node_id, &session.capabilities).await {
+            tracing::error!("Fallo de BBDD (capabilities) para {}: {}", address_str, e);
         }
+    }
+
+    session.reached = Some(reached);
+    result
+}
+
+async fn request_addr<S>(stream: &mut MessageStream<S>) -> Result<SessionPhase>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_message(message::NetworkMessage::GetAddr)
+        .await
+        .context("Fallo al enviar 'getaddr'")?;
+    Ok(SessionPhase::AwaitAddr)
+}
+
+async fn request_headers<S>(stream: &mut MessageStream<S>, locator: Vec<BlockHash>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = message_blockdata::GetHeadersMessage::new(locator, BlockHash::all_zeros());
+    stream
+        .write_message(message::NetworkMessage::GetHeaders(request))
+        .await
+        .context("Fallo al enviar 'getheaders'")?;
+    Ok(())
+}
+
+// Nuestro 'ping' sale justo tras el 'verack' del peer; el RTT se mide al
+// recibir el 'pong' con el mismo nonce, sea cual sea la fase.
+async fn send_ping<S>(stream: &mut MessageStream<S>) -> Result<(u64, Instant)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let nonce: u64 = rand::random();
+    stream
+        .write_message(message::NetworkMessage::Ping(nonce))
+        .await
+        .context("Fallo al enviar 'ping'")?;
+    Ok((nonce, Instant::now()))
+}
+
+// Estado de los sondeos que siguen al de la punta.
+#[derive(Debug, Default)]
+struct Probes {
+    // Parada del 'getcfcheckpt' enviado.
+    filter_stop: Option<(u32, BlockHash)>,
+    // Momento en que se pidió el bloque de archivo, para medir la latencia.
+    block_requested: Option<Instant>,
+}
+
+// Tras el sondeo de punta viene, si le toca, el de filtros compactos. Sólo se
+// verifica a quien anuncia COMPACT_FILTERS y cuando la referencia ya llega a
+// la primera parada posible.
+async fn start_filter_probe<S>(
+    stream: &mut MessageStream<S>,
+    session: &Session,
+    probes: &mut Probes,
+) -> Result<SessionPhase>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let advertised = session
+        .handshake
+        .as_ref()
+        .is_some_and(|v| v.services.has(ServiceFlags::COMPACT_FILTERS));
+    let stop = match &session.chain {
+        Some(chain) if session.filters && advertised => cfilters::stop(chain),
+        _ => None,
+    };
+    let (_, stop_hash) = match stop {
+        Some(stop) => stop,
+        None => return start_archive_probe(stream, session, probes).await,
+    };
+    stream
+        .write_message(message::NetworkMessage::GetCFCheckpt(
+            message_filter::GetCFCheckpt {
+                filter_type: cfilters::BASIC_FILTER,
+                stop_hash,
+            },
+        ))
+        .await
+        .context("Fallo al enviar 'getcfcheckpt'")?;
+    probes.filter_stop = stop;
+    Ok(SessionPhase::AwaitCfCheckpt)
+}
+
+// Después, si le toca, el de archivo. Sólo se verifica a quien anuncia
+// NETWORK, y mientras la referencia no llegue al bloque 1 se deja para otra
+// sesión.
+async fn start_archive_probe<S>(
+    stream: &mut MessageStream<S>,
+    session: &Session,
+    probes: &mut Probes,
+) -> Result<SessionPhase>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let advertised = session
+        .handshake
+        .as_ref()
+        .is_some_and(|v| v.services.has(ServiceFlags::NETWORK));
+    let target = match &session.archive {
+        Some(probe) if advertised => probe.target(),
+        _ => None,
+    };
+    let hash = match target {
+        Some(hash) => hash,
+        None => return request_addr(stream).await,
+    };
+    request_block(stream, hash).await?;
+    probes.block_requested = Some(Instant::now());
+    Ok(SessionPhase::AwaitBlock)
+}
+
+async fn request_block<S>(stream: &mut MessageStream<S>, hash: BlockHash) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_message(message::NetworkMessage::GetData(vec![
+            message_blockdata::Inventory::Block(hash),
+        ]))
+        .await
+        .context("Fallo al enviar 'getdata'")?;
+    Ok(())
+}
 
-        let mut payload_buf = vec![0u8; payload_size];
-        if let Err(e) = stream.read_exact(&mut payload_buf).await {
-            tracing::warn!(target: "p2p", "Fallo al leer el payload desde {}: {}. Conexión cerrada.", address_str, e);
-            return Err(anyhow::Error::from(e)
-                .context(format!("Fallo al leer payload desde {}", address_str)));
+// Plazo al entrar en la fase `next`. En modo recolección 'await_addr' empieza
+// con el plazo de inactividad y fija además el límite absoluto.
+fn phase_deadline(
+    session: &Session,
+    next: SessionPhase,
+    harvest_end: &mut tokio::time::Instant,
+) -> tokio::time::Instant {
+    let now = tokio::time::Instant::now();
+    match &session.harvest {
+        Some(harvest) if next == SessionPhase::AwaitAddr => {
+            *harvest_end = now + harvest.max_duration;
+            now + harvest.idle_timeout
         }
+        _ => now + next.timeout(),
+    }
+}
 
-        let mut full_message_buf = Vec::with_capacity(24 + payload_size);
-        full_message_buf.extend_from_slice(&header_buf);
-        full_message_buf.extend_from_slice(&payload_buf);
+// Conduce la sesión fase a fase: 'version' -> 'verack' -> ['headers'] ->
+// ['cfcheckpt'] -> ['block'] -> 'addr'/'addrv2'.
+// Cada fase tiene su propio plazo y los mensajes que no tocan en la fase
+// actual se ignoran (salvo 'ping', que siempre se contesta). `reached` refleja
+// la última fase alcanzada, también cuando la sesión termina con error.
+async fn drive_session<S>(
+    db: &crate::db::Database,
+    network: Network,
+    node_id: i64,
+    address_str: &str,
+    mut stream: MessageStream<S>,
+    session: &mut Session,
+    reached: &mut SessionPhase,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut phase = *reached;
+    let mut deadline = tokio::time::Instant::now() + phase.timeout();
+    // Límite absoluto de la fase 'await_addr' en modo recolección.
+    let mut harvest_end = deadline;
+    // Sondeo de la punta de cadena: último localizador enviado, rama del peer
+    // pendiente de superar a la referencia, lotes pedidos y, si esta sesión
+    // está poniendo al día la referencia, su turno.
+    let chain = session.chain.clone();
+    let mut locator = chain::Locator::new();
+    let mut branch = None;
+    let mut batches = 0;
+    let mut catch_up = None;
+    let mut probes = Probes::default();
+    // Las medidas de tiempo sólo se toman en sesiones salientes, que empiezan
+    // justo después de enviar 'version'.
+    let outbound = phase == SessionPhase::AwaitVersion;
+    let started = Instant::now();
+    let mut ping_sent = None;
+
+    while phase != SessionPhase::Done {
+        let read = tokio::time::timeout_at(deadline, stream.read_message()).await;
+
+        // En modo recolección la sesión acaba cuando el peer deja de enviar
+        // direcciones (o cierra), y eso no es un fallo si ya recibimos alguna.
+        if phase == SessionPhase::AwaitAddr
+            && session.addr_messages > 0
+            && !matches!(read, Ok(Ok(_)))
+        {
+            tracing::debug!(target: "p2p", "Recolección terminada con {}: {} mensajes addr, {} direcciones", address_str, session.addr_messages, session.addresses.len());
+            break;
+        }
 
-        let reply: message::RawNetworkMessage = match deserialize(&full_message_buf) {
-            Ok(msg) => msg,
-            Err(e) => {
-                tracing::warn!(target: "p2p", "Mensaje inválido recibido de {}: {}. Ignorando.", address_str, e);
-                return Err(anyhow::Error::from(e)
-                    .context(format!("Mensaje inválido desde {}", address_str)));
+        // Los sondeos opcionales no cortan la sesión: los nodos en IBD ignoran
+        // 'getheaders', los que no tienen el índice de filtros al día ignoran
+        // 'getcfcheckpt' y los podados ignoran (o cortan) el 'getdata' de un
+        // bloque antiguo. Si el peer corta durante un sondeo ya no se le pueden
+        // pedir direcciones: la sesión acaba sin 'getaddr' pero cuenta como
+        // éxito, porque el handshake (y la punta, si se sondeó) se completaron.
+        let skip_to = match (phase, &read) {
+            (SessionPhase::AwaitHeaders, Err(_)) => {
+                tracing::debug!(target: "p2p", "{} no respondió a 'getheaders'", address_str);
+                session.chain_tip = Some(chain::no_response());
+                Some(start_filter_probe(&mut stream, session, &mut probes).await?)
+            }
+            (SessionPhase::AwaitCfCheckpt, Err(_)) => {
+                tracing::debug!(target: "p2p", "{} no respondió a 'getcfcheckpt'", address_str);
+                session.filter_check = Some(cfilters::not_served());
+                Some(start_archive_probe(&mut stream, session, &mut probes).await?)
+            }
+            (SessionPhase::AwaitCfCheckpt, Ok(Err(e))) => {
+                // Un corte no dice si el nodo sirve filtros: se deja la
+                // verificación anterior y se repite en la próxima sesión.
+                tracing::debug!(target: "p2p", "{} cortó la conexión al pedirle filtros: {}", address_str, e);
+                return Ok(());
             }
+            (SessionPhase::AwaitBlock, Err(_)) => {
+                tracing::debug!(target: "p2p", "{} no respondió a la petición del bloque de archivo", address_str);
+                session.archive_check = Some(archive::inconclusive());
+                Some(request_addr(&mut stream).await?)
+            }
+            (SessionPhase::AwaitBlock, Ok(Err(e))) => {
+                tracing::debug!(target: "p2p", "{} cortó la conexión al pedirle el bloque de archivo: {}", address_str, e);
+                session.archive_check = Some(archive::inconclusive());
+                return Ok(());
+            }
+            _ => None,
         };
+        if let Some(next) = skip_to {
+            deadline = phase_deadline(session, next, &mut harvest_end);
+            phase = next;
+            *reached = phase;
+            continue;
+        }
 
-        tracing::debug!(target: "p2p", "Recibido mensaje '{}' de {}", reply.payload().cmd(), address_str);
-
-        match reply.payload() {
-            message::NetworkMessage::Version(x) => {
-                let soft = &x.user_agent;
-                let services = &x.services.to_string();
-                let protocol_version = x.version as i32;
-                let start_height = x.start_height;
-                let relay = x.relay;
+        let msg = match read {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(e)) => {
+                tracing::warn!(target: "p2p", "Fallo al leer mensaje desde {} ({}): {}. Conexión cerrada.", address_str, phase, e);
+                return Err(anyhow::Error::from(e).context(format!(
+                    "Fallo al leer mensaje desde {} en la fase '{}'",
+                    address_str, phase
+                )));
+            }
 
-                if let Err(e) = db.handle_successful_connection(&address_str).await {
-                    tracing::error!("Fallo de BBDD (handle_success) para {}: {}", address_str, e);
-                }
+            Err(_) => {
+                return Err(anyhow::Error::from(ProtocolError::PhaseTimeout(phase))
+                    .context(format!("Sesión con {} agotada", address_str)));
+            }
+        };
 
-                if let Err(e) = db
-                    .update_handshake_info(
-                        &address_str,
-                        soft,
-                        services,
-                        protocol_version,
-                        start_height,
-                        relay,
-                    )
-                    .await
-                {
-                    tracing::error!("Fallo de BBDD (handshake_info) para {}: {}", address_str, e);
+        tracing::debug!(target: "p2p", "Recibido mensaje '{}' de {} ({})", msg.cmd(), address_str, phase);
+
+        let addr_messages = session.addr_messages;
+        let next = match (phase, msg) {
+            (SessionPhase::AwaitVersion, message::NetworkMessage::Version(x)) => {
+                let version = version_info(&x);
+                session.handshake = Some(version.clone());
+                session.timings.clock_offset_s = clock_offset(x.timestamp);
+
+                if !session.reconnect {
+                    if let Err(e) = db.update_handshake_info(node_id, &version).await {
+                        tracing::error!(
+                            "Fallo de BBDD (handshake_info) para {}: {}",
+                            address_str,
+                            e
+                        );
+                    }
                 }
 
-                let sendaddrv2_message = message::RawNetworkMessage::new(
-                    Network::Bitcoin.magic(),
-                    message::NetworkMessage::SendAddrV2,
-                );
-                stream
-                    .write_all(serialize(&sendaddrv2_message).as_slice())
-                    .await
-                    .context("Fallo al enviar 'sendaddrv2'")?;
-
-                let second_message = message::RawNetworkMessage::new(
-                    Network::Bitcoin.magic(),
-                    message::NetworkMessage::Verack,
-                );
-                stream
-                    .write_all(serialize(&second_message).as_slice())
-                    .await
-                    .context("Fallo al enviar 'verack'")?;
+                send_verack(&mut stream, x.version, address_str).await?;
+                SessionPhase::AwaitVerack
             }
-            message::NetworkMessage::Verack => {
-                tracing::info!(target: "p2p", "Handshake completado con {}", address_str);
-                let getaddr_message = message::RawNetworkMessage::new(
-                    Network::Bitcoin.magic(),
-                    message::NetworkMessage::GetAddr,
+            (SessionPhase::AwaitVersion, other) => {
+                return Err(
+                    anyhow::Error::from(ProtocolError::NoVersion(other.cmd().to_string()))
+                        .context(format!("Handshake inválido con {}", address_str)),
                 );
-                stream
-                    .write_all(serialize(&getaddr_message).as_slice())
-                    .await
-                    .context("Fallo al enviar 'getaddr'")?;
             }
-            message::NetworkMessage::Ping(nonce) => {
-                let pong_message = message::RawNetworkMessage::new(
-                    Network::Bitcoin.magic(),
-                    message::NetworkMessage::Pong(*nonce),
-                );
+            (_, message::NetworkMessage::Version(_)) => {
+                return Err(anyhow::Error::from(ProtocolError::UnexpectedMessage {
+                    phase,
+                    command: "version".to_string(),
+                })
+                .context(format!("Handshake inválido con {}", address_str)));
+            }
+            (_, message::NetworkMessage::Ping(nonce)) => {
                 stream
-                    .write_all(serialize(&pong_message).as_slice())
+                    .write_message(message::NetworkMessage::Pong(nonce))
                     .await
                     .context("Fallo al enviar 'pong'")?;
+                phase
             }
-            message::NetworkMessage::Addr(ref x) => {
-                tracing::info!(target: "p2p", "Recibido mensaje Addr con {} direcciones de {}", x.len(), address_str);
-                for addr in x {
-                    let type_addr = type_address(addr.1.address);
-                    let services_str = addr.1.services.to_string();
-                    if let Err(e) = db
-                        .upsert_addrv2_node(
-                            &type_addr.address_type,
-                            &type_addr.address,
-                            addr.1.port,
-                            &services_str,
-                        )
-                        .await
-                    {
-                        tracing::error!("Fallo al insertar nodo Addr {}: {}", type_addr.address, e);
+            (_, message::NetworkMessage::Pong(nonce))
+                if ping_sent.is_some_and(|(sent, _)| sent == nonce) =>
+            {
+                session.timings.ping_ms = ping_sent.take().map(|(_, at)| elapsed_ms(at));
+                phase
+            }
+            (_, message::NetworkMessage::SendAddrV2) => {
+                session.capabilities.sendaddrv2 = true;
+                phase
+            }
+            (_, message::NetworkMessage::WtxidRelay) => {
+                session.capabilities.wtxidrelay = true;
+                phase
+            }
+            (_, message::NetworkMessage::SendHeaders) => {
+                session.capabilities.sendheaders = true;
+                phase
+            }
+            (_, message::NetworkMessage::SendCmpct(x)) => {
+                // Los nodos antiguos anuncian varias versiones; nos quedamos con la mayor.
+                let version = x.version as i64;
+                session.capabilities.sendcmpct_version = Some(
+                    session
+                        .capabilities
+                        .sendcmpct_version
+                        .map_or(version, |v| v.max(version)),
+                );
+                phase
+            }
+            (_, message::NetworkMessage::FeeFilter(rate)) => {
+                session.capabilities.feefilter = Some(rate);
+                phase
+            }
+            (SessionPhase::AwaitVerack, message::NetworkMessage::Verack) => {
+                tracing::info!(target: "p2p", "Handshake completado con {}", address_str);
+                if outbound {
+                    session.timings.handshake_ms = Some(elapsed_ms(started));
+                    if !session.reconnect {
+                        if let Err(e) = db.record_handshake(node_id).await {
+                            tracing::error!(
+                                "Fallo de BBDD (handshake) para {}: {}",
+                                address_str,
+                                e
+                            );
+                        }
                     }
+                    ping_sent = Some(send_ping(&mut stream).await?);
+                }
+                match &chain {
+                    Some(chain) => {
+                        locator = chain.locator();
+                        request_headers(&mut stream, chain::hashes(&locator)).await?;
+                        SessionPhase::AwaitHeaders
+                    }
+                    None => start_filter_probe(&mut stream, session, &mut probes).await?,
                 }
-                break;
             }
-            message::NetworkMessage::AddrV2(ref addrv2_messages) => {
-                tracing::info!(target: "p2p", "Recibido AddrV2 ({} nodos) de {}", addrv2_messages.len(), address_str);
-
-                let messages_to_process: Vec<_> = addrv2_messages.iter().cloned().collect();
-                let db_clone = db.clone();
-
-                tokio::spawn(async move {
-                    let mut nodes_to_insert: HashMap<String, crate::db::DiscoveredNode> =
-                        HashMap::with_capacity(messages_to_process.len());
+            (SessionPhase::AwaitHeaders, message::NetworkMessage::Headers(headers)) => {
+                let start_height = session.handshake.as_ref().map(|v| v.start_height);
+                let probe = match &chain {
+                    Some(chain) => chain.classify(&locator, &headers, start_height, &mut branch),
+                    None => Err("sesión sin cadena de referencia".to_string()),
+                };
+                let next = match probe {
+                    Ok(Probe::Tip(tip)) => {
+                        tracing::debug!(target: "p2p", "Punta de {}: {} ({:?})", address_str, tip.status, tip.height);
+                        session.chain_tip = Some(tip);
+                        start_filter_probe(&mut stream, session, &mut probes).await?
+                    }
+                    Ok(Probe::More(next)) => {
+                        // La referencia va por detrás del peer: una sola sesión
+                        // a la vez sigue pidiendo lotes hasta alcanzarlo.
+                        if catch_up.is_none() {
+                            catch_up = chain.as_ref().and_then(|chain| chain.try_catch_up());
+                        }
+                        batches += 1;
+                        if catch_up.is_some() && batches < chain::MAX_CATCH_UP_BATCHES {
+                            locator = next;
+                            request_headers(&mut stream, chain::hashes(&locator)).await?;
+                            deadline = tokio::time::Instant::now() + phase.timeout();
+                            phase
+                        } else {
+                            start_filter_probe(&mut stream, session, &mut probes).await?
+                        }
+                    }
+                    Err(reason) => {
+                        tracing::debug!(target: "p2p", "Respuesta 'headers' de {} descartada: {}", address_str, reason);
+                        start_filter_probe(&mut stream, session, &mut probes).await?
+                    }
+                };
+                if next != SessionPhase::AwaitHeaders {
+                    catch_up = None;
+                }
+                next
+            }
+            (SessionPhase::AwaitCfCheckpt, message::NetworkMessage::CFCheckpt(x)) => {
+                let check = match probes.filter_stop {
+                    Some(stop) => cfilters::check(stop, &x),
+                    None => cfilters::not_served(),
+                };
+                tracing::debug!(target: "p2p", "Checkpoints de filtros de {}: válidos={} (parada {:?})", address_str, check.served, check.stop_height);
+                session.filter_check = Some(check);
+                start_archive_probe(&mut stream, session, &mut probes).await?
+            }
+            (SessionPhase::AwaitBlock, message::NetworkMessage::Block(block)) => {
+                let served = session
+                    .archive
+                    .as_ref()
+                    .is_some_and(|probe| probe.verify(&block));
+                let latency_ms = probes.block_requested.map(elapsed_ms);
+                tracing::debug!(target: "p2p", "Bloque de archivo de {}: válido={} ({:?} ms)", address_str, served, latency_ms);
+                session.archive_check = Some(crate::db::ArchiveCheck {
+                    served: Some(served),
+                    latency_ms,
+                });
+                request_addr(&mut stream).await?
+            }
+            (SessionPhase::AwaitBlock, message::NetworkMessage::NotFound(_)) => {
+                session.archive_check = Some(archive::not_served());
+                request_addr(&mut stream).await?
+            }
+            // Un peer puede mandar 'addr' sin pedírselo mientras esperamos
+            // otra respuesta; se guardan igual.
+            (current, message::NetworkMessage::Addr(x)) if current.after_handshake() => {
+                tracing::info!(target: "p2p", "Recibido mensaje Addr con {} direcciones de {}", x.len(), address_str);
+                session.addresses.extend(
+                    x.iter()
+                        .map(|(_, addr)| (NetAddress::from_legacy(addr.address), addr.port)),
+                );
+                store_addr(db, network, node_id, &x).await;
+                session.after_addr(phase, x.len())
+            }
+            (current, message::NetworkMessage::AddrV2(x)) if current.after_handshake() => {
+                tracing::info!(target: "p2p", "Recibido AddrV2 ({} nodos) de {}", x.len(), address_str);
+                session.addresses.extend(x.iter().filter_map(|entry| {
+                    NetAddress::from_addrv2(&entry.addr)
+                        .ok()
+                        .map(|address| (address, entry.port))
+                }));
+                let count = x.len();
+                spawn_store_addrv2(db, network, node_id, x);
+                session.after_addr(phase, count)
+            }
+            (_, other) => {
+                tracing::debug!(target: "p2p", "Ignorando '{}' de {} en la fase '{}'", other.cmd(), address_str, phase);
+                phase
+            }
+        };
 
-                    let now = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards")
-                        .as_secs() as u32;
+        match &session.harvest {
+            _ if next != phase => deadline = phase_deadline(session, next, &mut harvest_end),
+            Some(harvest)
+                if next == SessionPhase::AwaitAddr && session.addr_messages > addr_messages =>
+            {
+                let now = tokio::time::Instant::now();
+                deadline = (now + harvest.idle_timeout).min(harvest_end);
+            }
+            _ => {}
+        }
+        phase = next;
+        *reached = phase;
+    }
 
-                    const FORTY_EIGHT_HOURS: u32 = 48 * 60 * 60;
-                    const TEN_MINUTES: u32 = 10 * 60;
-                    const MAX_FUTURE: u32 = 60 * 60;
+    Ok(())
+}
 
-                    let mut filtered_count = 0;
-                    let mut future_count = 0;
-                    let mut attack_count = 0;
+// Marca de tiempo anunciada en 'addr', recortada a ahora si viene del futuro.
+fn gossip_time(time: u32, now: u32) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(time.min(now) as i64, 0).unwrap_or_default()
+}
 
-                    for entry in messages_to_process {
-                        let node_time = entry.time;
+async fn store_addr(
+    db: &crate::db::Database,
+    network: Network,
+    announcer_id: i64,
+    x: &[(u32, address::Address)],
+) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as u32;
+    for (time, addr) in x {
+        let net_address = NetAddress::from_legacy(addr.address);
+        let announcement = crate::db::Announcement {
+            announcer_id,
+            gossip_time: gossip_time(*time, now),
+        };
+        if let Err(e) = db
+            .upsert_addrv2_node(
+                network,
+                &net_address,
+                addr.port,
+                Some(addr.services.into()),
+                Some(announcement),
+            )
+            .await
+        {
+            tracing::error!("Fallo al insertar nodo Addr {}: {}", net_address, e);
+        }
+    }
+}
 
-                        if node_time > now + MAX_FUTURE {
-                            tracing::debug!(target: "p2p", 
-                                "Descartando nodo con timestamp muy lejano en el futuro: {} (ahora: {})", 
-                                node_time, now);
-                            attack_count += 1;
-                            continue;
-                        }
+fn spawn_store_addrv2(
+    db: &crate::db::Database,
+    network: Network,
+    announcer_id: i64,
+    addrv2_messages: Vec<address::AddrV2Message>,
+) {
+    let db_clone = db.clone();
+
+    tokio::spawn(async move {
+        let mut nodes_to_insert: HashMap<(NetAddress, u16), crate::db::DiscoveredNode> =
+            HashMap::with_capacity(addrv2_messages.len());
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs() as u32;
+
+        const FORTY_EIGHT_HOURS: u32 = 48 * 60 * 60;
+        const TEN_MINUTES: u32 = 10 * 60;
+        const MAX_FUTURE: u32 = 60 * 60;
+
+        let mut filtered_count = 0;
+        let mut future_count = 0;
+        let mut attack_count = 0;
+
+        for entry in addrv2_messages {
+            let node_time = entry.time;
+
+            if node_time > now + MAX_FUTURE {
+                tracing::debug!(target: "p2p", 
+                    "Descartando nodo con timestamp muy lejano en el futuro: {} (ahora: {})", 
+                    node_time, now);
+                attack_count += 1;
+                continue;
+            }
 
-                        let effective_time = if node_time > now && node_time <= now + TEN_MINUTES {
-                            future_count += 1;
-                            now
-                        } else {
-                            node_time
-                        };
+            let effective_time = if node_time > now && node_time <= now + TEN_MINUTES {
+                future_count += 1;
+                now
+            } else {
+                node_time
+            };
 
-                        if now.saturating_sub(effective_time) > FORTY_EIGHT_HOURS {
-                            filtered_count += 1;
-                            continue;
-                        }
+            if now.saturating_sub(effective_time) > FORTY_EIGHT_HOURS {
+                filtered_count += 1;
+                continue;
+            }
 
-                        let (addr_type, addr_str) = match &entry.addr {
-                            bitcoin::p2p::address::AddrV2::Ipv4(addr) => {
-                                ("ipv4".to_string(), addr.to_string())
-                            }
-                            bitcoin::p2p::address::AddrV2::Ipv6(addr) => {
-                                ("ipv6".to_string(), addr.to_string())
-                            }
-                            bitcoin::p2p::address::AddrV2::TorV2(bytes) => {
-                                let mut spec = data_encoding::BASE32.specification();
-                                spec.padding = None;
-                                match spec.encoding() {
-                                    Ok(encoding) => {
-                                        let addr = encoding.encode(bytes).to_lowercase() + ".onion";
-                                        ("onionv2".to_string(), addr)
-                                    }
-                                    Err(e) => {
-                                        tracing::error!(
-                                            "Fallo al crear encoder Base32 para TorV2: {}",
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                }
-                            }
-                            bitcoin::p2p::address::AddrV2::TorV3(bytes) => {
-                                let addr_str = match build_torv3_address(bytes) {
-                                    Ok(addr) => addr,
-                                    Err(e) => {
-                                        tracing::error!(
-                                            "Fallo al construir la dirección TorV3: {}",
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                };
-                                ("onionv3".to_string(), addr_str)
-                            }
-                            bitcoin::p2p::address::AddrV2::I2p(bytes) => {
-                                let mut spec = data_encoding::BASE32.specification();
-                                spec.padding = None;
-                                match spec.encoding() {
-                                    Ok(encoding) => {
-                                        let addr =
-                                            encoding.encode(bytes).to_lowercase() + ".b32.i2p";
-                                        ("i2p".to_string(), addr)
-                                    }
-                                    Err(e) => {
-                                        tracing::error!(
-                                            "Fallo al crear encoder Base32 para I2P: {}",
-                                            e
-                                        );
-                                        continue;
-                                    }
-                                }
-                            }
-                            bitcoin::p2p::address::AddrV2::Cjdns(addr_bytes) => {
-                                let ipv6_addr = std::net::Ipv6Addr::from(*addr_bytes);
-                                ("cjdns".to_string(), ipv6_addr.to_string())
-                            }
-                            bitcoin::p2p::address::AddrV2::Unknown(network_id, bytes) => {
-                                if *network_id == 0x07 && bytes.len() == 16 {
-                                    match <&[u8] as TryInto<[u8; 16]>>::try_into(bytes.as_slice()) {
-                                        Ok(addr_bytes_16) => {
-                                            let ipv6_addr = std::net::Ipv6Addr::from(addr_bytes_16);
-                                            ("yggdrasil".to_string(), ipv6_addr.to_string())
-                                        }
-                                        Err(_) => {
-                                            tracing::warn!("Recibido tipo Unknown (0x07) pero no son 16 bytes válidos.");
-                                            continue;
-                                        }
-                                    }
-                                } else {
-                                    tracing::debug!("Recibido tipo de dirección AddrV2 desconocido o no soportado: ID={}, Longitud={}", network_id, bytes.len());
-                                    continue;
-                                }
-                            }
-                        };
+            let net_address = match NetAddress::from_addrv2(&entry.addr) {
+                Ok(net_address) => net_address,
+                Err(e) => {
+                    tracing::debug!("{}", e);
+                    continue;
+                }
+            };
 
-                        let mut port_to_store = entry.port;
-                        if port_to_store == 0 && (addr_type == "i2p" || addr_type == "onionv2") {
-                            port_to_store = 8333;
-                        }
+            let mut port_to_store = entry.port;
+            if port_to_store == 0
+                && matches!(net_address, NetAddress::I2p(_) | NetAddress::TorV2(_))
+            {
+                port_to_store = default_port(network);
+            }
 
-                        let node_to_store = crate::db::DiscoveredNode {
-                            addr_type,
-                            addr_str: addr_str.clone(),
-                            port: port_to_store,
-                            services: entry.services.to_string(),
-                        };
+            let node_to_store = crate::db::DiscoveredNode {
+                address: net_address.clone(),
+                port: port_to_store,
+                services: entry.services.into(),
+                gossip_time: gossip_time(effective_time, now),
+            };
 
-                        nodes_to_insert.insert(addr_str, node_to_store);
+            nodes_to_insert.insert((net_address, port_to_store), node_to_store);
 
-                        if filtered_count > 0 || future_count > 0 || attack_count > 0 {
-                            tracing::info!(target: "p2p", 
-                            "AddrV2 filtrado: {} nodos aceptados, {} filtrados (>48h), {} normalizados (futuro cercano), {} rechazados (ataque/error)",
-                            nodes_to_insert.len(), filtered_count, future_count, attack_count);
-                        }
-                    }
+            if filtered_count > 0 || future_count > 0 || attack_count > 0 {
+                tracing::info!(target: "p2p", 
+                "AddrV2 filtrado: {} nodos aceptados, {} filtrados (>48h), {} normalizados (futuro cercano), {} rechazados (ataque/error)",
+                nodes_to_insert.len(), filtered_count, future_count, attack_count);
+            }
+        }
 
-                    if !nodes_to_insert.is_empty() {
-                        let nodes_vec: Vec<_> = nodes_to_insert.into_values().collect();
-                        let chunk_size = 50;
-
-                        for chunk in nodes_vec.chunks(chunk_size) {
-                            let max_retries = 3;
-                            let mut attempts = 0;
-                            loop {
-                                attempts += 1;
-                                match db_clone.batch_upsert_addrv2_nodes(chunk).await {
-                                    Ok(_) => break,
-                                    Err(e) => {
-                                        if let Some(db_err) = e.downcast_ref::<sqlx::Error>() {
-                                            if let sqlx::Error::Database(db_err_info) = db_err {
-                                                if db_err_info
-                                                    .code()
-                                                    .map_or(false, |code| code == "40P01")
-                                                {
-                                                    if attempts < max_retries {
-                                                        tracing::warn!(target: "p2p", "Deadlock detectado en chunk upsert (intento {}/{}). Reintentando...", attempts, max_retries);
-                                                        let delay =
-                                                            tokio::time::Duration::from_millis(
-                                                                rand::rng().random_range(100..=600),
-                                                            );
-                                                        tokio::time::sleep(delay).await;
-                                                        continue;
-                                                    } else {
-                                                        tracing::error!("Fallo en chunk upsert tras {} intentos (deadlock): {}", attempts, e);
-                                                        break;
-                                                    }
-                                                }
-                                            }
+        if !nodes_to_insert.is_empty() {
+            let nodes_vec: Vec<_> = nodes_to_insert.into_values().collect();
+            let chunk_size = 50;
+
+            for chunk in nodes_vec.chunks(chunk_size) {
+                let max_retries = 3;
+                let mut attempts = 0;
+                loop {
+                    attempts += 1;
+                    match db_clone
+                        .batch_upsert_addrv2_nodes(network, announcer_id, chunk)
+                        .await
+                    {
+                        Ok(_) => break,
+                        Err(e) => {
+                            if let Some(db_err) = e.downcast_ref::<sqlx::Error>() {
+                                if let sqlx::Error::Database(db_err_info) = db_err {
+                                    if db_err_info.code().map_or(false, |code| code == "40P01") {
+                                        if attempts < max_retries {
+                                            tracing::warn!(target: "p2p", "Deadlock detectado en chunk upsert (intento {}/{}). Reintentando...", attempts, max_retries);
+                                            let delay = tokio::time::Duration::from_millis(
+                                                rand::rng().random_range(100..=600),
+                                            );
+                                            tokio::time::sleep(delay).await;
+                                            continue;
+                                        } else {
+                                            tracing::error!("Fallo en chunk upsert tras {} intentos (deadlock): {}", attempts, e);
+                                            break;
                                         }
-                                        tracing::error!("Fallo irrecuperable en chunk upsert (tarea AddrV2): {}", e);
-                                        break;
                                     }
                                 }
                             }
+                            tracing::error!(
+                                "Fallo irrecuperable en chunk upsert (tarea AddrV2): {}",
+                                e
+                            );
+                            break;
                         }
                     }
-                });
+                }
+            }
+        }
+    });
+}
 
-                break;
+// Tiempo máximo para completar el handshake v2 (claves, basura y paquete de
+// versión) antes de volver a v1.
+const V2_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Abre el transporte con el peer. Si la sesión lo pide se intenta primero el
+// handshake BIP324; un nodo sin v2 suele cerrar la conexión, así que en caso de
+// fallo se reconecta y se habla v1. El resultado queda en la sesión.
+async fn open_transport<S, F, Fut>(
+    connect: F,
+    network: Network,
+    address_str: &str,
+    session: &mut Session,
+) -> Result<MessageStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<S>>,
+{
+    if session.try_v2 {
+        let started = Instant::now();
+        let stream = connect().await?;
+        session.timings.connect_ms = Some(elapsed_ms(started));
+        match tokio::time::timeout(
+            V2_HANDSHAKE_TIMEOUT,
+            MessageStream::handshake_v2(stream, network),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => {
+                session.v2_handshake = Some(true);
+                return Ok(stream);
+            }
+            Ok(Err(e)) => {
+                tracing::debug!(target: "p2p", "Handshake v2 con {} fallido, se reintenta en v1: {}", address_str, e);
+            }
+            Err(_) => {
+                tracing::debug!(target: "p2p", "Timeout en el handshake v2 con {}, se reintenta en v1", address_str);
             }
-            _ => {}
         }
+        session.v2_handshake = Some(false);
     }
 
-    Ok(())
+    let started = Instant::now();
+    let stream = connect().await?;
+    session.timings.connect_ms = Some(elapsed_ms(started));
+    Ok(MessageStream::new(stream, network))
 }
 
-pub async fn converse(db: &crate::db::Database, address: SocketAddr) -> Result<()> {
-    let conversation_timeout = Duration::from_secs(60);
+fn elapsed_ms(since: Instant) -> i32 {
+    since.elapsed().as_millis() as i32
+}
 
-    let task = async move {
-        let connect_timeout = Duration::from_secs(5);
+async fn connect_tcp(address: SocketAddr) -> Result<TcpStream> {
+    let connect_timeout = Duration::from_secs(5);
 
-        let connect_future = TcpStream::connect(&address);
-        let mut stream = tokio::time::timeout(connect_timeout, connect_future)
-            .await
-            .context(format!("Timeout al conectar con {}", address))?
-            .context(format!("Fallo al conectar con {}", address))?;
+    let connect_future = TcpStream::connect(&address);
+    let stream = tokio::time::timeout(connect_timeout, connect_future)
+        .await
+        .context(format!("Timeout al conectar con {}", address))?
+        .context(format!("Fallo al conectar con {}", address))?;
+    Ok(stream)
+}
 
-        let version_message = build_version_message(address)?;
-        let first_message =
-            message::RawNetworkMessage::new(Network::Bitcoin.magic(), version_message);
-        stream
-            .write_all(serialize(&first_message).as_slice())
-            .await
-            .context("Fallo al enviar el mensaje 'version'")?;
+async fn connect_from(address: SocketAddr, local_addr: Ipv6Addr) -> Result<TcpStream> {
+    let connect_timeout = Duration::from_secs(10);
+
+    let socket = TcpSocket::new_v6().context("Fallo al crear el socket IPv6")?;
+    socket
+        .bind(SocketAddr::new(IpAddr::V6(local_addr), 0))
+        .context(format!("Fallo al enlazar el socket a {}", local_addr))?;
+    let stream = tokio::time::timeout(connect_timeout, socket.connect(address))
+        .await
+        .context(format!(
+            "Timeout al conectar con {} vía {}",
+            address, local_addr
+        ))?
+        .context(format!(
+            "Fallo al conectar con {} vía {}",
+            address, local_addr
+        ))?;
+    Ok(stream)
+}
 
-        tracing::info!(target: "p2p", "Enviado mensaje 'version' a {}. Esperando respuesta...", address);
+async fn connect_proxied(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+    overlay: &str,
+) -> Result<Socks5Stream<TcpStream>> {
+    let connect_timeout = Duration::from_secs(30);
+
+    let connect_future = connect_socks(proxy, host, port);
+    let stream = tokio::time::timeout(connect_timeout, connect_future)
+        .await
+        .context(format!(
+            "Timeout al conectar a {}:{} vía {}",
+            host, port, overlay
+        ))?
+        .context(format!(
+            "Fallo al conectar a {}:{} vía {}",
+            host, port, overlay
+        ))?;
+    Ok(stream)
+}
 
-        handle_stream(db, address.to_string(), stream).await
-    };
+pub async fn converse(
+    db: &crate::db::Database,
+    network: Network,
+    node_id: i64,
+    address: SocketAddr,
+    session: &mut Session,
+) -> Result<()> {
+    let address_str = address.to_string();
+    let mut stream =
+        open_transport(|| connect_tcp(address), network, &address_str, session).await?;
+
+    let version_message = build_version_message(network, address)?;
+    stream
+        .write_message(version_message)
+        .await
+        .context("Fallo al enviar el mensaje 'version'")?;
+
+    tracing::info!(target: "p2p", "Enviado mensaje 'version' a {} ({}). Esperando respuesta...", address, stream.transport());
+
+    run_session(
+        db,
+        network,
+        node_id,
+        address_str,
+        stream,
+        session,
+        SessionPhase::AwaitVersion,
+    )
+    .await
+}
 
-    match tokio::time::timeout(conversation_timeout, task).await {
-        Ok(result) => result,
-        Err(_) => Err(anyhow::anyhow!(
-            "Timeout global de la conversación con {}",
-            address
-        )),
-    }
+pub async fn converse_overlay(
+    db: &crate::db::Database,
+    network: Network,
+    node_id: i64,
+    address: SocketAddr,
+    local_addr: Ipv6Addr,
+    session: &mut Session,
+) -> Result<()> {
+    let address_str = address.to_string();
+    let mut stream = open_transport(
+        || connect_from(address, local_addr),
+        network,
+        &address_str,
+        session,
+    )
+    .await?;
+
+    let version_message = build_version_message(network, address)?;
+    stream
+        .write_message(version_message)
+        .await
+        .context("Fallo al enviar el mensaje 'version'")?;
+
+    tracing::info!(target: "p2p", "Enviado mensaje 'version' a {} (vía {}, {}). Esperando respuesta...", address, local_addr, stream.transport());
+
+    run_session(
+        db,
+        network,
+        node_id,
+        address_str,
+        stream,
+        session,
+        SessionPhase::AwaitVersion,
+    )
+    .await
 }
 
-pub async fn converse_tor(db: &crate::db::Database, onion_address: &str, port: u16) -> Result<()> {
-    let proxy_addr = "127.0.0.1:9050";
-    let conversation_timeout = Duration::from_secs(60);
+pub async fn converse_tor(
+    db: &crate::db::Database,
+    network: Network,
+    proxy: &ProxyConfig,
+    node_id: i64,
+    onion_address: &str,
+    port: u16,
+    session: &mut Session,
+) -> Result<()> {
     let full_address = format!("{}:{}", onion_address, port);
-    let full_address_clone = full_address.clone();
-
-    let task = async move {
-        let connect_timeout = Duration::from_secs(30);
-
-        let connect_future = Socks5Stream::connect(proxy_addr, (onion_address, port));
-        let mut stream = tokio::time::timeout(connect_timeout, connect_future)
-            .await
-            .context(format!("Timeout al conectar a {} vía Tor", full_address))?
-            .context(format!("Fallo al conectar a {} vía Tor", full_address))?;
-
-        let dummy_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let version_message = build_version_message(dummy_addr)?;
-        let first_message =
-            message::RawNetworkMessage::new(Network::Bitcoin.magic(), version_message);
-        stream
-            .write_all(serialize(&first_message).as_slice())
-            .await
-            .context(format!("Fallo al enviar 'version' a {}", full_address))?;
-        handle_stream(db, full_address.clone(), stream).await
-    };
-
-    match tokio::time::timeout(conversation_timeout, task).await {
-        Ok(result) => result,
-        Err(_) => Err(anyhow::anyhow!(
-            "Timeout global de la conversación con {}",
-            full_address_clone
-        )),
-    }
+    let mut stream = open_transport(
+        || connect_proxied(proxy, onion_address, port, "Tor"),
+        network,
+        &full_address,
+        session,
+    )
+    .await?;
+
+    let dummy_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    let version_message = build_version_message(network, dummy_addr)?;
+    stream
+        .write_message(version_message)
+        .await
+        .context(format!("Fallo al enviar 'version' a {}", full_address))?;
+    run_session(
+        db,
+        network,
+        node_id,
+        full_address,
+        stream,
+        session,
+        SessionPhase::AwaitVersion,
+    )
+    .await
 }
 
-pub async fn converse_i2p(db: &crate::db::Database, onion_address: &str, port: u16) -> Result<()> {
-    let proxy_addr = "127.0.0.1:4446";
-    let conversation_timeout = Duration::from_secs(60);
+pub async fn converse_i2p(
+    db: &crate::db::Database,
+    network: Network,
+    proxy: &ProxyConfig,
+    node_id: i64,
+    onion_address: &str,
+    port: u16,
+    session: &mut Session,
+) -> Result<()> {
     let full_address = format!("{}:{}", onion_address, port);
-    let full_address_clone = full_address.clone();
-
-    let task = async move {
-        let connect_timeout = Duration::from_secs(30);
+    let mut stream = open_transport(
+        || connect_proxied(proxy, onion_address, port, "I2P"),
+        network,
+        &full_address,
+        session,
+    )
+    .await?;
+
+    let dummy_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
+    let version_message = build_version_message(network, dummy_addr)?;
+    stream
+        .write_message(version_message)
+        .await
+        .context(format!("Fallo al enviar 'version' a {}", full_address))?;
+    run_session(
+        db,
+        network,
+        node_id,
+        full_address,
+        stream,
+        session,
+        SessionPhase::AwaitVersion,
+    )
+    .await
+}
 
-        let connect_future = Socks5Stream::connect(proxy_addr, (onion_address, port));
-        let mut stream = tokio::time::timeout(connect_timeout, connect_future)
-            .await
-            .context(format!("Timeout al conectar a {} vía I2P", full_address))?
-            .context(format!("Fallo al conectar a {} vía I2P", full_address))?;
+async fn connect_socks(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> std::result::Result<Socks5Stream<TcpStream>, tokio_socks::Error> {
+    let target = (host, port);
+
+    if proxy.stream_isolation {
+        let username = format!(
+            "{}-{:016x}",
+            proxy.username.as_deref().unwrap_or("crawly"),
+            rand::random::<u64>()
+        );
+        let password = format!("{:032x}", rand::random::<u128>());
+        return Socks5Stream::connect_with_password(
+            proxy.address.as_str(),
+            target,
+            &username,
+            &password,
+        )
+        .await;
+    }
 
-        let dummy_addr: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let version_message = build_version_message(dummy_addr)?;
-        let first_message =
-            message::RawNetworkMessage::new(Network::Bitcoin.magic(), version_message);
-        stream
-            .write_all(serialize(&first_message).as_slice())
+    match (&proxy.username, &proxy.password) {
+        (Some(username), password) => {
+            Socks5Stream::connect_with_password(
+                proxy.address.as_str(),
+                target,
+                username,
+                password.as_deref().unwrap_or(""),
+            )
             .await
-            .context(format!("Fallo al enviar 'version' a {}", full_address))?;
-        handle_stream(db, full_address.clone(), stream).await
-    };
-
-    match tokio::time::timeout(conversation_timeout, task).await {
-        Ok(result) => result,
-        Err(_) => Err(anyhow::anyhow!(
-            "Timeout global de la conversación con {}",
-            full_address_clone
-        )),
+        }
+        (None, _) => Socks5Stream::connect(proxy.address.as_str(), target).await,
     }
 }
 
 pub async fn handle_connection(
     db: &crate::db::Database,
-    mut stream: TcpStream,
+    network: Network,
+    stream: TcpStream,
     peer_addr: SocketAddr,
 ) -> Result<()> {
     tracing::debug!(target: "p2p", "Esperando mensaje 'version' de {}", peer_addr);
 
-    let mut header_buf = [0u8; 24];
-    if let Err(_) =
-        tokio::time::timeout(Duration::from_secs(30), stream.read_exact(&mut header_buf)).await
-    {
-        tracing::warn!(target: "p2p", "Timeout esperando el primer mensaje de {}. Cerrando.", peer_addr);
-        return Ok(());
-    }
+    let mut stream = MessageStream::new(stream, network);
 
-    let payload_size = u32::from_le_bytes(header_buf[16..20].try_into()?) as usize;
+    // El primer mensaje de un peer desconocido tiene que ser 'version', así que
+    // no aceptamos payloads mayores que el de un 'version' legítimo.
+    let first_message = match tokio::time::timeout(
+        SessionPhase::AwaitVersion.timeout(),
+        stream.read_message_within(codec::payload_limit("version")),
+    )
+    .await
+    {
+        Ok(Ok(msg)) => msg,
+        Ok(Err(e)) => {
+            tracing::warn!(target: "p2p", "Primer mensaje inválido de {}: {}. Cerrando.", peer_addr, e);
+            return Ok(());
+        }
+        Err(_) => {
+            tracing::warn!(target: "p2p", "Timeout esperando el primer mensaje de {}. Cerrando.", peer_addr);
+            return Ok(());
+        }
+    };
 
-    if payload_size > 1024 {
-        tracing::warn!(target: "p2p", "El primer mensaje de {} tiene un payload de {} bytes. Demasiado grande. Descartando.", peer_addr, payload_size);
-        return Ok(());
-    }
+    let version_msg = match first_message {
+        message::NetworkMessage::Version(version_msg) => version_msg,
+        other => {
+            tracing::debug!(target: "p2p", "El primer mensaje de {} no fue 'version', sino '{}'. Ignorando.", peer_addr, other.cmd());
+            return Ok(());
+        }
+    };
 
-    let mut payload_buf = vec![0u8; payload_size];
-    if stream.read_exact(&mut payload_buf).await.is_err() {
-        tracing::warn!(target: "p2p", "Fallo al leer payload desde {}. Cerrando.", peer_addr);
-        return Ok(());
-    }
+    let version = version_info(&version_msg);
+    tracing::info!(target: "p2p", "Recibido 'version' de {}: user_agent='{}'", peer_addr, version.user_agent);
 
-    let full_message_buf = [&header_buf[..], &payload_buf[..]].concat();
-    let reply: message::RawNetworkMessage = match deserialize(&full_message_buf) {
-        Ok(msg) => msg,
-        Err(e) => {
-            tracing::warn!(target: "p2p", "Mensaje inválido recibido de {}: {} ({:?}). Ignorando.", peer_addr, e, e);
-            return Err(
-                anyhow::Error::from(e).context(format!("Mensaje inválido desde {}", peer_addr))
-            );
-        }
+    let address = NetAddress::from_ip(peer_addr.ip());
+    let listen_port = if version_msg.sender.port != 0 {
+        version_msg.sender.port
+    } else {
+        default_port(network)
     };
+    let node_id = db
+        .upsert_inbound_peer(network, &address, listen_port, &version)
+        .await?;
 
-    if let message::NetworkMessage::Version(version_msg) = reply.payload() {
-        let soft = &version_msg.user_agent;
-        let services = &version_msg.services.to_string();
-        let protocol_version = version_msg.version as i32;
-        let start_height = version_msg.start_height;
-        let relay = version_msg.relay;
+    let peer_addr_str = peer_addr.to_string();
+    stream
+        .write_message(build_version_message(network, peer_addr)?)
+        .await
+        .context(format!("Fallo al enviar 'version' a {}", peer_addr))?;
+    send_verack(&mut stream, version_msg.version, &peer_addr_str).await?;
 
-        tracing::info!(target: "p2p", "Recibido 'version' de {}: user_agent='{}'", peer_addr, soft);
+    let mut session = Session {
+        handshake: Some(version),
+        ..Default::default()
+    };
+    run_session(
+        db,
+        network,
+        node_id,
+        peer_addr_str,
+        stream,
+        &mut session,
+        SessionPhase::AwaitVerack,
+    )
+    .await
+}
 
-        db.update_inbound_node_info(
-            peer_addr.to_string().as_str(),
-            soft,
-            services,
-            protocol_version,
-            start_height,
-            relay,
-        )
-        .await?;
-    } else {
-        tracing::debug!(target: "p2p", "El primer mensaje de {} no fue 'version', sino '{}'. Ignorando.", peer_addr, reply.command());
-    }
+// Segundos que el reloj del peer va por delante (o por detrás, si es
+// negativo) del nuestro según el 'timestamp' de su 'version'.
+fn clock_offset(timestamp: i64) -> Option<i64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(timestamp - now.as_secs() as i64)
+}
 
-    Ok(())
+fn version_info(version: &message_network::VersionMessage) -> crate::db::VersionInfo {
+    crate::db::VersionInfo {
+        user_agent: version.user_agent.clone(),
+        services: version.services.into(),
+        protocol_version: version.version as i32,
+        start_height: version.start_height,
+        relay: version.relay,
+    }
 }
 
-fn build_version_message(address: SocketAddr) -> Result<message::NetworkMessage> {
-    let my_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(170, 75, 173, 236)), 8333);
+fn build_version_message(network: Network, address: SocketAddr) -> Result<message::NetworkMessage> {
+    let my_address = SocketAddr::new(
+        IpAddr::V4(Ipv4Addr::new(170, 75, 173, 236)),
+        default_port(network),
+    );
     let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
 
     let timestamp = SystemTime::now()
@@ -550,33 +1400,3 @@ fn build_version_message(address: SocketAddr) -> Result<message::NetworkMessage>
         ),
     ))
 }
-
-fn build_torv3_address(pubkey: &[u8; 32]) -> Result<String> {
-    const VERSION: u8 = 0x03;
-    const CHECKSUM_PREFIX: &[u8] = b".onion checksum";
-
-    let mut hasher = Sha3_256::new();
-    hasher.update(CHECKSUM_PREFIX);
-    hasher.update(pubkey);
-    hasher.update(&[VERSION]);
-
-    let hash = hasher.finalize();
-    let checksum: [u8; 2] = hash[..2]
-        .try_into()
-        .context("Fallo al truncar el checksum")?;
-
-    let mut full_data = [0u8; 35];
-    full_data[..32].copy_from_slice(pubkey);
-    full_data[32..34].copy_from_slice(&checksum);
-    full_data[34] = VERSION;
-
-    let mut spec = data_encoding::BASE32.specification();
-    spec.padding = None;
-    let encoding = spec
-        .encoding()
-        .context("Fallo al crear el encoder BASE32")?;
-
-    let address = encoding.encode(&full_data).to_lowercase();
-
-    Ok(address + ".onion")
-}
@@ -0,0 +1,636 @@
+// Transporte cifrado v2 (BIP324): intercambio de claves ElligatorSwift,
+// derivación con HKDF-SHA256 y paquetes FSChaCha20 + FSChaCha20Poly1305.
+// Sólo implementamos el lado iniciador, que es el que usa el crawler.
+
+use bitcoin::secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::Network;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::codec::CodecError;
+
+const REKEY_INTERVAL: u32 = 224;
+const ELLSWIFT_LEN: usize = 64;
+const GARBAGE_TERMINATOR_LEN: usize = 16;
+const MAX_GARBAGE_LEN: usize = 4095;
+// Basura que enviamos tras nuestra clave. El BIP permite hasta 4095 bytes; con
+// unos pocos basta para no tener un tamaño fijo.
+const MAX_SENT_GARBAGE: usize = 64;
+pub const LENGTH_LEN: usize = 3;
+pub const HEADER_LEN: usize = 1;
+pub const TAG_LEN: usize = 16;
+const IGNORE_BIT: u8 = 0x80;
+
+// Identificadores cortos de BIP324. El resto de comandos viajan con un 0
+// seguido del comando de 12 bytes, como en v1.
+const SHORT_IDS: [(u8, &str); 28] = [
+    (1, "addr"),
+    (2, "block"),
+    (3, "blocktxn"),
+    (4, "cmpctblock"),
+    (5, "feefilter"),
+    (6, "filteradd"),
+    (7, "filterclear"),
+    (8, "filterload"),
+    (9, "getblocks"),
+    (10, "getblocktxn"),
+    (11, "getdata"),
+    (12, "getheaders"),
+    (13, "headers"),
+    (14, "inv"),
+    (15, "mempool"),
+    (16, "merkleblock"),
+    (17, "notfound"),
+    (18, "ping"),
+    (19, "pong"),
+    (20, "sendcmpct"),
+    (21, "tx"),
+    (22, "getcfilters"),
+    (23, "cfilter"),
+    (24, "getcfheaders"),
+    (25, "cfheaders"),
+    (26, "getcfcheckpt"),
+    (27, "cfcheckpt"),
+    (28, "addrv2"),
+];
+
+pub fn short_id(command: &str) -> Option<u8> {
+    SHORT_IDS
+        .iter()
+        .find(|(_, name)| *name == command)
+        .map(|(id, _)| *id)
+}
+
+pub fn command_for_short_id(id: u8) -> Option<&'static str> {
+    SHORT_IDS
+        .iter()
+        .find(|(short, _)| *short == id)
+        .map(|(_, name)| *name)
+}
+
+// Cifrado de la longitud: ChaCha20 continuo que cambia de clave cada
+// REKEY_INTERVAL trozos usando su propio keystream.
+struct FsChaCha20 {
+    cipher: ChaCha20,
+    rekey_interval: u32,
+    chunk_counter: u32,
+    rekey_counter: u64,
+}
+
+impl FsChaCha20 {
+    fn new(key: [u8; 32], rekey_interval: u32) -> Self {
+        FsChaCha20 {
+            cipher: ChaCha20::new(&key.into(), &Self::nonce(0).into()),
+            rekey_interval,
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn nonce(rekey_counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&rekey_counter.to_le_bytes());
+        nonce
+    }
+
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunk_counter += 1;
+        if self.chunk_counter == self.rekey_interval {
+            let mut key = [0u8; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.rekey_counter += 1;
+            self.cipher = ChaCha20::new(&key.into(), &Self::nonce(self.rekey_counter).into());
+            self.chunk_counter = 0;
+        }
+    }
+}
+
+// AEAD de los paquetes: ChaCha20Poly1305 con el contador de paquetes como
+// nonce y cambio de clave cada REKEY_INTERVAL paquetes.
+struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        FsChaCha20Poly1305 {
+            key,
+            packet_counter: 0,
+        }
+    }
+
+    fn nonce(&self) -> [u8; 12] {
+        let interval = REKEY_INTERVAL as u64;
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&((self.packet_counter % interval) as u32).to_le_bytes());
+        nonce[4..].copy_from_slice(&(self.packet_counter / interval).to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let nonce = self.nonce();
+        let ciphertext = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("ChaCha20Poly1305 no falla al cifrar");
+        self.advance(nonce);
+        ciphertext
+    }
+
+    fn decrypt(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CodecError> {
+        let nonce = self.nonce();
+        let plaintext = ChaCha20Poly1305::new(&self.key.into())
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| CodecError::Decrypt)?;
+        self.advance(nonce);
+        Ok(plaintext)
+    }
+
+    fn advance(&mut self, nonce: [u8; 12]) {
+        if (self.packet_counter + 1).is_multiple_of(REKEY_INTERVAL as u64) {
+            let mut rekey_nonce = [0xffu8; 12];
+            rekey_nonce[4..].copy_from_slice(&nonce[4..]);
+            let block = ChaCha20Poly1305::new(&self.key.into())
+                .encrypt(
+                    &rekey_nonce.into(),
+                    Payload {
+                        msg: &[0u8; 32],
+                        aad: &[],
+                    },
+                )
+                .expect("ChaCha20Poly1305 no falla al cifrar");
+            self.key.copy_from_slice(&block[..32]);
+        }
+        self.packet_counter += 1;
+    }
+}
+
+// Estado de cifrado de una conexión v2 ya establecida.
+pub struct V2Cipher {
+    send_length: FsChaCha20,
+    send_packet: FsChaCha20Poly1305,
+    recv_length: FsChaCha20,
+    recv_packet: FsChaCha20Poly1305,
+    pub session_id: [u8; 32],
+}
+
+struct Keys {
+    cipher: V2Cipher,
+    send_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    recv_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+}
+
+fn derive_keys(ecdh_secret: &[u8; 32], network: Network) -> Keys {
+    let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+    salt.extend_from_slice(&network.magic().to_bytes());
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), ecdh_secret);
+    let expand = |info: &[u8]| {
+        let mut okm = [0u8; 32];
+        hkdf.expand(info, &mut okm)
+            .expect("32 bytes es una longitud válida para HKDF-SHA256");
+        okm
+    };
+
+    let terminators = expand(b"garbage_terminators");
+    let mut send_garbage_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+    let mut recv_garbage_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+    send_garbage_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LEN]);
+    recv_garbage_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LEN..]);
+
+    Keys {
+        cipher: V2Cipher {
+            send_length: FsChaCha20::new(expand(b"initiator_L"), REKEY_INTERVAL),
+            send_packet: FsChaCha20Poly1305::new(expand(b"initiator_P")),
+            recv_length: FsChaCha20::new(expand(b"responder_L"), REKEY_INTERVAL),
+            recv_packet: FsChaCha20Poly1305::new(expand(b"responder_P")),
+            session_id: expand(b"session_id"),
+        },
+        send_garbage_terminator,
+        recv_garbage_terminator,
+    }
+}
+
+impl V2Cipher {
+    pub fn encrypt_packet(&mut self, contents: &[u8], aad: &[u8], ignore: bool) -> Vec<u8> {
+        let mut length = (contents.len() as u32).to_le_bytes()[..LENGTH_LEN].to_vec();
+        self.send_length.crypt(&mut length);
+
+        let mut plaintext = Vec::with_capacity(HEADER_LEN + contents.len());
+        plaintext.push(if ignore { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+
+        let mut packet = length;
+        packet.extend(self.send_packet.encrypt(aad, &plaintext));
+        packet
+    }
+
+    pub fn decrypt_length(&mut self, mut length: [u8; LENGTH_LEN]) -> usize {
+        self.recv_length.crypt(&mut length);
+        u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize
+    }
+
+    // Devuelve el contenido del paquete, o None si es un paquete señuelo.
+    pub fn decrypt_packet(
+        &mut self,
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Option<Vec<u8>>, CodecError> {
+        let mut plaintext = self.recv_packet.decrypt(aad, ciphertext)?;
+        if plaintext.is_empty() {
+            return Err(CodecError::Decrypt);
+        }
+        if plaintext[0] & IGNORE_BIT != 0 {
+            return Ok(None);
+        }
+        plaintext.remove(0);
+        Ok(Some(plaintext))
+    }
+
+    async fn read_packet<S>(
+        &mut self,
+        stream: &mut S,
+        aad: &[u8],
+    ) -> Result<Option<Vec<u8>>, CodecError>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let mut length = [0u8; LENGTH_LEN];
+        stream.read_exact(&mut length).await?;
+        let length = self.decrypt_length(length);
+        let mut ciphertext = vec![0u8; HEADER_LEN + length + TAG_LEN];
+        stream.read_exact(&mut ciphertext).await?;
+        self.decrypt_packet(aad, &ciphertext)
+    }
+}
+
+fn random_secret_key() -> SecretKey {
+    loop {
+        if let Ok(key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+            return key;
+        }
+    }
+}
+
+// Handshake BIP324 como iniciador. Un nodo que sólo habla v1 cierra la
+// conexión al no reconocer el magic, así que el error de E/S resultante es la
+// señal para reintentar en v1.
+pub async fn initiate<S>(stream: &mut S, network: Network) -> Result<V2Cipher, CodecError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let secret_key = random_secret_key();
+    let ellswift_ours =
+        ElligatorSwift::from_seckey(&Secp256k1::new(), secret_key, Some(rand::random()));
+    let garbage_len = rand::rng().random_range(0..=MAX_SENT_GARBAGE);
+    let garbage: Vec<u8> = (0..garbage_len).map(|_| rand::random()).collect();
+
+    let mut hello = ellswift_ours.to_array().to_vec();
+    hello.extend_from_slice(&garbage);
+    stream.write_all(&hello).await?;
+
+    let mut ellswift_theirs = [0u8; ELLSWIFT_LEN];
+    stream.read_exact(&mut ellswift_theirs).await?;
+    if ellswift_theirs[..4] == network.magic().to_bytes() {
+        return Err(CodecError::Handshake(
+            "el peer respondió con un mensaje v1".to_string(),
+        ));
+    }
+    let ellswift_theirs = ElligatorSwift::from_array(ellswift_theirs);
+
+    let ecdh_secret = ElligatorSwift::shared_secret(
+        ellswift_ours,
+        ellswift_theirs,
+        secret_key,
+        ElligatorSwiftParty::A,
+        None,
+    );
+    let Keys {
+        mut cipher,
+        send_garbage_terminator,
+        recv_garbage_terminator,
+    } = derive_keys(ecdh_secret.as_secret_bytes(), network);
+
+    // Terminador de nuestra basura seguido del paquete de versión (vacío), que
+    // autentica la basura enviada como AAD.
+    let mut reply = send_garbage_terminator.to_vec();
+    reply.extend(cipher.encrypt_packet(&[], &garbage, false));
+    stream.write_all(&reply).await?;
+
+    let mut received = vec![0u8; GARBAGE_TERMINATOR_LEN];
+    stream.read_exact(&mut received).await?;
+    while received[received.len() - GARBAGE_TERMINATOR_LEN..] != recv_garbage_terminator {
+        if received.len() >= MAX_GARBAGE_LEN + GARBAGE_TERMINATOR_LEN {
+            return Err(CodecError::Handshake(
+                "no se encontró el terminador de basura".to_string(),
+            ));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        received.push(byte[0]);
+    }
+    received.truncate(received.len() - GARBAGE_TERMINATOR_LEN);
+
+    // El primer paquete (sea señuelo o no) lleva la basura recibida como AAD.
+    // El primero que no es señuelo es el de versión, cuyo contenido se ignora.
+    let mut aad = received;
+    while cipher.read_packet(stream, &aad).await?.is_none() {
+        aad.clear();
+    }
+
+    Ok(cipher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    // Secreto ECDH fijo para los vectores de derivación y cifrado de paquetes.
+    // Los valores esperados salen de la implementación de referencia del BIP
+    // (Python) con el magic de mainnet.
+    const ECDH_SECRET: [u8; 32] = [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
+        25, 26, 27, 28, 29, 30, 31,
+    ];
+
+    fn key(hex_key: &str) -> [u8; 32] {
+        hex::decode(hex_key).unwrap().try_into().unwrap()
+    }
+
+    // Cifra `plaintext` `rekey_interval` veces y devuelve el resultado de la
+    // siguiente, la primera con la clave renovada.
+    fn fschacha20_after_rotation(plaintext: &str, hex_key: &str, rekey_interval: u32) -> String {
+        let plaintext = hex::decode(plaintext).unwrap();
+        let mut cipher = FsChaCha20::new(key(hex_key), rekey_interval);
+        for _ in 0..rekey_interval {
+            cipher.crypt(&mut plaintext.clone());
+        }
+        let mut chunk = plaintext;
+        cipher.crypt(&mut chunk);
+        hex::encode(chunk)
+    }
+
+    // El otro extremo de la conexión: sus claves de envío son las nuestras de
+    // recepción y viceversa.
+    fn responder(ecdh_secret: &[u8; 32], network: Network) -> V2Cipher {
+        let cipher = derive_keys(ecdh_secret, network).cipher;
+        V2Cipher {
+            send_length: cipher.recv_length,
+            send_packet: cipher.recv_packet,
+            recv_length: cipher.send_length,
+            recv_packet: cipher.send_packet,
+            session_id: cipher.session_id,
+        }
+    }
+
+    fn decrypt(cipher: &mut V2Cipher, packet: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let mut length = [0u8; LENGTH_LEN];
+        length.copy_from_slice(&packet[..LENGTH_LEN]);
+        let length = cipher.decrypt_length(length);
+        assert_eq!(packet.len(), LENGTH_LEN + HEADER_LEN + length + TAG_LEN);
+        cipher.decrypt_packet(aad, &packet[LENGTH_LEN..]).unwrap()
+    }
+
+    #[test]
+    fn fschacha20_vectors() {
+        // Vectores de FSChaCha20 de Bitcoin Core (crypto_tests.cpp).
+        assert_eq!(
+            fschacha20_after_rotation(
+                "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                256
+            ),
+            "a93df4ef03011f3db95f60d996e1785df5de38fc39bfcb663a47bb5561928349"
+        );
+        assert_eq!(
+            fschacha20_after_rotation(
+                "e93fdb5c762804b9a706816aca31e35b11d2aa3080108ef46a5b1f1508819c0a",
+                "8ec4c3ccdaea336bdeb245636970be01266509b33f3d2642504eaf412206207a",
+                4096
+            ),
+            "8bfaa4eacff308fdb4a94a5ff25bd9d0c1f84b77f81239f67ff39d6e1ac280c9"
+        );
+    }
+
+    #[test]
+    fn fschacha20poly1305_nonce_layout() {
+        let mut aead = FsChaCha20Poly1305::new([0u8; 32]);
+        let cases: [(u64, [u8; 12]); 4] = [
+            (0, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (223, [223, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (224, [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]),
+            (224 * 258 + 5, [5, 0, 0, 0, 2, 1, 0, 0, 0, 0, 0, 0]),
+        ];
+        for (counter, nonce) in cases.iter() {
+            aead.packet_counter = *counter;
+            assert_eq!(aead.nonce(), *nonce, "paquete {}", counter);
+        }
+    }
+
+    #[test]
+    fn fschacha20poly1305_rekeys_every_224_packets() {
+        let initial = [7u8; 32];
+        let mut aead = FsChaCha20Poly1305::new(initial);
+        for _ in 0..REKEY_INTERVAL - 1 {
+            aead.encrypt(&[], &[]);
+        }
+        assert_eq!(aead.key, initial);
+
+        // La nueva clave son los primeros 32 bytes de cifrar 32 ceros con el
+        // nonce 0xffffffff seguido del número de rekey.
+        let mut rekey_nonce = [0xffu8; 12];
+        rekey_nonce[4..].copy_from_slice(&0u64.to_le_bytes());
+        let expected = ChaCha20Poly1305::new(&initial.into())
+            .encrypt(
+                &rekey_nonce.into(),
+                Payload {
+                    msg: &[0u8; 32],
+                    aad: &[],
+                },
+            )
+            .unwrap();
+        aead.encrypt(&[], &[]);
+        assert_eq!(aead.key[..], expected[..32]);
+        assert_eq!(aead.packet_counter, REKEY_INTERVAL as u64);
+
+        // Un receptor que va al mismo ritmo sigue descifrando tras el rekey.
+        let mut sender = FsChaCha20Poly1305::new(initial);
+        let mut receiver = FsChaCha20Poly1305::new(initial);
+        for i in 0..(2 * REKEY_INTERVAL + 3) {
+            let plaintext = i.to_le_bytes();
+            let ciphertext = sender.encrypt(b"aad", &plaintext);
+            assert_eq!(receiver.decrypt(b"aad", &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn derive_keys_vectors() {
+        let keys = derive_keys(&ECDH_SECRET, Network::Bitcoin);
+        assert_eq!(
+            hex::encode(keys.send_garbage_terminator),
+            "d5b4dcfc2f5fa9f1511623eb99db5d81"
+        );
+        assert_eq!(
+            hex::encode(keys.recv_garbage_terminator),
+            "f2e6cb1f1920617fb019d0a8457fb2d2"
+        );
+        assert_eq!(
+            hex::encode(keys.cipher.session_id),
+            "31240e251b9cbbd21db1e2f0584281d42e892b122343c7b4a7e16d69a9374502"
+        );
+
+        // El magic entra en la sal, así que cada red deriva claves distintas.
+        let testnet = derive_keys(&ECDH_SECRET, Network::Testnet);
+        assert_ne!(testnet.cipher.session_id, keys.cipher.session_id);
+    }
+
+    #[test]
+    fn encrypt_packet_vectors() {
+        let mut cipher = derive_keys(&ECDH_SECRET, Network::Bitcoin).cipher;
+        // Paquete de versión vacío con la basura enviada como AAD.
+        assert_eq!(
+            hex::encode(cipher.encrypt_packet(&[], &[0xde, 0xad, 0xbe, 0xef], false)),
+            "88da7cbebd459bf2235d5b5ca374addc285bebee"
+        );
+        // 'ping' (identificador corto 18) con nonce 0.
+        let mut ping = vec![18u8];
+        ping.extend_from_slice(&[0u8; 8]);
+        assert_eq!(
+            hex::encode(cipher.encrypt_packet(&ping, &[], false)),
+            "cba126def2102ee97be69814f1c0ef77c90f3a593b693f4a4ecb53de6e"
+        );
+        // Paquete señuelo.
+        assert_eq!(
+            hex::encode(cipher.encrypt_packet(b"hola", &[], true)),
+            "a429e24b8189d559996beda969063787b3377f2265c53ec1"
+        );
+        for _ in 3..REKEY_INTERVAL {
+            cipher.encrypt_packet(&[], &[], false);
+        }
+        // Primer paquete tras el rekey de la longitud y del AEAD: 'pong'.
+        let mut pong = vec![19u8];
+        pong.extend_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(
+            hex::encode(cipher.encrypt_packet(&pong, &[], false)),
+            "b812846f239a20edbf918bb73ce221b904a7cf58a5cf28905714262cd2"
+        );
+    }
+
+    #[test]
+    fn decrypt_packets() {
+        let mut ours = derive_keys(&ECDH_SECRET, Network::Bitcoin).cipher;
+        let mut theirs = responder(&ECDH_SECRET, Network::Bitcoin);
+
+        let packet = ours.encrypt_packet(&[], b"basura", false);
+        assert_eq!(decrypt(&mut theirs, &packet, b"basura"), Some(vec![]));
+
+        let packet = ours.encrypt_packet(b"hola", &[], true);
+        assert_eq!(decrypt(&mut theirs, &packet, &[]), None);
+
+        for i in 0..(2 * REKEY_INTERVAL) {
+            let contents = i.to_le_bytes();
+            let packet = theirs.encrypt_packet(&contents, &[], false);
+            assert_eq!(decrypt(&mut ours, &packet, &[]), Some(contents.to_vec()));
+        }
+
+        // Un bit cambiado invalida la etiqueta.
+        let mut packet = ours.encrypt_packet(b"hola", &[], false);
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        theirs.decrypt_length([packet[0], packet[1], packet[2]]);
+        assert!(matches!(
+            theirs.decrypt_packet(&[], &packet[LENGTH_LEN..]),
+            Err(CodecError::Decrypt)
+        ));
+    }
+
+    // Handshake completo contra un respondedor simulado que envía basura y un
+    // paquete señuelo antes del de versión.
+    #[tokio::test]
+    async fn initiate_handshake() {
+        let network = Network::Bitcoin;
+        let (mut local, mut remote) = tokio::io::duplex(64 * 1024);
+
+        let responder = tokio::spawn(async move {
+            let mut ellswift_theirs = [0u8; ELLSWIFT_LEN];
+            remote.read_exact(&mut ellswift_theirs).await.unwrap();
+            let ellswift_theirs = ElligatorSwift::from_array(ellswift_theirs);
+
+            let secret_key = random_secret_key();
+            let ellswift_ours =
+                ElligatorSwift::from_seckey(&Secp256k1::new(), secret_key, Some(rand::random()));
+            let ecdh_secret = ElligatorSwift::shared_secret(
+                ellswift_theirs,
+                ellswift_ours,
+                secret_key,
+                ElligatorSwiftParty::B,
+                None,
+            );
+            let keys = derive_keys(ecdh_secret.as_secret_bytes(), network);
+            let mut cipher = responder(ecdh_secret.as_secret_bytes(), network);
+
+            let garbage = b"basura del respondedor".to_vec();
+            let mut hello = ellswift_ours.to_array().to_vec();
+            hello.extend_from_slice(&garbage);
+            hello.extend_from_slice(&keys.recv_garbage_terminator);
+            hello.extend(cipher.encrypt_packet(b"senuelo", &garbage, true));
+            hello.extend(cipher.encrypt_packet(&[], &[], false));
+            remote.write_all(&hello).await.unwrap();
+
+            let mut received = Vec::new();
+            while !received.ends_with(&keys.send_garbage_terminator) {
+                let mut byte = [0u8; 1];
+                remote.read_exact(&mut byte).await.unwrap();
+                received.push(byte[0]);
+            }
+            received.truncate(received.len() - GARBAGE_TERMINATOR_LEN);
+            assert!(received.len() <= MAX_SENT_GARBAGE);
+
+            let version = cipher.read_packet(&mut remote, &received).await.unwrap();
+            assert_eq!(version, Some(vec![]));
+            let message = cipher.read_packet(&mut remote, &[]).await.unwrap();
+            (cipher.session_id, message)
+        });
+
+        let mut cipher = initiate(&mut local, network).await.unwrap();
+        let ping = [18u8, 1, 2, 3, 4, 5, 6, 7, 8];
+        local
+            .write_all(&cipher.encrypt_packet(&ping, &[], false))
+            .await
+            .unwrap();
+
+        let (session_id, message) = responder.await.unwrap();
+        assert_eq!(session_id, cipher.session_id);
+        assert_eq!(message, Some(ping.to_vec()));
+    }
+
+    #[test]
+    fn short_ids() {
+        assert_eq!(short_id("addrv2"), Some(28));
+        assert_eq!(short_id("version"), None);
+        assert_eq!(command_for_short_id(13), Some("headers"));
+        assert_eq!(command_for_short_id(0), None);
+        assert_eq!(command_for_short_id(29), None);
+    }
+}